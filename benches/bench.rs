@@ -8,13 +8,13 @@ use bytes::BytesMut;
 use futures::executor::{block_on, spawn};
 use futures::future::ok;
 use futures::prelude::*;
-use simproto::sim::{Handler, Sim};
+use simproto::sim::{Handler, Sim, SubscribeDecision};
 use simproto::util::PairIO;
 use test::{black_box, Bencher};
 
 #[bench]
 fn rpc(b: &mut Bencher) {
-    let mut handler = Handler::new();
+    let handler = Handler::new();
     let topic_echo = BytesMut::from(r"echo").freeze();
     handler.on_rpc(topic_echo.clone(), Box::new(|req| Box::new(ok(req))));
     let sim = Sim::new(handler);
@@ -36,12 +36,12 @@ fn rpc(b: &mut Bencher) {
 
 #[bench]
 fn simple_notify(b: &mut Bencher) {
-    let mut handler = Handler::new();
+    let handler = Handler::new();
     let topic_once = BytesMut::from(r"once").freeze();
 
     let (one_sink, fut) = handler.on_subs(
         topic_once.clone(),
-        Box::new(|req| Box::new(ok(req))),
+        Box::new(|req| Box::new(ok(SubscribeDecision::Accept(req)))),
         Box::new(|req| Box::new(ok(req))),
     );
     block_on(spawn(fut.map_err(|e| panic!("on_sub fut panic {:?}", e)))).unwrap();
@@ -55,7 +55,7 @@ fn simple_notify(b: &mut Bencher) {
     block_on(spawn(fut.map_err(|e| panic!("io2 sim fut panic {:?}", e)))).unwrap();
 
     let receiver = req1
-        .sub(topic_once, BytesMut::from(&[] as &[u8]).freeze())
+        .sub(topic_once.clone(), BytesMut::from(&[] as &[u8]).freeze())
         .and_then(|(_, _, receiver)| ok(receiver.unwrap()));
 
     let receiver = block_on(receiver).unwrap();
@@ -63,7 +63,7 @@ fn simple_notify(b: &mut Bencher) {
     b.iter(|| {
         if let Some((one_sink, receiver)) = std::mem::replace(&mut inner, None) {
             let fut = one_sink
-                .send(BytesMut::from(b"hello" as &[u8]).freeze())
+                .send((topic_once.clone(), BytesMut::from(b"hello" as &[u8]).freeze()))
                 .map_err(|e| panic!("Sending notification panic {:?}", e))
                 .and_then(|s| {
                     receiver.next().map(|(msg, r)| {
@@ -0,0 +1,228 @@
+//! Generator of Rust bindings of SimProto services described by a schema.
+//!
+//! The bindings of a schema are its structs, serializable with serde, and a
+//! trait per service defined with `simproto::service`, along with the schema
+//! itself in `SCHEMA`. See the README for the syntax of schemas.
+
+mod schema;
+
+pub use schema::{Error, Field, Method, Schema, Service, Struct, Type};
+
+use std::env;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Generates the bindings of the schema file `path` in `$OUT_DIR`.
+///
+/// The bindings of `calculator.simproto` are written to `calculator.rs`. Meant
+/// to be called from a build script, which is rerun when the schema changes.
+pub fn compile<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let out_dir = env::var_os("OUT_DIR")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "OUT_DIR is not set"))?;
+    compile_to(path, out_dir)
+}
+
+/// Generates the bindings of the schema file `path` in `out_dir`.
+pub fn compile_to<P: AsRef<Path>, Q: AsRef<Path>>(path: P, out_dir: Q) -> io::Result<()> {
+    let path = path.as_ref();
+    let source = fs::read_to_string(path)?;
+    let code = generate(&source).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), e),
+        )
+    })?;
+    let name = path
+        .file_stem()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "schema path has no file name"))?;
+    fs::write(out_dir.as_ref().join(name).with_extension("rs"), code)?;
+    println!("cargo:rerun-if-changed={}", path.display());
+    Ok(())
+}
+
+/// Returns the bindings of the schema `source`.
+pub fn generate(source: &str) -> Result<String, Error> {
+    let schema = Schema::parse(source)?;
+    let mut code = String::new();
+    code.push_str("// Generated by simproto-codegen, do not edit.\n");
+    for s in &schema.structs {
+        write_struct(&mut code, s);
+    }
+    for service in &schema.services {
+        write_service(&mut code, service);
+    }
+    writeln!(
+        code,
+        "\n/// Schema of the services, see `Handler::serve_schema`.\npub const SCHEMA: &str = {:?};",
+        source
+    ).unwrap();
+    Ok(code)
+}
+
+fn write_struct(code: &mut String, s: &Struct) {
+    writeln!(code, "\n#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]").unwrap();
+    writeln!(code, "pub struct {} {{", s.name).unwrap();
+    for field in &s.fields {
+        writeln!(code, "    pub {}: {},", field.name, rust_type(&field.ty)).unwrap();
+    }
+    code.push_str("}\n");
+}
+
+fn write_service(code: &mut String, service: &Service) {
+    writeln!(code, "\n#[simproto::service]").unwrap();
+    writeln!(code, "pub trait {} {{", service.name).unwrap();
+    for method in &service.methods {
+        match *method {
+            Method::Rpc {
+                ref name,
+                ref request,
+                ref response,
+            } => {
+                let request = match *request {
+                    Some(ref request) => format!(", request: {}", rust_type(request)),
+                    None => String::new(),
+                };
+                writeln!(
+                    code,
+                    "    fn {}(&self{}) -> Box<::simproto::__service::Future<Item = {}, \
+                     Error = ::std::io::Error> + Send + Sync>;",
+                    name,
+                    request,
+                    rust_type(response)
+                ).unwrap();
+            }
+            Method::Subscription {
+                ref name,
+                ref notification,
+            } => {
+                writeln!(
+                    code,
+                    "    fn {}(&self) -> Box<::simproto::__service::Stream<Item = {}, \
+                     Error = ::simproto::__service::Never> + Send>;",
+                    name,
+                    rust_type(notification)
+                ).unwrap();
+            }
+        }
+    }
+    code.push_str("}\n");
+}
+
+fn rust_type(ty: &Type) -> String {
+    match *ty {
+        Type::Bool => "bool".to_string(),
+        Type::U8 => "u8".to_string(),
+        Type::U16 => "u16".to_string(),
+        Type::U32 => "u32".to_string(),
+        Type::U64 => "u64".to_string(),
+        Type::I8 => "i8".to_string(),
+        Type::I16 => "i16".to_string(),
+        Type::I32 => "i32".to_string(),
+        Type::I64 => "i64".to_string(),
+        Type::F32 => "f32".to_string(),
+        Type::F64 => "f64".to_string(),
+        Type::String => "String".to_string(),
+        Type::Bytes => "Vec<u8>".to_string(),
+        Type::List(ref ty) => format!("Vec<{}>", rust_type(ty)),
+        Type::Optional(ref ty) => format!("Option<{}>", rust_type(ty)),
+        Type::Named(ref name) => name.clone(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CALCULATOR: &str = "
+        // Operands of an addition.
+        struct Add {
+            a: u32,
+            b: list<optional<u32>>,
+        }
+
+        service Calculator {
+            rpc add(Add) -> u32;
+            rpc zero() -> u32;
+            sub ticks -> u64;
+        }
+    ";
+
+    #[test]
+    fn parse_schema() {
+        let schema = Schema::parse(CALCULATOR).unwrap();
+        assert_eq!(
+            schema.structs,
+            vec![Struct {
+                name: "Add".to_string(),
+                fields: vec![
+                    Field {
+                        name: "a".to_string(),
+                        ty: Type::U32,
+                    },
+                    Field {
+                        name: "b".to_string(),
+                        ty: Type::List(Box::new(Type::Optional(Box::new(Type::U32)))),
+                    },
+                ],
+            }]
+        );
+        assert_eq!(
+            schema.services[0].methods,
+            vec![
+                Method::Rpc {
+                    name: "add".to_string(),
+                    request: Some(Type::Named("Add".to_string())),
+                    response: Type::U32,
+                },
+                Method::Rpc {
+                    name: "zero".to_string(),
+                    request: None,
+                    response: Type::U32,
+                },
+                Method::Subscription {
+                    name: "ticks".to_string(),
+                    notification: Type::U64,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn schema_errors() {
+        let error = Schema::parse("service S {\n  rpc get(Missing) -> u32;\n}").unwrap_err();
+        assert_eq!(error.line, 2);
+        assert_eq!(error.message, "unknown type `Missing`");
+
+        let error = Schema::parse("struct A {}\nstruct A {}").unwrap_err();
+        assert_eq!(error.line, 2);
+
+        let error = Schema::parse("service S {\n  rpc get() u32;\n}").unwrap_err();
+        assert_eq!(error.message, "expected `->`, found `u32`");
+
+        let error = Schema::parse("struct A {").unwrap_err();
+        assert_eq!(error.message, "expected `name`, found end of schema");
+
+        let error = Schema::parse("struct A {\n  type: u32,\n}").unwrap_err();
+        assert_eq!(error.line, 2);
+        assert_eq!(error.message, "`type` is a reserved word");
+
+        let error = Schema::parse("struct service {}").unwrap_err();
+        assert_eq!(error.message, "`service` is a reserved word");
+
+        let error = Schema::parse("service S {\n  rpc new() -> u32;\n}").unwrap_err();
+        assert_eq!(error.message, "`new` is reserved for the methods of the generated client");
+    }
+
+    #[test]
+    fn generate_bindings() {
+        let code = generate(CALCULATOR).unwrap();
+        assert!(code.contains("pub struct Add {\n    pub a: u32,\n    pub b: Vec<Option<u32>>,\n}"));
+        assert!(code.contains("#[simproto::service]\npub trait Calculator {"));
+        assert!(code.contains("fn add(&self, request: Add) -> Box<"));
+        assert!(code.contains("fn zero(&self) -> Box<"));
+        assert!(code.contains("fn ticks(&self) -> Box<::simproto::__service::Stream<Item = u64"));
+        assert!(code.contains("pub const SCHEMA: &str = \""));
+    }
+}
@@ -0,0 +1,373 @@
+//! Parsing of schema files.
+
+use std::collections::HashSet;
+use std::error;
+use std::fmt;
+
+/// Payload types and services described by a schema.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schema {
+    pub structs: Vec<Struct>,
+    pub services: Vec<Service>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Struct {
+    pub name: String,
+    pub fields: Vec<Field>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Field {
+    pub name: String,
+    pub ty: Type,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Type {
+    Bool,
+    U8,
+    U16,
+    U32,
+    U64,
+    I8,
+    I16,
+    I32,
+    I64,
+    F32,
+    F64,
+    String,
+    Bytes,
+    List(Box<Type>),
+    Optional(Box<Type>),
+    /// A struct of the schema.
+    Named(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Service {
+    pub name: String,
+    pub methods: Vec<Method>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Method {
+    /// An RPC topic taking `request`, if any, and answering with `response`.
+    Rpc {
+        name: String,
+        request: Option<Type>,
+        response: Type,
+    },
+    /// A subscription topic notifying `notification`.
+    Subscription { name: String, notification: Type },
+}
+
+impl Method {
+    pub fn name(&self) -> &str {
+        match *self {
+            Method::Rpc { ref name, .. } | Method::Subscription { ref name, .. } => name,
+        }
+    }
+}
+
+/// Error in a schema, at `line`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Error {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+impl Schema {
+    /// Parses the schema `source`.
+    pub fn parse(source: &str) -> Result<Schema, Error> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            pos: 0,
+            definitions: HashSet::new(),
+            references: Vec::new(),
+        };
+        let mut schema = Schema {
+            structs: Vec::new(),
+            services: Vec::new(),
+        };
+        while let Some(token) = parser.next() {
+            match token.text {
+                "struct" => schema.structs.push(parser.parse_struct()?),
+                "service" => schema.services.push(parser.parse_service()?),
+                text => {
+                    return Err(token.error(format!(
+                        "expected `struct` or `service`, found `{}`",
+                        text
+                    )))
+                }
+            }
+        }
+        parser.check(&schema)?;
+        Ok(schema)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Token<'a> {
+    text: &'a str,
+    line: usize,
+}
+
+impl<'a> Token<'a> {
+    fn error(&self, message: String) -> Error {
+        Error {
+            line: self.line,
+            message,
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        let line_number = i + 1;
+        let line = match line.find("//") {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        let mut rest = line.trim_left();
+        while !rest.is_empty() {
+            let len = if rest.starts_with("->") {
+                2
+            } else if rest.starts_with(|c: char| "{}()<>,;:".contains(c)) {
+                1
+            } else if rest.starts_with(|c: char| c.is_alphanumeric() || c == '_') {
+                rest.find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                    .unwrap_or_else(|| rest.len())
+            } else {
+                return Err(Error {
+                    line: line_number,
+                    message: format!("unexpected character `{}`", rest.chars().next().unwrap()),
+                });
+            };
+            tokens.push(Token {
+                text: &rest[..len],
+                line: line_number,
+            });
+            rest = rest[len..].trim_left();
+        }
+    }
+    Ok(tokens)
+}
+
+/// Words which can't be used as names, being keywords of schemas or of Rust.
+const RESERVED: &[&str] = &[
+    "_", "rpc", "service", "struct", "sub", "Self", "abstract", "alignof", "as", "async",
+    "await", "become", "box", "break", "const", "continue", "crate", "do", "dyn", "else", "enum",
+    "extern", "false", "final", "fn", "for", "if", "impl", "in", "let", "loop", "macro", "match",
+    "mod", "move", "mut", "offsetof", "override", "priv", "proc", "pub", "pure", "ref", "return",
+    "self", "sizeof", "static", "super", "trait", "true", "try", "type", "typeof", "unsafe",
+    "unsized", "use", "virtual", "where", "while", "yield",
+];
+
+/// Names of the methods of the generated clients, which methods can't have.
+const CLIENT_METHODS: &[&str] = &["new", "into_inner"];
+
+/// Returns whether `text` is a valid Rust identifier.
+fn is_ident(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+    /// Names of the structs and services defined so far.
+    definitions: HashSet<String>,
+    /// Named types used so far, with their line.
+    references: Vec<(String, usize)>,
+}
+
+impl<'a> Parser<'a> {
+    fn next(&mut self) -> Option<Token<'a>> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.pos).map(|token| token.text)
+    }
+
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .or_else(|| self.tokens.last())
+            .map_or(1, |token| token.line)
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), Error> {
+        match self.next() {
+            Some(ref token) if token.text == text => Ok(()),
+            Some(token) => Err(token.error(format!("expected `{}`, found `{}`", text, token.text))),
+            None => Err(self.eof(text)),
+        }
+    }
+
+    fn eof(&self, expected: &str) -> Error {
+        Error {
+            line: self.line(),
+            message: format!("expected `{}`, found end of schema", expected),
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, Error> {
+        match self.next() {
+            Some(ref token) if RESERVED.contains(&token.text) => {
+                Err(token.error(format!("`{}` is a reserved word", token.text)))
+            }
+            Some(ref token) if is_ident(token.text) => Ok(token.text.to_string()),
+            Some(token) => Err(token.error(format!("expected a name, found `{}`", token.text))),
+            None => Err(self.eof("name")),
+        }
+    }
+
+    /// Parses the name of a struct or service, which must be unique.
+    fn definition(&mut self) -> Result<String, Error> {
+        let line = self.line();
+        let name = self.ident()?;
+        if !self.definitions.insert(name.clone()) {
+            return Err(Error {
+                line,
+                message: format!("`{}` is defined more than once", name),
+            });
+        }
+        Ok(name)
+    }
+
+    fn parse_struct(&mut self) -> Result<Struct, Error> {
+        let name = self.definition()?;
+        self.expect("{")?;
+        let mut fields = Vec::new();
+        while self.peek() != Some("}") {
+            let name = self.ident()?;
+            self.expect(":")?;
+            let ty = self.parse_type()?;
+            fields.push(Field { name, ty });
+            if self.peek() != Some("}") {
+                self.expect(",")?;
+            }
+        }
+        self.expect("}")?;
+        Ok(Struct { name, fields })
+    }
+
+    fn parse_service(&mut self) -> Result<Service, Error> {
+        let name = self.definition()?;
+        self.expect("{")?;
+        let mut methods = Vec::new();
+        while self.peek() != Some("}") {
+            let token = self.next().ok_or_else(|| self.eof("}"))?;
+            let method = match token.text {
+                "rpc" => {
+                    let name = self.ident()?;
+                    self.expect("(")?;
+                    let request = if self.peek() == Some(")") {
+                        None
+                    } else {
+                        Some(self.parse_type()?)
+                    };
+                    self.expect(")")?;
+                    self.expect("->")?;
+                    let response = self.parse_type()?;
+                    Method::Rpc {
+                        name,
+                        request,
+                        response,
+                    }
+                }
+                "sub" => {
+                    let name = self.ident()?;
+                    self.expect("->")?;
+                    let notification = self.parse_type()?;
+                    Method::Subscription { name, notification }
+                }
+                text => {
+                    return Err(token.error(format!("expected `rpc` or `sub`, found `{}`", text)))
+                }
+            };
+            if CLIENT_METHODS.contains(&method.name()) {
+                return Err(token.error(format!(
+                    "`{}` is reserved for the methods of the generated client",
+                    method.name()
+                )));
+            }
+            if methods.iter().any(|m: &Method| m.name() == method.name()) {
+                return Err(token.error(format!(
+                    "`{}::{}` is defined more than once",
+                    name,
+                    method.name()
+                )));
+            }
+            self.expect(";")?;
+            methods.push(method);
+        }
+        self.expect("}")?;
+        Ok(Service { name, methods })
+    }
+
+    fn parse_type(&mut self) -> Result<Type, Error> {
+        let line = self.line();
+        let name = self.ident()?;
+        let ty = match &*name {
+            "bool" => Type::Bool,
+            "u8" => Type::U8,
+            "u16" => Type::U16,
+            "u32" => Type::U32,
+            "u64" => Type::U64,
+            "i8" => Type::I8,
+            "i16" => Type::I16,
+            "i32" => Type::I32,
+            "i64" => Type::I64,
+            "f32" => Type::F32,
+            "f64" => Type::F64,
+            "string" => Type::String,
+            "bytes" => Type::Bytes,
+            "list" => Type::List(Box::new(self.parse_parameter()?)),
+            "optional" => Type::Optional(Box::new(self.parse_parameter()?)),
+            _ => {
+                self.references.push((name.clone(), line));
+                Type::Named(name.clone())
+            }
+        };
+        Ok(ty)
+    }
+
+    /// Parses the `<T>` of a generic type.
+    fn parse_parameter(&mut self) -> Result<Type, Error> {
+        self.expect("<")?;
+        let ty = self.parse_type()?;
+        self.expect(">")?;
+        Ok(ty)
+    }
+
+    /// Checks that the named types of the schema are its structs.
+    fn check(&self, schema: &Schema) -> Result<(), Error> {
+        for &(ref name, line) in &self.references {
+            if !schema.structs.iter().any(|s| s.name == *name) {
+                return Err(Error {
+                    line,
+                    message: format!("unknown type `{}`", name),
+                });
+            }
+        }
+        Ok(())
+    }
+}
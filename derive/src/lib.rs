@@ -0,0 +1,374 @@
+//! Procedural macros of SimProto, re-exported by `simproto`.
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use syn::{
+    FnArg, GenericArgument, Ident, ItemTrait, LitByteStr, PathArguments, ReturnType, TraitItem,
+    Type, TypeParamBound,
+};
+
+/// Defines a service of topics with a trait.
+///
+/// Every method of the trait takes `&self` and is either:
+///
+/// - an RPC, taking at most one request argument and returning
+///   `Box<Future<Item = Response, Error = io::Error> + Send + Sync>`,
+/// - a subscription, taking no argument and returning
+///   `Box<Stream<Item = Notification, Error = Never> + Send>`. The stream is
+///   taken once when the service is registered and its items are notified to
+///   every subscriber.
+///
+/// Requests, responses and notifications are serialized with serde. A method
+/// `add` of the trait `Calculator` is handled on the topic `Calculator/add`.
+///
+/// For a trait `Calculator`, the macro also generates:
+///
+/// - `register_calculator(handler, service, format)`, registering the topics
+///   of an implementation on a `sim::Handler`,
+/// - `CalculatorClient<F>`, wrapping a `sim::Requestor` with a method per
+///   topic of the service.
+///
+/// ```ignore
+/// #[service]
+/// pub trait Calculator {
+///     fn add(&self, request: (u32, u32)) -> Box<Future<Item = u32, Error = io::Error> + Send + Sync>;
+///     fn ticks(&self) -> Box<Stream<Item = u64, Error = Never> + Send>;
+/// }
+///
+/// let fut = register_calculator(&handler, MyCalculator, Json);
+/// let client = CalculatorClient::new(requestor, Json);
+/// let (client, sum) = block_on(client.add(&(1, 2)))?;
+/// ```
+#[proc_macro_attribute]
+pub fn service(args: TokenStream, input: TokenStream) -> TokenStream {
+    if !args.is_empty() {
+        panic!("#[service] takes no arguments");
+    }
+    let item: ItemTrait = syn::parse(input).expect("#[service] can only be used on a trait");
+    expand(&item).into()
+}
+
+/// A method of a service.
+enum Method {
+    /// An RPC taking `request`, if any, and answering with `response`.
+    Rpc {
+        ident: Ident,
+        request: Option<Type>,
+        response: Type,
+    },
+    /// A subscription notifying `notification`.
+    Subscription { ident: Ident, notification: Type },
+}
+
+impl Method {
+    fn parse(item: &TraitItem) -> Method {
+        let method = match *item {
+            TraitItem::Method(ref method) => method,
+            _ => panic!("#[service] traits can only have methods"),
+        };
+        let sig = &method.sig;
+        let ident = sig.ident.clone();
+        let output = match sig.decl.output {
+            ReturnType::Type(_, ref ty) => &**ty,
+            ReturnType::Default => panic!("service method `{}` must return a future or a stream", ident),
+        };
+        let mut inputs = sig.decl.inputs.iter();
+        match inputs.next() {
+            Some(&FnArg::SelfRef(ref arg)) if arg.mutability.is_none() => (),
+            _ => panic!("service method `{}` must take `&self`", ident),
+        }
+        let request = match inputs.next() {
+            Some(&FnArg::Captured(ref arg)) => Some(arg.ty.clone()),
+            Some(_) => panic!("unsupported argument of service method `{}`", ident),
+            None => None,
+        };
+        if inputs.next().is_some() {
+            panic!("service method `{}` takes at most one argument", ident);
+        }
+
+        if let Some(notification) = item_of(output, "Stream") {
+            if request.is_some() {
+                panic!("subscription `{}` takes no argument", ident);
+            }
+            Method::Subscription {
+                ident,
+                notification,
+            }
+        } else if let Some(response) = item_of(output, "Future") {
+            Method::Rpc {
+                ident,
+                request,
+                response,
+            }
+        } else {
+            panic!("service method `{}` must return a future or a stream", ident)
+        }
+    }
+
+    fn ident(&self) -> &Ident {
+        match *self {
+            Method::Rpc { ref ident, .. } | Method::Subscription { ref ident, .. } => ident,
+        }
+    }
+}
+
+/// Returns the `Item` type of the trait `name` in `ty`, such as the item of
+/// `Box<Future<Item = T, Error = E>>`.
+fn item_of(ty: &Type, name: &str) -> Option<Type> {
+    match *ty {
+        Type::Path(ref ty) => ty.path.segments.iter().filter_map(|segment| {
+            let args = match segment.arguments {
+                PathArguments::AngleBracketed(ref args) => &args.args,
+                _ => return None,
+            };
+            args.iter()
+                .filter_map(|arg| match *arg {
+                    GenericArgument::Binding(ref binding)
+                        if segment.ident == name && binding.ident == "Item" =>
+                    {
+                        Some(binding.ty.clone())
+                    }
+                    GenericArgument::Type(ref ty) => item_of(ty, name),
+                    _ => None,
+                })
+                .next()
+        }).next(),
+        Type::TraitObject(ref ty) => ty.bounds
+            .iter()
+            .filter_map(|bound| match *bound {
+                TypeParamBound::Trait(ref bound) => {
+                    item_of(&Type::Path(syn::TypePath {
+                        qself: None,
+                        path: bound.path.clone(),
+                    }), name)
+                }
+                _ => None,
+            })
+            .next(),
+        Type::Paren(ref ty) => item_of(&ty.elem, name),
+        _ => None,
+    }
+}
+
+/// Converts a `CamelCase` trait name to `snake_case`.
+fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() {
+            if i > 0 {
+                snake.push('_');
+            }
+            snake.extend(c.to_lowercase());
+        } else {
+            snake.push(c);
+        }
+    }
+    snake
+}
+
+fn expand(item: &ItemTrait) -> proc_macro2::TokenStream {
+    if !item.generics.params.is_empty() {
+        panic!("#[service] traits can't be generic");
+    }
+    let vis = &item.vis;
+    let name = &item.ident;
+    let methods: Vec<_> = item.items.iter().map(Method::parse).collect();
+    let topic = |method: &Method| {
+        let topic = format!("{}/{}", name, method.ident());
+        LitByteStr::new(topic.as_bytes(), Span::call_site())
+    };
+
+    let registrations = methods.iter().map(|method| {
+        let topic = topic(method);
+        match *method {
+            Method::Rpc {
+                ref ident,
+                request: Some(ref request),
+                ..
+            } => quote! {
+                {
+                    let service = __service::Arc::clone(&service);
+                    handler.on_rpc_typed(
+                        __service::Bytes::from_static(#topic),
+                        format.clone(),
+                        move |request: #request| service.#ident(request),
+                    );
+                }
+            },
+            Method::Rpc {
+                ref ident,
+                request: None,
+                ..
+            } => quote! {
+                {
+                    let service = __service::Arc::clone(&service);
+                    handler.on_rpc_typed(
+                        __service::Bytes::from_static(#topic),
+                        format.clone(),
+                        move |_: ()| service.#ident(),
+                    );
+                }
+            },
+            Method::Subscription { ref ident, .. } => quote! {
+                {
+                    let (sink, fut) = handler.on_subs_typed(
+                        __service::Bytes::from_static(#topic),
+                        format.clone(),
+                        |_: ()| -> Box<__service::Future<
+                            Item = Result<(), ()>,
+                            Error = __service::io::Error,
+                        > + Send + Sync> {
+                            Box::new(__service::ok(Ok(())))
+                        },
+                        |_: ()| -> Box<__service::Future<
+                            Item = (),
+                            Error = __service::io::Error,
+                        > + Send + Sync> {
+                            Box::new(__service::ok(()))
+                        },
+                    );
+                    let notify = service
+                        .#ident()
+                        .map(|notification| (__service::Bytes::from_static(#topic), notification))
+                        .map_err(|e| -> ::simproto::sim::typed::NotifyError { e.never_into() })
+                        .forward(sink)
+                        .map(|_| ())
+                        .recover(|_| ());
+                    futures.push(Box::new(fut));
+                    futures.push(Box::new(notify));
+                }
+            },
+        }
+    });
+
+    let client = Ident::new(&format!("{}Client", name), Span::call_site());
+    let calls = methods.iter().map(|method| {
+        let topic = topic(method);
+        match *method {
+            Method::Rpc {
+                ref ident,
+                ref request,
+                ref response,
+            } => {
+                let doc = format!("Calls `{}::{}` on the peer.", name, ident);
+                let (param, request) = match *request {
+                    Some(ref request) => (quote!(request: &#request), quote!(request)),
+                    None => (quote!(), quote!(&())),
+                };
+                quote! {
+                    #[doc = #doc]
+                    #vis fn #ident(self, #param) -> impl ::simproto::__service::Future<
+                        Item = (Self, Result<#response, ::simproto::sim::RpcResponse>),
+                        Error = ::simproto::__service::io::Error,
+                    > {
+                        let format = self.format;
+                        let call = self.requestor.rpc_typed(
+                            ::simproto::__service::Bytes::from_static(#topic),
+                            format.clone(),
+                            request,
+                        );
+                        ::simproto::__service::Future::map(call, move |(requestor, response)| {
+                            (#client { requestor, format }, response)
+                        })
+                    }
+                }
+            }
+            Method::Subscription {
+                ref ident,
+                ref notification,
+            } => {
+                let doc = format!("Subscribes to `{}::{}` on the peer.", name, ident);
+                quote! {
+                    #[doc = #doc]
+                    #vis fn #ident(self) -> impl ::simproto::__service::Future<
+                        Item = (
+                            Self,
+                            Result<
+                                ::simproto::sim::typed::Notifications<#notification, F>,
+                                ::simproto::sim::SubscriptionResponse,
+                            >,
+                        ),
+                        Error = ::simproto::__service::io::Error,
+                    > {
+                        let format = self.format;
+                        let sub = self.requestor.sub_typed::<(), (), #notification, F>(
+                            ::simproto::__service::Bytes::from_static(#topic),
+                            format.clone(),
+                            &(),
+                        );
+                        ::simproto::__service::Future::map(sub, move |(requestor, response)| {
+                            let response = response.map(|((), notifications)| notifications);
+                            (#client { requestor, format }, response)
+                        })
+                    }
+                }
+            }
+        }
+    });
+
+    let register = Ident::new(
+        &format!("register_{}", snake_case(&name.to_string())),
+        Span::call_site(),
+    );
+    let register_doc = format!(
+        "Registers the topics of the `{}` service implemented by `service` on `handler`.\n\n\
+         The returned future notifies the subscribers of the service and must be spawned.",
+        name
+    );
+    let client_doc = format!("Client of the `{}` service of a peer.", name);
+
+    quote! {
+        #item
+
+        #[doc = #register_doc]
+        #vis fn #register<T, F>(
+            handler: &::simproto::sim::Handler,
+            service: T,
+            format: F,
+        ) -> impl ::simproto::__service::Future<
+            Item = (),
+            Error = ::simproto::__service::Never,
+        > + Send
+        where
+            T: #name + Send + Sync + 'static,
+            F: ::simproto::sim::typed::Format,
+        {
+            #[allow(unused_imports)]
+            use ::simproto::__service::{self, Future, Sink, Stream};
+            let service = __service::Arc::new(service);
+            #[allow(unused_mut)]
+            let mut futures: Vec<Box<
+                __service::Future<Item = (), Error = __service::Never> + Send
+            >> = Vec::new();
+            #(#registrations)*
+            __service::join_all(futures).map(|_| ())
+        }
+
+        #[doc = #client_doc]
+        #[derive(Clone)]
+        #vis struct #client<F> {
+            requestor: ::simproto::sim::Requestor,
+            format: F,
+        }
+
+        impl<F: ::simproto::sim::typed::Format> #client<F> {
+            /// Wraps `requestor`, encoding messages in `format`.
+            #vis fn new(requestor: ::simproto::sim::Requestor, format: F) -> Self {
+                #client { requestor, format }
+            }
+
+            /// Returns the wrapped requestor.
+            #vis fn into_inner(self) -> ::simproto::sim::Requestor {
+                self.requestor
+            }
+
+            #(#calls)*
+        }
+    }
+}
@@ -0,0 +1,227 @@
+//! Framing over blocking `std::io` objects.
+//!
+//! This mirrors `Framed` for synchronous programs, using the same `Decoder`
+//! and `Encoder` traits without requiring an executor.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use codec::{Decoder, Encoder};
+
+use bytes::BytesMut;
+
+const INITIAL_CAPACITY: usize = 8 * 1024;
+
+/// Reads and writes frames on a blocking I/O object, using `Decoder` and
+/// `Encoder` to decode and encode them.
+///
+/// Reading requires `T: Read` and writing requires `T: Write`, so read-only
+/// or write-only objects like stdin and stdout can be framed as well.
+pub struct Framed<T, U> {
+    inner: T,
+    codec: U,
+    eof: bool,
+    is_readable: bool,
+    readbuf: BytesMut,
+    writebuf: BytesMut,
+}
+
+impl<T, U> Framed<T, U> {
+    /// Creates a new `Framed` over `inner` using `codec`.
+    pub fn new(inner: T, codec: U) -> Framed<T, U> {
+        Framed {
+            inner,
+            codec,
+            eof: false,
+            is_readable: false,
+            readbuf: BytesMut::with_capacity(INITIAL_CAPACITY),
+            writebuf: BytesMut::with_capacity(INITIAL_CAPACITY),
+        }
+    }
+
+    /// Returns a reference to the underlying I/O object.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the underlying I/O object.
+    ///
+    /// Note that care should be taken to not tamper with the underlying stream
+    /// of data as it may corrupt the stream of frames otherwise being worked
+    /// with.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Consumes the `Framed`, returning its underlying I/O object.
+    ///
+    /// Any data left in the read or write buffer is lost.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Returns a reference to the codec.
+    pub fn codec(&self) -> &U {
+        &self.codec
+    }
+
+    /// Returns a mutable reference to the codec.
+    pub fn codec_mut(&mut self) -> &mut U {
+        &mut self.codec
+    }
+
+    /// Returns a reference to the buffer of read but not yet decoded data.
+    pub fn read_buffer(&self) -> &BytesMut {
+        &self.readbuf
+    }
+
+    /// Returns a reference to the buffer of encoded but not yet written data.
+    pub fn write_buffer(&self) -> &BytesMut {
+        &self.writebuf
+    }
+}
+
+impl<T, U> Framed<T, U>
+where
+    T: Read,
+    U: Decoder,
+{
+    /// Blocks until a frame is decoded.
+    ///
+    /// Returns `None` once the underlying object reached EOF and all buffered
+    /// data has been decoded.
+    pub fn read_frame(&mut self) -> Result<Option<U::Item>, U::Error> {
+        let mut chunk = [0; INITIAL_CAPACITY];
+        loop {
+            // Same as the async `FramedRead`, decode as long as the decoder is
+            // readable and only read more data once it is not.
+            if self.is_readable {
+                if self.eof {
+                    return self.codec.decode_eof(&mut self.readbuf);
+                }
+
+                trace!("attempting to decode a frame");
+
+                if let Some(frame) = try!(self.codec.decode(&mut self.readbuf)) {
+                    trace!("frame decoded from buffer");
+                    return Ok(Some(frame));
+                }
+
+                self.is_readable = false;
+            }
+
+            let n = match self.inner.read(&mut chunk) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if n == 0 {
+                self.eof = true;
+            } else {
+                self.readbuf.extend_from_slice(&chunk[..n]);
+            }
+            self.is_readable = true;
+        }
+    }
+}
+
+impl<T, U> Framed<T, U>
+where
+    T: Write,
+    U: Encoder,
+{
+    /// Encodes a frame into the write buffer and writes it out, without
+    /// flushing the underlying object.
+    pub fn write_frame(&mut self, item: U::Item) -> Result<(), U::Error> {
+        try!(self.codec.encode(item, &mut self.writebuf));
+        self.write_buffered()
+    }
+
+    /// Writes out any buffered data and flushes the underlying object.
+    pub fn flush(&mut self) -> Result<(), U::Error> {
+        try!(self.write_buffered());
+        Ok(try!(self.inner.flush()))
+    }
+
+    /// Writes a frame and flushes the underlying object.
+    pub fn send(&mut self, item: U::Item) -> Result<(), U::Error> {
+        try!(self.write_frame(item));
+        self.flush()
+    }
+
+    fn write_buffered(&mut self) -> Result<(), U::Error> {
+        while !self.writebuf.is_empty() {
+            trace!("writing; remaining={}", self.writebuf.len());
+
+            let n = match self.inner.write(&self.writebuf) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write frame to transport",
+                ).into());
+            }
+
+            // Unwritten data stays buffered if a later write fails.
+            let _ = self.writebuf.split_to(n);
+        }
+        Ok(())
+    }
+}
+
+impl<T, U> Iterator for Framed<T, U>
+where
+    T: Read,
+    U: Decoder,
+{
+    type Item = Result<U::Item, U::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.read_frame() {
+            Ok(Some(frame)) => Some(Ok(frame)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl<T, U> fmt::Debug for Framed<T, U>
+where
+    T: fmt::Debug,
+    U: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Framed")
+            .field("io", &self.inner)
+            .field("codec", &self.codec)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use codec::LinesCodec;
+    use std::io::Cursor;
+
+    #[test]
+    fn read_frames() {
+        let io = Cursor::new(b"one\ntwo\nthree".to_vec());
+        let framed = Framed::new(io, LinesCodec::new());
+        let lines = framed.collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(lines, vec!["one", "two", "three"]);
+    }
+
+    #[test]
+    fn write_frames() {
+        let mut framed = Framed::new(Vec::new(), LinesCodec::new());
+        framed.write_frame("one".to_string()).unwrap();
+        framed.send("two".to_string()).unwrap();
+        assert!(framed.write_buffer().is_empty());
+        assert_eq!(&framed.get_ref()[..], b"one\ntwo\n");
+    }
+}
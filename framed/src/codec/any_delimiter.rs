@@ -0,0 +1,223 @@
+use std::{cmp, error, fmt, io, usize};
+
+use codec::{Decoder, Encoder};
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// A codec splitting a byte stream into chunks on any of a set of delimiters.
+///
+/// Decoded chunks do not contain the delimiter. Encoding appends
+/// `sequence_writer` to every chunk.
+///
+/// A chunk longer than the maximum chunk length is reported once with
+/// `AnyDelimiterCodecError::MaxChunkLengthExceeded`, then the codec discards
+/// bytes until the next delimiter and carries on with the following chunk.
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct AnyDelimiterCodec {
+    // Stored index of the next index to examine for the delimiter character.
+    // This is used to optimize searching.
+    next_index: usize,
+
+    // The maximum length of a chunk, without the delimiter.
+    max_length: usize,
+
+    // Whether an over-long chunk is being discarded.
+    is_discarding: bool,
+
+    // The bytes which end a chunk when decoding.
+    seek_delimiters: Vec<u8>,
+
+    // The bytes appended to every chunk when encoding.
+    sequence_writer: Vec<u8>,
+}
+
+impl AnyDelimiterCodec {
+    /// Creates an `AnyDelimiterCodec` without a maximum chunk length.
+    ///
+    /// Chunks end at any byte of `seek_delimiters`, encoded chunks are
+    /// followed by `sequence_writer`.
+    pub fn new(seek_delimiters: Vec<u8>, sequence_writer: Vec<u8>) -> AnyDelimiterCodec {
+        AnyDelimiterCodec {
+            next_index: 0,
+            max_length: usize::MAX,
+            is_discarding: false,
+            seek_delimiters,
+            sequence_writer,
+        }
+    }
+
+    /// Creates an `AnyDelimiterCodec` rejecting chunks longer than
+    /// `max_length` bytes.
+    pub fn new_with_max_length(
+        seek_delimiters: Vec<u8>,
+        sequence_writer: Vec<u8>,
+        max_length: usize,
+    ) -> AnyDelimiterCodec {
+        AnyDelimiterCodec {
+            max_length,
+            ..AnyDelimiterCodec::new(seek_delimiters, sequence_writer)
+        }
+    }
+
+    /// Returns the maximum chunk length.
+    pub fn max_length(&self) -> usize {
+        self.max_length
+    }
+}
+
+impl Decoder for AnyDelimiterCodec {
+    type Item = Bytes;
+    type Error = AnyDelimiterCodecError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Bytes>, AnyDelimiterCodecError> {
+        loop {
+            // Determine how far into the buffer we'll search for a delimiter. If
+            // there's no max_length set, we'll read to the end of the buffer.
+            let read_to = cmp::min(self.max_length.saturating_add(1), buf.len());
+
+            let new_chunk_offset = {
+                let seek_delimiters = &self.seek_delimiters;
+                buf[self.next_index..read_to]
+                    .iter()
+                    .position(|b| seek_delimiters.contains(b))
+            };
+
+            match (self.is_discarding, new_chunk_offset) {
+                (true, Some(offset)) => {
+                    // The over-long chunk ends here, skip it with its
+                    // delimiter and look for the next chunk.
+                    buf.split_to(offset + self.next_index + 1);
+                    self.is_discarding = false;
+                    self.next_index = 0;
+                }
+                (true, None) => {
+                    // Still discarding, drop everything searched so far.
+                    buf.split_to(read_to);
+                    self.next_index = 0;
+                    if buf.is_empty() {
+                        return Ok(None);
+                    }
+                }
+                (false, Some(offset)) => {
+                    let new_chunk_index = offset + self.next_index;
+                    self.next_index = 0;
+                    let mut chunk = buf.split_to(new_chunk_index + 1);
+                    chunk.truncate(new_chunk_index);
+                    return Ok(Some(chunk.freeze()));
+                }
+                (false, None) if buf.len() > self.max_length => {
+                    // The chunk is over-long, report it once and discard it.
+                    self.is_discarding = true;
+                    return Err(AnyDelimiterCodecError::MaxChunkLengthExceeded);
+                }
+                (false, None) => {
+                    // We didn't find a chunk or reach the length limit, so the next
+                    // call will resume searching at the current offset.
+                    self.next_index = read_to;
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    fn decode_eof(
+        &mut self,
+        buf: &mut BytesMut,
+    ) -> Result<Option<Bytes>, AnyDelimiterCodecError> {
+        Ok(match try!(self.decode(buf)) {
+            Some(frame) => Some(frame),
+            None => {
+                // No terminating delimiter - return remaining data, if any
+                if buf.is_empty() {
+                    None
+                } else {
+                    let chunk = buf.split_to(buf.len());
+                    self.next_index = 0;
+                    Some(chunk.freeze())
+                }
+            }
+        })
+    }
+}
+
+impl Encoder for AnyDelimiterCodec {
+    type Item = Bytes;
+    type Error = AnyDelimiterCodecError;
+
+    fn encode(&mut self, chunk: Bytes, buf: &mut BytesMut) -> Result<(), AnyDelimiterCodecError> {
+        buf.reserve(chunk.len() + self.sequence_writer.len());
+        buf.put_slice(&chunk);
+        buf.put_slice(&self.sequence_writer);
+        Ok(())
+    }
+}
+
+/// An error occurred while encoding or decoding a chunk.
+#[derive(Debug)]
+pub enum AnyDelimiterCodecError {
+    /// The maximum chunk length was exceeded.
+    MaxChunkLengthExceeded,
+    /// An IO error occurred.
+    Io(io::Error),
+}
+
+impl fmt::Display for AnyDelimiterCodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AnyDelimiterCodecError::MaxChunkLengthExceeded => {
+                write!(f, "max chunk length exceeded")
+            }
+            AnyDelimiterCodecError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for AnyDelimiterCodecError {
+    fn from(e: io::Error) -> AnyDelimiterCodecError {
+        AnyDelimiterCodecError::Io(e)
+    }
+}
+
+impl error::Error for AnyDelimiterCodecError {
+    fn description(&self) -> &str {
+        match self {
+            AnyDelimiterCodecError::MaxChunkLengthExceeded => "max chunk length exceeded",
+            AnyDelimiterCodecError::Io(e) => e.description(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_chunks() {
+        let mut codec = AnyDelimiterCodec::new(b",;".to_vec(), b";".to_vec());
+        let mut buf = BytesMut::from(&b"a,bc;d"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), &b"a"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), &b"bc"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert_eq!(codec.decode_eof(&mut buf).unwrap().unwrap(), &b"d"[..]);
+        assert_eq!(codec.decode_eof(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn discard_long_chunk() {
+        let mut codec = AnyDelimiterCodec::new_with_max_length(b",".to_vec(), b",".to_vec(), 2);
+        let mut buf = BytesMut::from(&b"long,ok,"[..]);
+        match codec.decode(&mut buf) {
+            Err(AnyDelimiterCodecError::MaxChunkLengthExceeded) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), &b"ok"[..]);
+    }
+
+    #[test]
+    fn encode_chunk() {
+        let mut codec = AnyDelimiterCodec::new(b",".to_vec(), b";\n".to_vec());
+        let mut buf = BytesMut::new();
+        codec.encode(Bytes::from(&b"chunk"[..]), &mut buf).unwrap();
+        assert_eq!(&buf[..], b"chunk;\n");
+    }
+}
@@ -0,0 +1,261 @@
+//! Frames encoded with Consistent Overhead Byte Stuffing.
+//!
+//! COBS removes every zero byte from a frame so that a single zero byte can
+//! delimit frames. After lost or corrupted bytes the decoder drops the broken
+//! frame and resynchronises on the next delimiter, which makes it suitable for
+//! unreliable byte streams such as serial links.
+
+use std::{cmp, io, usize};
+
+use codec::{Decoder, Encoder};
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+const DELIMITER: u8 = 0;
+
+/// Maximum length of a block of non-zero bytes.
+const MAX_BLOCK: u8 = 0xFF;
+
+/// Encodes and decodes COBS frames delimited by zero bytes.
+///
+/// Every encoded frame is preceded and followed by a delimiter, so that a
+/// frame following line noise is decoded correctly. Consecutive delimiters
+/// are ignored.
+///
+/// Frames which can't be decoded or which are longer than the maximum frame
+/// length are dropped with a warning instead of failing the stream.
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct CobsCodec {
+    // Stored index of the next index to examine for the delimiter.
+    next_index: usize,
+
+    // The maximum length of a decoded frame.
+    max_length: usize,
+
+    // Whether an over-long frame is being discarded.
+    is_discarding: bool,
+}
+
+impl CobsCodec {
+    /// Creates a `CobsCodec` with a maximum frame length of 8MB.
+    pub fn new() -> CobsCodec {
+        CobsCodec::new_with_max_length(8 * 1_024 * 1_024)
+    }
+
+    /// Creates a `CobsCodec` dropping frames longer than `max_length` bytes.
+    pub fn new_with_max_length(max_length: usize) -> CobsCodec {
+        CobsCodec {
+            next_index: 0,
+            max_length,
+            is_discarding: false,
+        }
+    }
+
+    /// Returns the maximum frame length.
+    pub fn max_length(&self) -> usize {
+        self.max_length
+    }
+
+    // Maximum length of an encoded frame without its delimiters.
+    fn max_encoded_length(&self) -> usize {
+        self.max_length
+            .saturating_add(self.max_length / (MAX_BLOCK as usize - 1))
+            .saturating_add(1)
+    }
+}
+
+impl Default for CobsCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for CobsCodec {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        let max_encoded_length = self.max_encoded_length();
+        loop {
+            let read_to = cmp::min(max_encoded_length.saturating_add(1), buf.len());
+
+            let delimiter_offset = buf[self.next_index..read_to]
+                .iter()
+                .position(|b| *b == DELIMITER);
+
+            match (self.is_discarding, delimiter_offset) {
+                (true, Some(offset)) => {
+                    buf.split_to(offset + self.next_index + 1);
+                    self.is_discarding = false;
+                    self.next_index = 0;
+                }
+                (true, None) => {
+                    buf.split_to(read_to);
+                    self.next_index = 0;
+                    if buf.is_empty() {
+                        return Ok(None);
+                    }
+                }
+                (false, Some(offset)) => {
+                    let delimiter_index = offset + self.next_index;
+                    self.next_index = 0;
+                    let mut frame = buf.split_to(delimiter_index + 1);
+                    frame.truncate(delimiter_index);
+                    if frame.is_empty() {
+                        continue;
+                    }
+                    match decode_frame(&frame) {
+                        Some(decoded) => return Ok(Some(decoded)),
+                        None => warn!("dropping corrupt frame of {} bytes", frame.len()),
+                    }
+                }
+                (false, None) if buf.len() > max_encoded_length => {
+                    warn!("dropping frame longer than {} bytes", self.max_length);
+                    self.is_discarding = true;
+                }
+                (false, None) => {
+                    self.next_index = read_to;
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        let frame = try!(self.decode(buf));
+        if frame.is_none() && !buf.is_empty() {
+            // The stream ended within a frame, which can't be told apart from
+            // a frame with lost bytes.
+            warn!("dropping unterminated frame of {} bytes", buf.len());
+            buf.clear();
+            self.next_index = 0;
+            self.is_discarding = false;
+        }
+        Ok(frame)
+    }
+}
+
+impl Encoder for CobsCodec {
+    type Item = Bytes;
+    type Error = io::Error;
+
+    fn encode(&mut self, data: Bytes, dst: &mut BytesMut) -> io::Result<()> {
+        if data.len() > self.max_length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "frame longer than the maximum frame length",
+            ));
+        }
+
+        dst.reserve(data.len() + data.len() / (MAX_BLOCK as usize - 1) + 3);
+        dst.put_u8(DELIMITER);
+
+        let mut code_index = dst.len();
+        let mut code = 1;
+        dst.put_u8(code);
+        for b in data.iter() {
+            if *b == DELIMITER {
+                dst[code_index] = code;
+                code_index = dst.len();
+                code = 1;
+                dst.put_u8(code);
+            } else {
+                dst.put_u8(*b);
+                code += 1;
+                if code == MAX_BLOCK {
+                    dst[code_index] = code;
+                    code_index = dst.len();
+                    code = 1;
+                    dst.put_u8(code);
+                }
+            }
+        }
+        dst[code_index] = code;
+
+        dst.put_u8(DELIMITER);
+        Ok(())
+    }
+}
+
+/// Decodes a frame without its delimiter, returns `None` if it is corrupt.
+fn decode_frame(src: &[u8]) -> Option<BytesMut> {
+    let mut dst = BytesMut::with_capacity(src.len());
+    let mut i = 0;
+    while i < src.len() {
+        let code = src[i];
+        i += 1;
+        let end = i + code as usize - 1;
+        if end > src.len() {
+            return None;
+        }
+        dst.put_slice(&src[i..end]);
+        i = end;
+        // A full block is not followed by an implicit zero.
+        if code != MAX_BLOCK && i < src.len() {
+            dst.put_u8(DELIMITER);
+        }
+    }
+    Some(dst)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn encode(codec: &mut CobsCodec, data: &[u8]) -> BytesMut {
+        let mut buf = BytesMut::new();
+        codec.encode(Bytes::from(data), &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn encode_frame() {
+        let mut codec = CobsCodec::new();
+        let buf = encode(&mut codec, &[0x11, 0x22, 0x00, 0x33]);
+        assert_eq!(&buf[..], &[0x00, 0x03, 0x11, 0x22, 0x02, 0x33, 0x00][..]);
+        let buf = encode(&mut codec, &[0x00]);
+        assert_eq!(&buf[..], &[0x00, 0x01, 0x01, 0x00][..]);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut codec = CobsCodec::new();
+        let long = (0..600).map(|i| (i % 255) as u8 + 1).collect::<Vec<_>>();
+        let frames = vec![
+            &b""[..],
+            &b"\0"[..],
+            &b"\0\0ab\0"[..],
+            &long[..254],
+            &long[..],
+        ];
+        let mut buf = BytesMut::new();
+        for frame in &frames {
+            codec.encode(Bytes::from(*frame), &mut buf).unwrap();
+        }
+        for frame in &frames {
+            assert_eq!(&codec.decode(&mut buf).unwrap().unwrap()[..], *frame);
+        }
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn resync_after_lost_byte() {
+        let mut codec = CobsCodec::new();
+        let mut buf = encode(&mut codec, b"\0first");
+        // Drop the code byte of the last block.
+        let broken = buf.len() - 7;
+        let tail = buf.split_off(broken);
+        buf.extend_from_slice(&tail[1..]);
+        buf.extend_from_slice(&encode(&mut codec, b"second"));
+
+        assert_eq!(&codec.decode(&mut buf).unwrap().unwrap()[..], b"second");
+    }
+
+    #[test]
+    fn drop_long_frame() {
+        let mut codec = CobsCodec::new_with_max_length(4);
+        let mut buf = BytesMut::from(&b"\x0a123456789\x00"[..]);
+        buf.extend_from_slice(&encode(&mut codec, b"ok"));
+        assert_eq!(&codec.decode(&mut buf).unwrap().unwrap()[..], b"ok");
+    }
+}
@@ -0,0 +1,367 @@
+//! Combinators layering transformations on top of codecs.
+//!
+//! `CodecExt` is implemented for every type and provides adapters mapping the
+//! items and errors of a codec, and `layer` for running a byte-level codec
+//! such as compression or checksums on each frame of a framing codec.
+
+use std::io;
+
+use codec::{Decoder, Encoder};
+
+use bytes::BytesMut;
+
+/// Adapters for `Decoder` and `Encoder` implementations.
+pub trait CodecExt: Sized {
+    /// Maps decoded items with `f`. Encoding is unchanged.
+    fn map_decode<F, I>(self, f: F) -> MapDecode<Self, F>
+    where
+        Self: Decoder,
+        F: FnMut(<Self as Decoder>::Item) -> I,
+    {
+        MapDecode { inner: self, f }
+    }
+
+    /// Maps items with `f` before encoding them. Decoding is unchanged.
+    fn map_encode<F, I>(self, f: F) -> MapEncode<Self, F>
+    where
+        Self: Encoder,
+        F: FnMut(I) -> <Self as Encoder>::Item,
+    {
+        MapEncode { inner: self, f }
+    }
+
+    /// Maps decoding and encoding errors with `f`.
+    ///
+    /// Not named `map_err`, which would be ambiguous with the method of
+    /// futures, streams and `Result` wherever `CodecExt` is imported.
+    fn map_codec_err<F>(self, f: F) -> MapErr<Self, F> {
+        MapErr { inner: self, f }
+    }
+
+    /// Runs `inner` on every frame produced by this codec.
+    ///
+    /// When decoding, each complete frame is handed to `inner.decode_eof` and
+    /// frames for which it returns `None` are skipped. When encoding, items
+    /// are encoded with `inner` and the resulting bytes are encoded as a frame
+    /// by this codec.
+    fn layer<I>(self, inner: I) -> Layered<Self, I> {
+        Layered { outer: self, inner }
+    }
+}
+
+impl<T> CodecExt for T {}
+
+/// Codec returned by `CodecExt::map_decode`.
+#[derive(Clone, Debug)]
+pub struct MapDecode<T, F> {
+    inner: T,
+    f: F,
+}
+
+impl<T, F> MapDecode<T, F> {
+    /// Returns a reference to the wrapped codec.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the wrapped codec.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Consumes the adapter, returning the wrapped codec.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T, F, I> Decoder for MapDecode<T, F>
+where
+    T: Decoder,
+    F: FnMut(T::Item) -> I,
+{
+    type Item = I;
+    type Error = T::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<I>, T::Error> {
+        Ok(try!(self.inner.decode(src)).map(&mut self.f))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<I>, T::Error> {
+        Ok(try!(self.inner.decode_eof(src)).map(&mut self.f))
+    }
+}
+
+impl<T: Encoder, F> Encoder for MapDecode<T, F> {
+    type Item = T::Item;
+    type Error = T::Error;
+
+    fn encode(&mut self, item: T::Item, dst: &mut BytesMut) -> Result<(), T::Error> {
+        self.inner.encode(item, dst)
+    }
+}
+
+/// Codec returned by `CodecExt::map_encode`.
+#[derive(Clone, Debug)]
+pub struct MapEncode<T, F> {
+    inner: T,
+    f: F,
+}
+
+impl<T, F> MapEncode<T, F> {
+    /// Returns a reference to the wrapped codec.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the wrapped codec.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Consumes the adapter, returning the wrapped codec.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Decoder, F> Decoder for MapEncode<T, F> {
+    type Item = T::Item;
+    type Error = T::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<T::Item>, T::Error> {
+        self.inner.decode(src)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<T::Item>, T::Error> {
+        self.inner.decode_eof(src)
+    }
+}
+
+impl<T, F, I> Encoder for MapEncode<T, F>
+where
+    T: Encoder,
+    F: FnMut(I) -> T::Item,
+{
+    type Item = I;
+    type Error = T::Error;
+
+    fn encode(&mut self, item: I, dst: &mut BytesMut) -> Result<(), T::Error> {
+        let item = (self.f)(item);
+        self.inner.encode(item, dst)
+    }
+}
+
+/// Codec returned by `CodecExt::map_codec_err`.
+#[derive(Clone, Debug)]
+pub struct MapErr<T, F> {
+    inner: T,
+    f: F,
+}
+
+impl<T, F> MapErr<T, F> {
+    /// Returns a reference to the wrapped codec.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the wrapped codec.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Consumes the adapter, returning the wrapped codec.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T, F, E> Decoder for MapErr<T, F>
+where
+    T: Decoder,
+    F: FnMut(<T as Decoder>::Error) -> E,
+    E: From<io::Error>,
+{
+    type Item = T::Item;
+    type Error = E;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<T::Item>, E> {
+        self.inner.decode(src).map_err(&mut self.f)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<T::Item>, E> {
+        self.inner.decode_eof(src).map_err(&mut self.f)
+    }
+}
+
+impl<T, F, E> Encoder for MapErr<T, F>
+where
+    T: Encoder,
+    F: FnMut(<T as Encoder>::Error) -> E,
+    E: From<io::Error>,
+{
+    type Item = T::Item;
+    type Error = E;
+
+    fn encode(&mut self, item: T::Item, dst: &mut BytesMut) -> Result<(), E> {
+        self.inner.encode(item, dst).map_err(&mut self.f)
+    }
+}
+
+/// Codec returned by `CodecExt::layer`.
+#[derive(Clone, Debug)]
+pub struct Layered<O, I> {
+    outer: O,
+    inner: I,
+}
+
+impl<O, I> Layered<O, I> {
+    /// Returns a reference to the framing codec.
+    pub fn outer(&self) -> &O {
+        &self.outer
+    }
+
+    /// Returns a reference to the codec run on every frame.
+    pub fn inner(&self) -> &I {
+        &self.inner
+    }
+
+    /// Consumes the adapter, returning the framing codec and the codec run on
+    /// every frame.
+    pub fn into_parts(self) -> (O, I) {
+        (self.outer, self.inner)
+    }
+
+    fn decode_frame(&mut self, frame: O::Item) -> Result<Option<I::Item>, I::Error>
+    where
+        O: Decoder,
+        O::Item: Into<BytesMut>,
+        I: Decoder,
+    {
+        let mut frame = frame.into();
+        self.inner.decode_eof(&mut frame)
+    }
+}
+
+impl<O, I> Decoder for Layered<O, I>
+where
+    O: Decoder,
+    O::Item: Into<BytesMut>,
+    I: Decoder,
+    <I as Decoder>::Error: From<<O as Decoder>::Error>,
+{
+    type Item = I::Item;
+    type Error = I::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<I::Item>, I::Error> {
+        while let Some(frame) = try!(self.outer.decode(src)) {
+            if let Some(item) = try!(self.decode_frame(frame)) {
+                return Ok(Some(item));
+            }
+        }
+        Ok(None)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<I::Item>, I::Error> {
+        while let Some(frame) = try!(self.outer.decode_eof(src)) {
+            if let Some(item) = try!(self.decode_frame(frame)) {
+                return Ok(Some(item));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<O, I> Encoder for Layered<O, I>
+where
+    O: Encoder,
+    O::Item: From<BytesMut>,
+    I: Encoder,
+    <I as Encoder>::Error: From<<O as Encoder>::Error>,
+{
+    type Item = I::Item;
+    type Error = I::Error;
+
+    fn encode(&mut self, item: I::Item, dst: &mut BytesMut) -> Result<(), I::Error> {
+        let mut frame = BytesMut::new();
+        try!(self.inner.encode(item, &mut frame));
+        Ok(try!(self.outer.encode(<O as Encoder>::Item::from(frame), dst)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::{BufMut, Bytes};
+    use codec::{LengthDelimitedCodec, LinesCodec, LinesCodecError};
+
+    // Xors every byte with a key and drops empty frames.
+    struct Xor(u8);
+
+    impl Decoder for Xor {
+        type Item = Bytes;
+        type Error = io::Error;
+
+        fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Bytes>> {
+            if src.is_empty() {
+                return Ok(None);
+            }
+            let key = self.0;
+            let data = src.take().iter().map(|b| b ^ key).collect::<Vec<_>>();
+            Ok(Some(Bytes::from(data)))
+        }
+    }
+
+    impl Encoder for Xor {
+        type Item = Bytes;
+        type Error = io::Error;
+
+        fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> io::Result<()> {
+            dst.reserve(item.len());
+            for b in item.iter() {
+                dst.put_u8(b ^ self.0);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn map_items() {
+        let mut codec = LinesCodec::new()
+            .map_decode(|line: String| line.len())
+            .map_encode(|n: usize| "x".repeat(n));
+        let mut buf = BytesMut::new();
+        codec.encode(3, &mut buf).unwrap();
+        assert_eq!(&buf[..], b"xxx\n");
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(3));
+    }
+
+    #[test]
+    fn map_errors() {
+        let mut codec =
+            LinesCodec::new_with_max_length(2).map_codec_err(|e: LinesCodecError| match e {
+                LinesCodecError::MaxLineLengthExceeded => {
+                    io::Error::new(io::ErrorKind::Other, "long")
+                }
+                LinesCodecError::Io(e) => e,
+            });
+        let mut buf = BytesMut::from(&b"long\n"[..]);
+        let err = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+    }
+
+    #[test]
+    fn layered_round_trip() {
+        let mut codec = LengthDelimitedCodec::new().layer(Xor(0x55));
+        let mut buf = BytesMut::new();
+        codec.encode(Bytes::from(&b"first"[..]), &mut buf).unwrap();
+        codec.encode(Bytes::new(), &mut buf).unwrap();
+        codec.encode(Bytes::from(&b"second"[..]), &mut buf).unwrap();
+        assert_eq!(&buf[4..9], &[0x33, 0x3c, 0x27, 0x26, 0x21][..]);
+
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), &b"first"[..]);
+        // The empty frame is skipped by the inner codec.
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), &b"second"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    }
+}
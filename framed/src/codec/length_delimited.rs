@@ -0,0 +1,477 @@
+//! Frames delimited by a length field in their header.
+//!
+//! A `LengthDelimitedCodec` is created with `LengthDelimitedCodec::new` for
+//! the common case of a four byte big endian length prefix, or configured
+//! with a `Builder` for other header layouts.
+
+use std::{cmp, fmt, io};
+
+use codec::{Decoder, Encoder};
+
+use bytes::{BigEndian, BufMut, ByteOrder, Bytes, BytesMut, LittleEndian};
+
+/// Maximum number of bytes of a varint length field.
+const MAX_VARINT_LEN: usize = 10;
+
+/// Encodes and decodes frames delimited by a length field.
+///
+/// Decoded frames are the bytes following the first `num_skip` bytes of the
+/// frame, by default the bytes following the length field.
+#[derive(Debug, Clone)]
+pub struct LengthDelimitedCodec {
+    builder: Builder,
+    state: DecodeState,
+}
+
+/// Configures a `LengthDelimitedCodec`.
+#[derive(Debug, Clone, Copy)]
+pub struct Builder {
+    // Maximum frame length
+    max_frame_len: usize,
+
+    // Width of the length field
+    length_field: LengthField,
+
+    // Number of bytes in the header before the length field
+    length_field_offset: usize,
+
+    // Adjust the length specified in the header field by this amount
+    length_adjustment: isize,
+
+    // Total number of bytes to skip before reading the payload, if not set,
+    // `length_field_offset + length_field_len`
+    num_skip: Option<usize>,
+
+    // Length field byte order (little or big endian)
+    big_endian: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LengthField {
+    Fixed(usize),
+    Varint,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum DecodeState {
+    Head,
+    Data(usize),
+}
+
+/// Error returned when a frame exceeds the maximum frame length.
+pub struct FrameTooBig {
+    _priv: (),
+}
+
+// ===== impl LengthDelimitedCodec =====
+
+impl LengthDelimitedCodec {
+    /// Creates a codec with the default configuration, see `Builder::new`.
+    pub fn new() -> LengthDelimitedCodec {
+        Builder::new().new_codec()
+    }
+
+    /// Creates a `Builder` to configure a codec.
+    pub fn builder() -> Builder {
+        Builder::new()
+    }
+
+    /// Returns the maximum frame length.
+    pub fn max_frame_length(&self) -> usize {
+        self.builder.max_frame_len
+    }
+
+    /// Updates the maximum frame length, frames longer than `val` fail to
+    /// decode and encode.
+    pub fn set_max_frame_length(&mut self, val: usize) {
+        self.builder.max_frame_length(val);
+    }
+
+    fn decode_head(&mut self, src: &mut BytesMut) -> io::Result<Option<usize>> {
+        let offset = self.builder.length_field_offset;
+        if src.len() < offset {
+            return Ok(None);
+        }
+
+        let (field_len, n) = match self.builder.length_field {
+            LengthField::Fixed(field_len) => {
+                if src.len() < offset + field_len {
+                    return Ok(None);
+                }
+                let field = &src[offset..offset + field_len];
+                let n = if self.builder.big_endian {
+                    BigEndian::read_uint(field, field_len)
+                } else {
+                    LittleEndian::read_uint(field, field_len)
+                };
+                (field_len, n)
+            }
+            LengthField::Varint => match try!(read_varint(&src[offset..])) {
+                Some(field) => field,
+                None => return Ok(None),
+            },
+        };
+        let head_len = offset + field_len;
+
+        let n = if self.builder.length_adjustment < 0 {
+            n.checked_sub(-self.builder.length_adjustment as u64)
+        } else {
+            n.checked_add(self.builder.length_adjustment as u64)
+        };
+        let n = match n {
+            Some(n) if n <= self.builder.max_frame_len as u64 => n as usize,
+            Some(_) => return Err(FrameTooBig { _priv: () }.into()),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "provided length would overflow after adjustment",
+                ))
+            }
+        };
+
+        let num_skip = self.builder.num_skip.unwrap_or(head_len);
+        if num_skip > head_len + n {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "frame is shorter than the bytes to skip",
+            ));
+        }
+        src.split_to(num_skip);
+
+        // Length of the remaining frame after skipping
+        let n = head_len + n - num_skip;
+
+        // Ensure that the buffer has enough space to read the incoming
+        // payload
+        src.reserve(n);
+
+        Ok(Some(n))
+    }
+
+    fn decode_data(&self, n: usize, src: &mut BytesMut) -> Option<BytesMut> {
+        if src.len() < n {
+            return None;
+        }
+        Some(src.split_to(n))
+    }
+}
+
+impl Decoder for LengthDelimitedCodec {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        let n = match self.state {
+            DecodeState::Head => match try!(self.decode_head(src)) {
+                Some(n) => {
+                    self.state = DecodeState::Data(n);
+                    n
+                }
+                None => return Ok(None),
+            },
+            DecodeState::Data(n) => n,
+        };
+
+        match self.decode_data(n, src) {
+            Some(data) => {
+                // Update the decode state
+                self.state = DecodeState::Head;
+
+                // Make sure the buffer has enough space to read the next head
+                src.reserve(self.builder.num_head_bytes());
+
+                Ok(Some(data))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder for LengthDelimitedCodec {
+    type Item = Bytes;
+    type Error = io::Error;
+
+    /// Writes the length field followed by `data`.
+    ///
+    /// Only the length field is written as header, `length_field_offset` and
+    /// `num_skip` are not taken into account.
+    fn encode(&mut self, data: Bytes, dst: &mut BytesMut) -> io::Result<()> {
+        let n = data.len();
+        if n > self.builder.max_frame_len {
+            return Err(FrameTooBig { _priv: () }.into());
+        }
+
+        // Adjust `n` with bounds checking
+        let n = if self.builder.length_adjustment < 0 {
+            n.checked_add(-self.builder.length_adjustment as usize)
+        } else {
+            n.checked_sub(self.builder.length_adjustment as usize)
+        };
+        let n = try!(n.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "provided length would overflow after adjustment",
+            )
+        })) as u64;
+
+        match self.builder.length_field {
+            LengthField::Fixed(field_len) => {
+                if field_len < 8 && n >> (field_len * 8) != 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "frame length does not fit into the length field",
+                    ));
+                }
+                dst.reserve(field_len + data.len());
+                if self.builder.big_endian {
+                    dst.put_uint_be(n, field_len);
+                } else {
+                    dst.put_uint_le(n, field_len);
+                }
+            }
+            LengthField::Varint => {
+                dst.reserve(MAX_VARINT_LEN + data.len());
+                write_varint(n, dst);
+            }
+        }
+
+        dst.put_slice(&data);
+        Ok(())
+    }
+}
+
+/// Reads a LEB128 varint, returning the number of bytes read and its value.
+fn read_varint(src: &[u8]) -> io::Result<Option<(usize, u64)>> {
+    let mut n = 0u64;
+    for (i, &byte) in src.iter().take(MAX_VARINT_LEN).enumerate() {
+        n |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some((i + 1, n)));
+        }
+    }
+    if src.len() >= MAX_VARINT_LEN {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "varint length field is too long",
+        ))
+    } else {
+        Ok(None)
+    }
+}
+
+fn write_varint(mut n: u64, dst: &mut BytesMut) {
+    while n >= 0x80 {
+        dst.put_u8((n as u8 & 0x7f) | 0x80);
+        n >>= 7;
+    }
+    dst.put_u8(n as u8);
+}
+
+// ===== impl Builder =====
+
+impl Builder {
+    /// Creates a builder with the default configuration:
+    ///
+    /// * a four byte big endian length field at the start of the frame,
+    /// * the length field holding the length of the payload,
+    /// * the length field being skipped when decoding,
+    /// * a maximum frame length of 8MB.
+    pub fn new() -> Builder {
+        Builder {
+            // Default max frame length of 8MB
+            max_frame_len: 8 * 1_024 * 1_024,
+
+            // Default byte length of 4
+            length_field: LengthField::Fixed(4),
+
+            // Default to the header field being at the start of the header.
+            length_field_offset: 0,
+
+            length_adjustment: 0,
+
+            // Total number of bytes to skip before reading the payload, if not set,
+            // `length_field_offset + length_field_len`
+            num_skip: None,
+
+            // Default to reading the length field in network (big) endian.
+            big_endian: true,
+        }
+    }
+
+    /// Reads and writes the length field in big endian (network) byte order.
+    pub fn big_endian(&mut self) -> &mut Self {
+        self.big_endian = true;
+        self
+    }
+
+    /// Reads and writes the length field in little endian byte order.
+    pub fn little_endian(&mut self) -> &mut Self {
+        self.big_endian = false;
+        self
+    }
+
+    /// Sets the maximum frame length, longer frames fail to decode and encode.
+    pub fn max_frame_length(&mut self, val: usize) -> &mut Self {
+        self.max_frame_len = val;
+        self
+    }
+
+    /// Sets the width of the length field in bytes.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `val` is not 1, 2, 4 or 8.
+    pub fn length_field_length(&mut self, val: usize) -> &mut Self {
+        assert!(
+            val == 1 || val == 2 || val == 4 || val == 8,
+            "invalid length field length"
+        );
+        self.length_field = LengthField::Fixed(val);
+        self
+    }
+
+    /// Uses an unsigned LEB128 varint as the length field.
+    ///
+    /// The byte order setting does not apply to a varint length field.
+    pub fn varint_length_field(&mut self) -> &mut Self {
+        self.length_field = LengthField::Varint;
+        self
+    }
+
+    /// Sets the number of bytes in the header before the length field.
+    pub fn length_field_offset(&mut self, val: usize) -> &mut Self {
+        self.length_field_offset = val;
+        self
+    }
+
+    /// Sets the value added to the length field to get the number of bytes
+    /// following the length field.
+    pub fn length_adjustment(&mut self, val: isize) -> &mut Self {
+        self.length_adjustment = val;
+        self
+    }
+
+    /// Sets the number of bytes to skip at the start of a frame before the
+    /// decoded frame begins.
+    ///
+    /// Defaults to the length of the header up to the end of the length field.
+    pub fn num_skip(&mut self, val: usize) -> &mut Self {
+        self.num_skip = Some(val);
+        self
+    }
+
+    /// Creates a `LengthDelimitedCodec` with this configuration.
+    pub fn new_codec(&self) -> LengthDelimitedCodec {
+        LengthDelimitedCodec {
+            builder: *self,
+            state: DecodeState::Head,
+        }
+    }
+
+    fn num_head_bytes(&self) -> usize {
+        let field_len = match self.length_field {
+            LengthField::Fixed(field_len) => field_len,
+            LengthField::Varint => 1,
+        };
+        let num = self.length_field_offset + field_len;
+        cmp::max(num, self.num_skip.unwrap_or(0))
+    }
+}
+
+// ===== impl FrameTooBig =====
+
+impl fmt::Debug for FrameTooBig {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("FrameTooBig").finish()
+    }
+}
+
+impl fmt::Display for FrameTooBig {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("frame size too big")
+    }
+}
+
+impl ::std::error::Error for FrameTooBig {
+    fn description(&self) -> &str {
+        "frame size too big"
+    }
+}
+
+impl From<FrameTooBig> for io::Error {
+    fn from(err: FrameTooBig) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode_all(codec: &mut LengthDelimitedCodec, src: &[u8]) -> Vec<BytesMut> {
+        let mut buf = BytesMut::from(src);
+        let mut frames = Vec::new();
+        while let Some(frame) = codec.decode(&mut buf).unwrap() {
+            frames.push(frame);
+        }
+        assert!(buf.is_empty());
+        frames
+    }
+
+    #[test]
+    fn default_round_trip() {
+        let mut codec = LengthDelimitedCodec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(Bytes::from(&b"abc"[..]), &mut buf).unwrap();
+        assert_eq!(&buf[..], &[0, 0, 0, 3, b'a', b'b', b'c']);
+        assert_eq!(decode_all(&mut codec, &buf), vec![BytesMut::from(&b"abc"[..])]);
+    }
+
+    #[test]
+    fn partial_frame() {
+        let mut codec = LengthDelimitedCodec::new();
+        let mut buf = BytesMut::from(&[0u8, 0, 0][..]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(&[2, b'a']);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(&[b'b']);
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), &b"ab"[..]);
+    }
+
+    #[test]
+    fn little_endian_with_offset_and_adjustment() {
+        // One type byte, a two byte length counting the whole frame, then the payload.
+        let mut codec = LengthDelimitedCodec::builder()
+            .little_endian()
+            .length_field_length(2)
+            .length_field_offset(1)
+            .length_adjustment(-3)
+            .num_skip(0)
+            .new_codec();
+        let frames = decode_all(&mut codec, &[9, 5, 0, b'h', b'i']);
+        assert_eq!(frames, vec![BytesMut::from(&[9u8, 5, 0, b'h', b'i'][..])]);
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        let mut codec = LengthDelimitedCodec::builder()
+            .varint_length_field()
+            .new_codec();
+        let data = Bytes::from(vec![7u8; 300]);
+        let mut buf = BytesMut::new();
+        codec.encode(data.clone(), &mut buf).unwrap();
+        assert_eq!(&buf[..2], &[0xac, 0x02]);
+        assert_eq!(decode_all(&mut codec, &buf), vec![BytesMut::from(&data[..])]);
+    }
+
+    #[test]
+    fn frame_too_big() {
+        let mut codec = LengthDelimitedCodec::builder()
+            .length_field_length(1)
+            .max_frame_length(4)
+            .new_codec();
+        let mut buf = BytesMut::from(&[5u8, 1, 2, 3, 4, 5][..]);
+        assert!(codec.decode(&mut buf).is_err());
+        assert!(codec.encode(Bytes::from(&[0u8; 5][..]), &mut buf).is_err());
+    }
+}
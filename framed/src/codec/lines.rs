@@ -0,0 +1,235 @@
+use std::{cmp, error, fmt, io, str, usize};
+
+use codec::{Decoder, Encoder};
+
+use bytes::{BufMut, BytesMut};
+
+/// A codec splitting a byte stream into lines.
+///
+/// Lines are delimited by `\n` and a trailing `\r` is removed. Decoded lines
+/// must be valid UTF-8. Encoding appends `\n` to every line.
+///
+/// A line longer than the maximum line length is reported once with
+/// `LinesCodecError::MaxLineLengthExceeded`, then the codec discards bytes
+/// until the next `\n` and carries on with the following line.
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct LinesCodec {
+    // Stored index of the next index to examine for a `\n` character.
+    // This is used to optimize searching.
+    // For example, if `decode` was called with `abc`, it would hold `3`,
+    // because that is the next index to examine.
+    // The next time `decode` is called with `abcde\n`, the method will
+    // only look at `de\n` before returning.
+    next_index: usize,
+
+    // The maximum length of a line, without the delimiter.
+    max_length: usize,
+
+    // Whether an over-long line is being discarded.
+    is_discarding: bool,
+}
+
+impl LinesCodec {
+    /// Creates a `LinesCodec` without a maximum line length.
+    ///
+    /// A peer which never sends a newline makes the read buffer grow without
+    /// bounds, prefer `new_with_max_length` for untrusted input.
+    pub fn new() -> LinesCodec {
+        LinesCodec {
+            next_index: 0,
+            max_length: usize::MAX,
+            is_discarding: false,
+        }
+    }
+
+    /// Creates a `LinesCodec` rejecting lines longer than `max_length` bytes.
+    pub fn new_with_max_length(max_length: usize) -> LinesCodec {
+        LinesCodec {
+            max_length,
+            ..LinesCodec::new()
+        }
+    }
+
+    /// Returns the maximum line length.
+    pub fn max_length(&self) -> usize {
+        self.max_length
+    }
+}
+
+fn utf8(buf: &[u8]) -> Result<&str, io::Error> {
+    str::from_utf8(buf)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "unable to decode input as UTF8"))
+}
+
+fn without_carriage_return(s: &[u8]) -> &[u8] {
+    if let Some(&b'\r') = s.last() {
+        &s[..s.len() - 1]
+    } else {
+        s
+    }
+}
+
+impl Decoder for LinesCodec {
+    type Item = String;
+    type Error = LinesCodecError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<String>, LinesCodecError> {
+        loop {
+            // Determine how far into the buffer we'll search for a newline. If
+            // there's no max_length set, we'll read to the end of the buffer.
+            let read_to = cmp::min(self.max_length.saturating_add(1), buf.len());
+
+            let newline_offset = buf[self.next_index..read_to]
+                .iter()
+                .position(|b| *b == b'\n');
+
+            match (self.is_discarding, newline_offset) {
+                (true, Some(offset)) => {
+                    // The over-long line ends here, skip it with its newline
+                    // and look for the next line.
+                    buf.split_to(offset + self.next_index + 1);
+                    self.is_discarding = false;
+                    self.next_index = 0;
+                }
+                (true, None) => {
+                    // Still discarding, drop everything searched so far.
+                    buf.split_to(read_to);
+                    self.next_index = 0;
+                    if buf.is_empty() {
+                        return Ok(None);
+                    }
+                }
+                (false, Some(offset)) => {
+                    let newline_index = offset + self.next_index;
+                    self.next_index = 0;
+                    let line = buf.split_to(newline_index + 1);
+                    let line = &line[..line.len() - 1];
+                    let line = without_carriage_return(line);
+                    let line = try!(utf8(line));
+                    return Ok(Some(line.to_string()));
+                }
+                (false, None) if buf.len() > self.max_length => {
+                    // The line is over-long, report it once and discard it.
+                    self.is_discarding = true;
+                    return Err(LinesCodecError::MaxLineLengthExceeded);
+                }
+                (false, None) => {
+                    // We didn't find a line or reach the length limit, so the next
+                    // call will resume searching at the current offset.
+                    self.next_index = read_to;
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<String>, LinesCodecError> {
+        Ok(match try!(self.decode(buf)) {
+            Some(frame) => Some(frame),
+            None => {
+                // No terminating newline - return remaining data, if any
+                if buf.is_empty() || buf == &b"\r"[..] {
+                    None
+                } else {
+                    let line = buf.split_to(buf.len());
+                    let line = without_carriage_return(&line);
+                    let line = try!(utf8(line));
+                    self.next_index = 0;
+                    Some(line.to_string())
+                }
+            }
+        })
+    }
+}
+
+impl Encoder for LinesCodec {
+    type Item = String;
+    type Error = LinesCodecError;
+
+    fn encode(&mut self, line: String, buf: &mut BytesMut) -> Result<(), LinesCodecError> {
+        buf.reserve(line.len() + 1);
+        buf.put_slice(line.as_bytes());
+        buf.put_u8(b'\n');
+        Ok(())
+    }
+}
+
+/// An error occurred while encoding or decoding a line.
+#[derive(Debug)]
+pub enum LinesCodecError {
+    /// The maximum line length was exceeded.
+    MaxLineLengthExceeded,
+    /// An IO error occurred.
+    Io(io::Error),
+}
+
+impl fmt::Display for LinesCodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinesCodecError::MaxLineLengthExceeded => write!(f, "max line length exceeded"),
+            LinesCodecError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for LinesCodecError {
+    fn from(e: io::Error) -> LinesCodecError {
+        LinesCodecError::Io(e)
+    }
+}
+
+impl error::Error for LinesCodecError {
+    fn description(&self) -> &str {
+        match self {
+            LinesCodecError::MaxLineLengthExceeded => "max line length exceeded",
+            LinesCodecError::Io(e) => e.description(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_lines() {
+        let mut codec = LinesCodec::new();
+        let mut buf = BytesMut::from(&b"line 1\nline 2\r\nline"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), "line 1");
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), "line 2");
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(b" 3\n");
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), "line 3");
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn decode_eof_partial_line() {
+        let mut codec = LinesCodec::new();
+        let mut buf = BytesMut::from(&b"line 1\nline 2\r"[..]);
+        assert_eq!(codec.decode_eof(&mut buf).unwrap().unwrap(), "line 1");
+        assert_eq!(codec.decode_eof(&mut buf).unwrap().unwrap(), "line 2");
+        assert_eq!(codec.decode_eof(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn discard_long_line() {
+        let mut codec = LinesCodec::new_with_max_length(4);
+        let mut buf = BytesMut::from(&b"toolong"[..]);
+        match codec.decode(&mut buf) {
+            Err(LinesCodecError::MaxLineLengthExceeded) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        buf.extend_from_slice(b"line\nok\n");
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), "ok");
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn encode_line() {
+        let mut codec = LinesCodec::new();
+        let mut buf = BytesMut::new();
+        codec.encode("line".to_string(), &mut buf).unwrap();
+        assert_eq!(&buf[..], b"line\n");
+    }
+}
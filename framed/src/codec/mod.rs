@@ -10,8 +10,18 @@
 //! [`Stream`]: #
 //! [transports]: #
 
+mod any_delimiter;
+mod cobs;
+mod combinator;
 mod decoder;
 mod encoder;
+pub mod length_delimited;
+mod lines;
 
+pub use self::any_delimiter::{AnyDelimiterCodec, AnyDelimiterCodecError};
+pub use self::cobs::CobsCodec;
+pub use self::combinator::{CodecExt, Layered, MapDecode, MapEncode, MapErr};
 pub use self::decoder::Decoder;
 pub use self::encoder::Encoder;
+pub use self::length_delimited::LengthDelimitedCodec;
+pub use self::lines::{LinesCodec, LinesCodecError};
@@ -0,0 +1,386 @@
+//! Framing over datagram transports.
+//!
+//! Unlike `Framed`, which decodes frames from a byte stream, `FramedDatagram`
+//! decodes exactly one frame from every datagram and encodes every frame as
+//! one datagram.
+//!
+//! `UnixDatagramSocket` implements `AsyncDatagram` for Unix datagram sockets.
+
+#[cfg(unix)]
+use std::cmp;
+use std::fmt;
+use std::io;
+#[cfg(unix)]
+use std::net::Shutdown;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use std::thread;
+
+use codec::{Decoder, Encoder};
+
+use bytes::BytesMut;
+#[cfg(unix)]
+use bytes::Bytes;
+#[cfg(unix)]
+use futures::channel::mpsc;
+use futures::prelude::*;
+
+/// Size of the buffer datagrams are received into.
+const MAX_DATAGRAM_SIZE: usize = 64 * 1024;
+
+/// A datagram socket which can be polled for sending and receiving.
+pub trait AsyncDatagram {
+    /// The address of a peer.
+    type Addr;
+
+    /// Attempts to receive a datagram into `buf`, returning its length and the
+    /// address it was received from.
+    ///
+    /// The remainder of a datagram longer than `buf` may be discarded.
+    fn poll_recv_from(
+        &mut self,
+        cx: &mut task::Context,
+        buf: &mut [u8],
+    ) -> Poll<(usize, Self::Addr), io::Error>;
+
+    /// Attempts to send `buf` as a single datagram to `addr`, or to the
+    /// connected peer if `addr` is `None`.
+    fn poll_send_to(
+        &mut self,
+        cx: &mut task::Context,
+        buf: &[u8],
+        addr: Option<&Self::Addr>,
+    ) -> Poll<usize, io::Error>;
+}
+
+/// A Unix datagram socket which can be polled, addressed by path.
+///
+/// There is no reactor, so datagrams are received by a background thread,
+/// which exits once the socket is dropped. Sending blocks until the datagram
+/// is queued by the kernel. Datagrams from unnamed sockets are dropped, as
+/// there is no address to answer them at.
+#[cfg(unix)]
+pub struct UnixDatagramSocket {
+    socket: UnixDatagram,
+    incoming: mpsc::UnboundedReceiver<io::Result<(Bytes, PathBuf)>>,
+}
+
+#[cfg(unix)]
+impl UnixDatagramSocket {
+    /// Creates a socket bound to `path`.
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<UnixDatagramSocket> {
+        UnixDatagramSocket::new(UnixDatagram::bind(path)?)
+    }
+
+    /// Wraps `socket`, which must be in blocking mode.
+    pub fn new(socket: UnixDatagram) -> io::Result<UnixDatagramSocket> {
+        let receiver = socket.try_clone()?;
+        let (tx, incoming) = mpsc::unbounded();
+        thread::spawn(move || {
+            let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+            loop {
+                let received = receiver.recv_from(&mut buf).map(|(n, addr)| {
+                    (Bytes::from(&buf[..n]), addr.as_pathname().map(Path::to_path_buf))
+                });
+                let sent = match received {
+                    Ok((datagram, Some(addr))) => tx.unbounded_send(Ok((datagram, addr))),
+                    Ok((_, None)) => continue,
+                    Err(e) => {
+                        let _ = tx.unbounded_send(Err(e));
+                        return;
+                    }
+                };
+                if sent.is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(UnixDatagramSocket { socket, incoming })
+    }
+
+    /// Connects the socket to `path`, the peer datagrams without an address are sent to.
+    pub fn connect<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.socket.connect(path)
+    }
+
+    /// Returns a reference to the underlying socket.
+    pub fn get_ref(&self) -> &UnixDatagram {
+        &self.socket
+    }
+}
+
+#[cfg(unix)]
+impl AsyncDatagram for UnixDatagramSocket {
+    type Addr = PathBuf;
+
+    fn poll_recv_from(
+        &mut self,
+        cx: &mut task::Context,
+        buf: &mut [u8],
+    ) -> Poll<(usize, PathBuf), io::Error> {
+        match self.incoming.poll_next(cx) {
+            Ok(Async::Ready(Some(Ok((datagram, addr))))) => {
+                let len = cmp::min(datagram.len(), buf.len());
+                buf[..len].copy_from_slice(&datagram[..len]);
+                Ok(Async::Ready((len, addr)))
+            }
+            Ok(Async::Ready(Some(Err(e)))) => Err(e),
+            Ok(Async::Pending) => Ok(Async::Pending),
+            Ok(Async::Ready(None)) | Err(_) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "receiving thread stopped",
+            )),
+        }
+    }
+
+    fn poll_send_to(
+        &mut self,
+        _: &mut task::Context,
+        buf: &[u8],
+        addr: Option<&PathBuf>,
+    ) -> Poll<usize, io::Error> {
+        let n = match addr {
+            Some(addr) => self.socket.send_to(buf, addr)?,
+            None => self.socket.send(buf)?,
+        };
+        Ok(Async::Ready(n))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixDatagramSocket {
+    fn drop(&mut self) {
+        // Wakes the receiving thread, which shares the socket.
+        let _ = self.socket.shutdown(Shutdown::Both);
+    }
+}
+
+#[cfg(unix)]
+impl fmt::Debug for UnixDatagramSocket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UnixDatagramSocket")
+            .field("socket", &self.socket)
+            .finish()
+    }
+}
+
+/// A unified `Stream` and `Sink` interface to a datagram socket, using the
+/// `Encoder` and `Decoder` traits to encode and decode one frame per datagram.
+///
+/// The stream yields decoded frames together with the address of their
+/// sender. Frames are sent with an optional address, `None` sending them to
+/// the connected peer. Datagrams from which `decode_eof` returns no frame are
+/// skipped.
+pub struct FramedDatagram<T: AsyncDatagram, C> {
+    inner: T,
+    codec: C,
+    rd: Vec<u8>,
+    wr: BytesMut,
+    out_addr: Option<T::Addr>,
+}
+
+impl<T: AsyncDatagram, C> FramedDatagram<T, C> {
+    /// Creates a new `FramedDatagram` over `inner` using `codec`.
+    pub fn new(inner: T, codec: C) -> FramedDatagram<T, C> {
+        FramedDatagram {
+            inner,
+            codec,
+            rd: vec![0; MAX_DATAGRAM_SIZE],
+            wr: BytesMut::with_capacity(MAX_DATAGRAM_SIZE),
+            out_addr: None,
+        }
+    }
+
+    /// Returns a reference to the underlying socket.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the underlying socket.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Consumes the `FramedDatagram`, returning its underlying socket.
+    ///
+    /// A frame which has not been flushed yet is lost.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Returns a reference to the codec.
+    pub fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    /// Returns a mutable reference to the codec.
+    pub fn codec_mut(&mut self) -> &mut C {
+        &mut self.codec
+    }
+}
+
+impl<T, C> Stream for FramedDatagram<T, C>
+where
+    T: AsyncDatagram,
+    C: Decoder,
+{
+    type Item = (C::Item, T::Addr);
+    type Error = C::Error;
+
+    fn poll_next(&mut self, cx: &mut task::Context) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            let (n, addr) = try_ready!(self.inner.poll_recv_from(cx, &mut self.rd));
+            trace!("received datagram of {} bytes", n);
+
+            let mut datagram = BytesMut::from(&self.rd[..n]);
+            if let Some(frame) = try!(self.codec.decode_eof(&mut datagram)) {
+                return Ok(Async::Ready(Some((frame, addr))));
+            }
+        }
+    }
+}
+
+impl<T, C> Sink for FramedDatagram<T, C>
+where
+    T: AsyncDatagram,
+    C: Encoder,
+{
+    type SinkItem = (C::Item, Option<T::Addr>);
+    type SinkError = C::Error;
+
+    fn poll_ready(&mut self, cx: &mut task::Context) -> Result<Async<()>, Self::SinkError> {
+        // Only one datagram is buffered at a time.
+        self.poll_flush(cx)
+    }
+
+    fn start_send(&mut self, item: Self::SinkItem) -> Result<(), Self::SinkError> {
+        let (frame, addr) = item;
+        try!(self.codec.encode(frame, &mut self.wr));
+        self.out_addr = addr;
+        Ok(())
+    }
+
+    fn poll_flush(&mut self, cx: &mut task::Context) -> Poll<(), Self::SinkError> {
+        if self.wr.is_empty() {
+            return Ok(Async::Ready(()));
+        }
+
+        trace!("sending datagram of {} bytes", self.wr.len());
+        let n = try_ready!(self.inner.poll_send_to(cx, &self.wr, self.out_addr.as_ref()));
+        let len = self.wr.len();
+        self.wr.clear();
+        self.out_addr = None;
+
+        if n != len {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "failed to write entire datagram to socket",
+            ).into());
+        }
+        Ok(Async::Ready(()))
+    }
+
+    fn poll_close(&mut self, cx: &mut task::Context) -> Poll<(), Self::SinkError> {
+        self.poll_flush(cx)
+    }
+}
+
+impl<T, C> fmt::Debug for FramedDatagram<T, C>
+where
+    T: AsyncDatagram + fmt::Debug,
+    C: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FramedDatagram")
+            .field("io", &self.inner)
+            .field("codec", &self.codec)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use codec::LinesCodec;
+    use futures::executor::block_on;
+    use std::collections::VecDeque;
+
+    struct MockSocket {
+        incoming: VecDeque<(&'static [u8], u32)>,
+        sent: Vec<(Vec<u8>, Option<u32>)>,
+    }
+
+    impl AsyncDatagram for MockSocket {
+        type Addr = u32;
+
+        fn poll_recv_from(
+            &mut self,
+            _: &mut task::Context,
+            buf: &mut [u8],
+        ) -> Poll<(usize, u32), io::Error> {
+            match self.incoming.pop_front() {
+                Some((datagram, addr)) => {
+                    buf[..datagram.len()].copy_from_slice(datagram);
+                    Ok(Async::Ready((datagram.len(), addr)))
+                }
+                None => Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed")),
+            }
+        }
+
+        fn poll_send_to(
+            &mut self,
+            _: &mut task::Context,
+            buf: &[u8],
+            addr: Option<&u32>,
+        ) -> Poll<usize, io::Error> {
+            self.sent.push((buf.to_vec(), addr.cloned()));
+            Ok(Async::Ready(buf.len()))
+        }
+    }
+
+    #[test]
+    fn frame_per_datagram() {
+        let socket = MockSocket {
+            incoming: vec![(&b"one"[..], 1), (&b""[..], 2), (&b"two\n"[..], 3)]
+                .into_iter()
+                .collect(),
+            sent: Vec::new(),
+        };
+        let framed = FramedDatagram::new(socket, LinesCodec::new());
+
+        let framed = block_on(framed.send(("a".to_string(), Some(7)))).unwrap();
+        let framed = block_on(framed.send(("b".to_string(), None))).unwrap();
+        assert_eq!(
+            framed.get_ref().sent,
+            vec![(b"a\n".to_vec(), Some(7)), (b"b\n".to_vec(), None)]
+        );
+
+        let (first, framed) = block_on(framed.next()).map_err(|(e, _)| e).unwrap();
+        assert_eq!(first, Some(("one".to_string(), 1)));
+        let (second, _) = block_on(framed.next()).map_err(|(e, _)| e).unwrap();
+        assert_eq!(second, Some(("two".to_string(), 3)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_datagram_socket() {
+        use temp_path::TempPath;
+
+        let server_path = TempPath::new("server.sock");
+        let client_path = TempPath::new("client.sock");
+        let server = UnixDatagramSocket::bind(&server_path).unwrap();
+        let client = UnixDatagramSocket::bind(&client_path).unwrap();
+        client.connect(&server_path).unwrap();
+        let server = FramedDatagram::new(server, LinesCodec::new());
+        let client = FramedDatagram::new(client, LinesCodec::new());
+
+        block_on(client.send(("ping".to_string(), None))).unwrap();
+        let (received, _) = block_on(server.next()).map_err(|(e, _)| e).unwrap();
+        let (line, addr) = received.unwrap();
+        assert_eq!(line, "ping");
+        assert_eq!(addr, *client_path);
+    }
+}
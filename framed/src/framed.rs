@@ -1,9 +1,14 @@
 use std::fmt;
 use std::io;
+use std::time::Duration;
 
 use codec::{Decoder, Encoder};
 use framed_read::{framed_read2, framed_read2_with_buffer, FramedRead2};
 use framed_write::{framed_write2, framed_write2_with_buffer, FramedWrite2};
+use pool::BufferPool;
+
+pub use framed_read::FramedRead;
+pub use framed_write::{FlushPolicy, FramedWrite};
 
 use bytes::BytesMut;
 use futures::io::Initializer;
@@ -79,6 +84,77 @@ impl<T, U> Framed<T, U> {
         &mut self.inner.get_mut().get_mut().0
     }
 
+    /// Sets the capacity above which idle read and write buffers are shrunk.
+    ///
+    /// The read buffer is idle when it has been drained and no data has
+    /// arrived for a while, the write buffer once it has been flushed. `None`
+    /// keeps buffers as they grew, which is the default.
+    pub fn set_max_idle_capacity(&mut self, capacity: Option<usize>) {
+        self.inner.set_max_idle_capacity(capacity);
+        self.inner.get_mut().set_max_idle_capacity(capacity);
+    }
+
+    /// Sets a pool idle read and write buffers are returned to, and taken
+    /// from again when there is data to read or frames to write.
+    ///
+    /// Sharing one pool between many mostly idle connections keeps memory
+    /// only with those which have data in flight.
+    pub fn set_buffer_pool(&mut self, pool: Option<BufferPool>) {
+        self.inner.set_buffer_pool(pool.clone());
+        self.inner.get_mut().set_buffer_pool(pool);
+    }
+
+    /// Sets how long the read buffer stays drained before it is idle, 100
+    /// milliseconds by default.
+    pub fn set_idle_delay(&mut self, delay: Duration) {
+        self.inner.set_idle_delay(delay);
+    }
+
+    /// Returns the current capacity of the read buffer.
+    pub fn read_capacity(&self) -> usize {
+        self.inner.buffer().capacity()
+    }
+
+    /// Returns the current capacity of the write buffer.
+    pub fn write_capacity(&self) -> usize {
+        self.inner.get_ref().buffer().capacity()
+    }
+
+    /// Returns a reference to the buffer of read but not yet decoded data.
+    pub fn read_buffer(&self) -> &BytesMut {
+        self.inner.buffer()
+    }
+
+    /// Returns a mutable reference to the buffer of read but not yet decoded
+    /// data.
+    pub fn read_buffer_mut(&mut self) -> &mut BytesMut {
+        self.inner.buffer_mut()
+    }
+
+    /// Returns the policy deciding when flushes write buffered frames.
+    pub fn flush_policy(&self) -> FlushPolicy {
+        self.inner.get_ref().flush_policy()
+    }
+
+    /// Sets the policy deciding when flushes write buffered frames.
+    ///
+    /// Frames are written on every flush by default. Deferring writes lets
+    /// several small frames share one write to the I/O object.
+    pub fn set_flush_policy(&mut self, policy: FlushPolicy) {
+        self.inner.get_mut().set_flush_policy(policy)
+    }
+
+    /// Returns a reference to the buffer of encoded but not yet written data.
+    pub fn write_buffer(&self) -> &BytesMut {
+        self.inner.get_ref().buffer()
+    }
+
+    /// Returns a mutable reference to the buffer of encoded but not yet
+    /// written data.
+    pub fn write_buffer_mut(&mut self) -> &mut BytesMut {
+        self.inner.get_mut().buffer_mut()
+    }
+
     /// Consumes the `Frame`, returning its underlying I/O stream.
     ///
     /// Note that care should be taken to not tamper with the underlying stream
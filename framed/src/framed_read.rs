@@ -1,17 +1,143 @@
+use std::fmt;
+
 use codec::Decoder;
+use framed::Fuse;
+use pool::{BufferPool, Reclaim};
 
 use bytes::{BufMut, BytesMut};
 use futures::prelude::*;
+use std::time::Duration;
+
+/// A `Stream` of frames decoded from an `AsyncRead`.
+///
+/// This is the read half of `Framed`, for I/O objects which are only
+/// readable such as pipes, files or the output of a child process.
+pub struct FramedRead<T, D> {
+    inner: FramedRead2<Fuse<T, D>>,
+}
 
 pub struct FramedRead2<T> {
     inner: T,
     eof: bool,
     is_readable: bool,
     buffer: BytesMut,
+    reclaim: Reclaim,
 }
 
 const INITIAL_CAPACITY: usize = 8 * 1024;
 
+// ===== impl FramedRead =====
+
+impl<T, D> FramedRead<T, D>
+where
+    T: AsyncRead,
+    D: Decoder,
+{
+    /// Creates a new `FramedRead` decoding frames from `inner` with `decoder`.
+    pub fn new(inner: T, decoder: D) -> FramedRead<T, D> {
+        FramedRead {
+            inner: framed_read2(Fuse(inner, decoder)),
+        }
+    }
+}
+
+impl<T, D> FramedRead<T, D> {
+    /// Returns a reference to the underlying I/O stream wrapped by
+    /// `FramedRead`.
+    ///
+    /// Note that care should be taken to not tamper with the underlying stream
+    /// of data coming in as it may corrupt the stream of frames otherwise
+    /// being worked with.
+    pub fn get_ref(&self) -> &T {
+        &self.inner.get_ref().0
+    }
+
+    /// Returns a mutable reference to the underlying I/O stream wrapped by
+    /// `FramedRead`.
+    ///
+    /// Note that care should be taken to not tamper with the underlying stream
+    /// of data coming in as it may corrupt the stream of frames otherwise
+    /// being worked with.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner.get_mut().0
+    }
+
+    /// Consumes the `FramedRead`, returning its underlying I/O stream.
+    ///
+    /// Any data left in the read buffer is lost.
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner().0
+    }
+
+    /// Returns a reference to the decoder.
+    pub fn decoder(&self) -> &D {
+        &self.inner.get_ref().1
+    }
+
+    /// Returns a mutable reference to the decoder.
+    pub fn decoder_mut(&mut self) -> &mut D {
+        &mut self.inner.get_mut().1
+    }
+
+    /// Sets the capacity above which the read buffer is shrunk once no data
+    /// has arrived for a while. `None` keeps the buffer as it grew.
+    pub fn set_max_idle_capacity(&mut self, capacity: Option<usize>) {
+        self.inner.set_max_idle_capacity(capacity)
+    }
+
+    /// Sets a pool the read buffer is returned to once no data has arrived
+    /// for a while, and taken from when data arrives.
+    pub fn set_buffer_pool(&mut self, pool: Option<BufferPool>) {
+        self.inner.set_buffer_pool(pool)
+    }
+
+    /// Sets how long the read buffer stays drained before its memory is
+    /// given back, 100 milliseconds by default.
+    pub fn set_idle_delay(&mut self, delay: Duration) {
+        self.inner.set_idle_delay(delay)
+    }
+
+    /// Returns a reference to the buffer of read but not yet decoded data.
+    pub fn read_buffer(&self) -> &BytesMut {
+        self.inner.buffer()
+    }
+
+    /// Returns a mutable reference to the buffer of read but not yet decoded
+    /// data.
+    pub fn read_buffer_mut(&mut self) -> &mut BytesMut {
+        self.inner.buffer_mut()
+    }
+}
+
+impl<T, D> Stream for FramedRead<T, D>
+where
+    T: AsyncRead,
+    D: Decoder,
+{
+    type Item = D::Item;
+    type Error = D::Error;
+
+    fn poll_next(&mut self, cx: &mut task::Context) -> Poll<Option<Self::Item>, Self::Error> {
+        self.inner.poll_next(cx)
+    }
+}
+
+impl<T, D> fmt::Debug for FramedRead<T, D>
+where
+    T: fmt::Debug,
+    D: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FramedRead")
+            .field("inner", &self.inner.get_ref().0)
+            .field("decoder", &self.inner.get_ref().1)
+            .field("eof", &self.inner.eof)
+            .field("is_readable", &self.inner.is_readable)
+            .field("buffer", &self.inner.buffer)
+            .finish()
+    }
+}
+
 // ===== impl FramedRead2 =====
 
 pub fn framed_read2<T>(inner: T) -> FramedRead2<T> {
@@ -20,6 +146,7 @@ pub fn framed_read2<T>(inner: T) -> FramedRead2<T> {
         eof: false,
         is_readable: false,
         buffer: BytesMut::with_capacity(INITIAL_CAPACITY),
+        reclaim: Reclaim::default(),
     }
 }
 
@@ -33,6 +160,7 @@ pub fn framed_read2_with_buffer<T>(inner: T, mut buf: BytesMut) -> FramedRead2<T
         eof: false,
         is_readable: buf.len() > 0,
         buffer: buf,
+        reclaim: Reclaim::default(),
     }
 }
 
@@ -52,6 +180,26 @@ impl<T> FramedRead2<T> {
     pub fn get_mut(&mut self) -> &mut T {
         &mut self.inner
     }
+
+    pub fn buffer(&self) -> &BytesMut {
+        &self.buffer
+    }
+
+    pub fn buffer_mut(&mut self) -> &mut BytesMut {
+        &mut self.buffer
+    }
+
+    pub fn set_max_idle_capacity(&mut self, capacity: Option<usize>) {
+        self.reclaim.set_max_idle_capacity(capacity)
+    }
+
+    pub fn set_buffer_pool(&mut self, pool: Option<BufferPool>) {
+        self.reclaim.set_pool(pool)
+    }
+
+    pub fn set_idle_delay(&mut self, delay: Duration) {
+        self.reclaim.set_idle_delay(delay)
+    }
 }
 
 impl<T> Stream for FramedRead2<T>
@@ -89,19 +237,84 @@ where
             // Otherwise, try to read more data and try again. Make sure we've
             // got room for at least one byte to read to ensure that we don't
             // get a spurious 0 that looks like EOF
+            self.reclaim.acquire(&mut self.buffer);
             self.buffer.reserve(1);
-            unsafe {
-                let n = {
-                    let b = self.buffer.bytes_mut();
-                    try_ready!(self.inner.poll_read(cx, b))
-                };
-                if n != 0 {
-                    self.buffer.advance_mut(n);
-                } else {
-                    self.eof = true;
+            let read = unsafe {
+                let b = self.buffer.bytes_mut();
+                try!(self.inner.poll_read(cx, b))
+            };
+            match read {
+                Async::Ready(0) => self.eof = true,
+                Async::Ready(n) => {
+                    self.reclaim.active();
+                    unsafe { self.buffer.advance_mut(n) }
+                }
+                Async::Pending => {
+                    // Nothing has been read for a while, give back the memory
+                    // of a drained buffer until more data arrives.
+                    if self.buffer.is_empty() {
+                        self.reclaim.idle_for(&mut self.buffer, cx.waker());
+                    }
+                    return Ok(Async::Pending);
                 }
             }
             self.is_readable = true;
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use codec::LinesCodec;
+    use futures::executor::block_on;
+    use futures::future;
+    use std::io::{self, Cursor};
+    use std::time::Instant;
+
+    #[test]
+    fn read_frames() {
+        let io = Cursor::new(b"one\ntwo\nthree".to_vec());
+        let framed = FramedRead::new(io, LinesCodec::new());
+        let lines = block_on(framed.collect()).unwrap();
+        assert_eq!(lines, vec!["one", "two", "three"]);
+    }
+
+    #[test]
+    fn release_idle_buffer() {
+        let pool = BufferPool::new(INITIAL_CAPACITY, 4);
+        let mut framed = FramedRead::new(ReadOnce(Some(b"one\n".to_vec())), LinesCodec::new());
+        framed.set_buffer_pool(Some(pool.clone()));
+        framed.set_idle_delay(Duration::from_millis(20));
+        let start = Instant::now();
+        block_on(future::poll_fn(|cx| {
+            while framed.poll_next(cx).unwrap().is_ready() {}
+            // Woken by the timer once the buffer has been idle long enough.
+            if pool.is_empty() {
+                return Ok(Async::Pending);
+            }
+            Ok::<_, ()>(Async::Ready(()))
+        })).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(pool.len(), 1);
+    }
+
+    /// Yields its data once, then never becomes readable again.
+    struct ReadOnce(Option<Vec<u8>>);
+
+    impl AsyncRead for ReadOnce {
+        fn poll_read(
+            &mut self,
+            _: &mut task::Context,
+            buf: &mut [u8],
+        ) -> Result<Async<usize>, io::Error> {
+            match self.0.take() {
+                Some(data) => {
+                    buf[..data.len()].copy_from_slice(&data);
+                    Ok(Async::Ready(data.len()))
+                }
+                None => Ok(Async::Pending),
+            }
+        }
+    }
+}
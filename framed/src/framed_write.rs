@@ -1,25 +1,205 @@
-use std::io;
+use std::time::{Duration, Instant};
+use std::{fmt, io};
 
 use codec::{Decoder, Encoder};
+use framed::Fuse;
+use pool::{BufferPool, Reclaim};
+use timer::Wakeup;
 
 use bytes::BytesMut;
 use futures::prelude::*;
 
+/// A `Sink` of frames encoded to an `AsyncWrite`.
+///
+/// This is the write half of `Framed`, for I/O objects which are only
+/// writable such as pipes, files or the input of a child process.
+pub struct FramedWrite<T, E> {
+    inner: FramedWrite2<Fuse<T, E>>,
+}
+
 pub struct FramedWrite2<T> {
     inner: T,
     buffer: BytesMut,
+    policy: FlushPolicy,
+    frames: usize,
+    deadline: Option<Instant>,
+    /// Wakes the task of a deferred flush at `deadline`.
+    wakeup: Wakeup,
+    flushing: bool,
+    reclaim: Reclaim,
+}
+
+/// When a flush writes buffered frames out to the underlying I/O object.
+///
+/// A deferred flush stays pending, so a task driving the sink is woken and
+/// flushes again once the policy allows it. Closing the sink, and applying
+/// backpressure, always writes the whole buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Writes buffered frames on every flush.
+    Immediate,
+    /// Writes once `bytes` are buffered, or `max_delay` after the first frame
+    /// was buffered.
+    Bytes { bytes: usize, max_delay: Duration },
+    /// Writes once `frames` are buffered, or `max_delay` after the first
+    /// frame was buffered.
+    Frames { frames: usize, max_delay: Duration },
+    /// Writes the given duration after the first frame was buffered.
+    Delay(Duration),
+}
+
+impl FlushPolicy {
+    fn max_delay(&self) -> Option<Duration> {
+        match *self {
+            FlushPolicy::Immediate => None,
+            FlushPolicy::Bytes { max_delay, .. } | FlushPolicy::Frames { max_delay, .. } => {
+                Some(max_delay)
+            }
+            FlushPolicy::Delay(delay) => Some(delay),
+        }
+    }
+}
+
+impl Default for FlushPolicy {
+    fn default() -> FlushPolicy {
+        FlushPolicy::Immediate
+    }
 }
 
 const INITIAL_CAPACITY: usize = 8 * 1024;
 const BACKPRESSURE_BOUNDARY: usize = INITIAL_CAPACITY;
 
+// ===== impl FramedWrite =====
+
+impl<T, E> FramedWrite<T, E>
+where
+    T: AsyncWrite,
+    E: Encoder,
+{
+    /// Creates a new `FramedWrite` encoding frames into `inner` with `encoder`.
+    pub fn new(inner: T, encoder: E) -> FramedWrite<T, E> {
+        FramedWrite {
+            inner: framed_write2(Fuse(inner, encoder)),
+        }
+    }
+}
+
+impl<T, E> FramedWrite<T, E> {
+    /// Returns a reference to the underlying I/O stream wrapped by
+    /// `FramedWrite`.
+    ///
+    /// Note that care should be taken to not tamper with the underlying stream
+    /// of data going out as it may corrupt the stream of frames otherwise
+    /// being worked with.
+    pub fn get_ref(&self) -> &T {
+        &self.inner.get_ref().0
+    }
+
+    /// Returns a mutable reference to the underlying I/O stream wrapped by
+    /// `FramedWrite`.
+    ///
+    /// Note that care should be taken to not tamper with the underlying stream
+    /// of data going out as it may corrupt the stream of frames otherwise
+    /// being worked with.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner.get_mut().0
+    }
+
+    /// Consumes the `FramedWrite`, returning its underlying I/O stream.
+    ///
+    /// Any data left in the write buffer is lost, flush the sink first to
+    /// keep it.
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner().0
+    }
+
+    /// Returns a reference to the encoder.
+    pub fn encoder(&self) -> &E {
+        &self.inner.get_ref().1
+    }
+
+    /// Returns a mutable reference to the encoder.
+    pub fn encoder_mut(&mut self) -> &mut E {
+        &mut self.inner.get_mut().1
+    }
+
+    /// Returns the policy deciding when flushes write buffered frames.
+    pub fn flush_policy(&self) -> FlushPolicy {
+        self.inner.flush_policy()
+    }
+
+    /// Sets the policy deciding when flushes write buffered frames.
+    pub fn set_flush_policy(&mut self, policy: FlushPolicy) {
+        self.inner.set_flush_policy(policy)
+    }
+
+    /// Sets the capacity above which the write buffer is shrunk once it has
+    /// been flushed. `None` keeps the buffer as it grew.
+    pub fn set_max_idle_capacity(&mut self, capacity: Option<usize>) {
+        self.inner.set_max_idle_capacity(capacity)
+    }
+
+    /// Sets a pool the write buffer is returned to once it has been flushed,
+    /// and taken from when the next frame is sent.
+    pub fn set_buffer_pool(&mut self, pool: Option<BufferPool>) {
+        self.inner.set_buffer_pool(pool)
+    }
+
+    /// Returns a reference to the buffer of encoded but not yet written data.
+    pub fn write_buffer(&self) -> &BytesMut {
+        self.inner.buffer()
+    }
+
+    /// Returns a mutable reference to the buffer of encoded but not yet
+    /// written data.
+    pub fn write_buffer_mut(&mut self) -> &mut BytesMut {
+        self.inner.buffer_mut()
+    }
+}
+
+impl<T, E> Sink for FramedWrite<T, E>
+where
+    T: AsyncWrite,
+    E: Encoder,
+{
+    type SinkItem = E::Item;
+    type SinkError = E::Error;
+
+    fn poll_ready(&mut self, cx: &mut task::Context) -> Result<Async<()>, Self::SinkError> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn start_send(&mut self, item: Self::SinkItem) -> Result<(), Self::SinkError> {
+        self.inner.start_send(item)
+    }
+
+    fn poll_flush(&mut self, cx: &mut task::Context) -> Poll<(), Self::SinkError> {
+        self.inner.poll_flush(cx)
+    }
+
+    fn poll_close(&mut self, cx: &mut task::Context) -> Poll<(), Self::SinkError> {
+        self.inner.poll_close(cx)
+    }
+}
+
+impl<T, E> fmt::Debug for FramedWrite<T, E>
+where
+    T: fmt::Debug,
+    E: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FramedWrite")
+            .field("inner", &self.inner.get_ref().0)
+            .field("encoder", &self.inner.get_ref().1)
+            .field("buffer", &self.inner.buffer)
+            .finish()
+    }
+}
+
 // ===== impl FramedWrite2 =====
 
 pub fn framed_write2<T>(inner: T) -> FramedWrite2<T> {
-    FramedWrite2 {
-        inner: inner,
-        buffer: BytesMut::with_capacity(INITIAL_CAPACITY),
-    }
+    framed_write2_with_buffer(inner, BytesMut::with_capacity(INITIAL_CAPACITY))
 }
 
 pub fn framed_write2_with_buffer<T>(inner: T, mut buf: BytesMut) -> FramedWrite2<T> {
@@ -30,6 +210,12 @@ pub fn framed_write2_with_buffer<T>(inner: T, mut buf: BytesMut) -> FramedWrite2
     FramedWrite2 {
         inner: inner,
         buffer: buf,
+        policy: FlushPolicy::Immediate,
+        frames: 0,
+        deadline: None,
+        wakeup: Wakeup::new(),
+        flushing: false,
+        reclaim: Reclaim::default(),
     }
 }
 
@@ -49,6 +235,49 @@ impl<T> FramedWrite2<T> {
     pub fn get_mut(&mut self) -> &mut T {
         &mut self.inner
     }
+
+    pub fn buffer(&self) -> &BytesMut {
+        &self.buffer
+    }
+
+    pub fn buffer_mut(&mut self) -> &mut BytesMut {
+        &mut self.buffer
+    }
+
+    pub fn flush_policy(&self) -> FlushPolicy {
+        self.policy
+    }
+
+    pub fn set_flush_policy(&mut self, policy: FlushPolicy) {
+        self.policy = policy;
+        if !self.buffer.is_empty() {
+            self.deadline = policy.max_delay().map(|delay| Instant::now() + delay);
+        }
+    }
+
+    pub fn set_max_idle_capacity(&mut self, capacity: Option<usize>) {
+        self.reclaim.set_max_idle_capacity(capacity)
+    }
+
+    pub fn set_buffer_pool(&mut self, pool: Option<BufferPool>) {
+        self.reclaim.set_pool(pool)
+    }
+
+    /// Returns whether a flush should write the buffer now.
+    fn should_flush(&self) -> bool {
+        if self.flushing || self.buffer.is_empty() {
+            return true;
+        }
+        if self.deadline.map_or(false, |deadline| deadline <= Instant::now()) {
+            return true;
+        }
+        match self.policy {
+            FlushPolicy::Immediate => true,
+            FlushPolicy::Bytes { bytes, .. } => self.buffer.len() >= bytes,
+            FlushPolicy::Frames { frames, .. } => self.frames >= frames,
+            FlushPolicy::Delay(_) => false,
+        }
+    }
 }
 
 impl<T> Sink for FramedWrite2<T>
@@ -62,7 +291,7 @@ where
         // If the buffer is already over 8KiB, then attempt to flush it. If after flushing it's
         // *still* over 8KiB, then apply backpressure (reject the send).
         if self.buffer.len() >= BACKPRESSURE_BOUNDARY {
-            try!(self.poll_flush(cx));
+            try!(self.poll_write_buffer(cx));
 
             if self.buffer.len() >= BACKPRESSURE_BOUNDARY {
                 Ok(Async::Pending)
@@ -75,13 +304,42 @@ where
     }
 
     fn start_send(&mut self, item: T::Item) -> Result<(), Self::SinkError> {
+        if self.buffer.is_empty() {
+            self.deadline = self.policy.max_delay().map(|delay| Instant::now() + delay);
+        }
+        self.reclaim.acquire(&mut self.buffer);
         try!(self.inner.encode(item, &mut self.buffer));
+        self.frames += 1;
 
         Ok(())
     }
 
     fn poll_flush(&mut self, cx: &mut task::Context) -> Poll<(), Self::SinkError> {
+        if !self.should_flush() {
+            trace!("deferring flush; buffered={}", self.buffer.len());
+            if let Some(deadline) = self.deadline {
+                self.wakeup.set(deadline, cx.waker());
+            }
+            return Ok(Async::Pending);
+        }
+        self.poll_write_buffer(cx)
+    }
+
+    fn poll_close(&mut self, cx: &mut task::Context) -> Poll<(), Self::SinkError> {
+        try_ready!(self.poll_write_buffer(cx));
+        Ok(try!(self.inner.poll_close(cx)))
+    }
+}
+
+impl<T> FramedWrite2<T>
+where
+    T: AsyncWrite + Encoder,
+{
+    /// Writes the whole buffer and flushes the underlying I/O object,
+    /// regardless of the flush policy.
+    fn poll_write_buffer(&mut self, cx: &mut task::Context) -> Poll<(), T::Error> {
         trace!("flushing framed transport");
+        self.flushing = true;
 
         while !self.buffer.is_empty() {
             trace!("writing; remaining={}", self.buffer.len());
@@ -99,18 +357,16 @@ where
             // data.
             let _ = self.buffer.split_to(n);
         }
+        self.frames = 0;
 
         // Try flushing the underlying IO
         try_ready!(self.inner.poll_flush(cx));
+        self.flushing = false;
+        self.reclaim.idle(&mut self.buffer);
 
         trace!("framed transport flushed");
         return Ok(Async::Ready(()));
     }
-
-    fn poll_close(&mut self, cx: &mut task::Context) -> Poll<(), Self::SinkError> {
-        try_ready!(self.poll_flush(cx));
-        Ok(try!(self.inner.poll_close(cx)))
-    }
 }
 
 impl<T: Decoder> Decoder for FramedWrite2<T> {
@@ -131,3 +387,56 @@ impl<T: AsyncRead> AsyncRead for FramedWrite2<T> {
         self.inner.poll_read(cx, buf)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use codec::LinesCodec;
+    use futures::executor::block_on;
+    use futures::stream;
+    use std::io::Cursor;
+
+    #[test]
+    fn write_frames() {
+        let framed = FramedWrite::new(Cursor::new(Vec::new()), LinesCodec::new());
+        let framed = block_on(framed.send("one".to_string())).unwrap();
+        let framed = block_on(framed.send("two".to_string())).unwrap();
+        assert!(framed.write_buffer().is_empty());
+        assert_eq!(&framed.get_ref().get_ref()[..], b"one\ntwo\n");
+    }
+
+    #[test]
+    fn release_flushed_buffer() {
+        let pool = BufferPool::new(INITIAL_CAPACITY, 4);
+        let mut framed = FramedWrite::new(Cursor::new(Vec::new()), LinesCodec::new());
+        framed.set_buffer_pool(Some(pool.clone()));
+        let framed = block_on(framed.send("one".to_string())).unwrap();
+        assert_eq!(pool.len(), 1);
+        let framed = block_on(framed.send("two".to_string())).unwrap();
+        assert_eq!(pool.len(), 1);
+        assert_eq!(&framed.get_ref().get_ref()[..], b"one\ntwo\n");
+    }
+
+    #[test]
+    fn delay_flush() {
+        let mut framed = FramedWrite::new(Cursor::new(Vec::new()), LinesCodec::new());
+        framed.set_flush_policy(FlushPolicy::Delay(Duration::from_millis(10)));
+        let start = Instant::now();
+        let framed = block_on(framed.send("one".to_string())).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(10));
+        assert_eq!(&framed.get_ref().get_ref()[..], b"one\n");
+    }
+
+    #[test]
+    fn frames_flush() {
+        let mut framed = FramedWrite::new(Cursor::new(Vec::new()), LinesCodec::new());
+        framed.set_flush_policy(FlushPolicy::Frames {
+            frames: 2,
+            max_delay: Duration::from_secs(3600),
+        });
+        let frames = stream::iter_ok::<_, io::Error>(vec!["one".to_string(), "two".to_string()]);
+        let (framed, _) = block_on(framed.send_all(frames)).unwrap();
+        assert!(framed.write_buffer().is_empty());
+        assert_eq!(&framed.get_ref().get_ref()[..], b"one\ntwo\n");
+    }
+}
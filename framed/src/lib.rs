@@ -2,10 +2,20 @@ extern crate bytes;
 #[macro_use]
 extern crate futures;
 #[macro_use]
+extern crate lazy_static;
+#[macro_use]
 extern crate log;
 
+pub mod blocking;
 pub mod codec;
+pub mod datagram;
 
 pub mod framed;
 mod framed_read;
 mod framed_write;
+pub mod pool;
+#[cfg(test)]
+mod temp_path;
+mod timer;
+
+pub use timer::Delay;
@@ -0,0 +1,211 @@
+//! Reuse of read and write buffers between idle connections.
+//!
+//! A framed transport grows its buffers to fit the largest frame it has seen.
+//! A `BufferPool` shared between many transports lets idle ones hand their
+//! buffers back, so memory is only held by connections with data in flight.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use futures::task::Waker;
+use timer;
+
+/// A pool of buffers shared between framed transports.
+///
+/// Cloning a `BufferPool` gives another handle to the same pool.
+#[derive(Clone)]
+pub struct BufferPool {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    buffers: Vec<BytesMut>,
+    capacity: usize,
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    /// Creates a pool handing out buffers of `capacity` bytes and keeping at
+    /// most `max_buffers` idle buffers.
+    pub fn new(capacity: usize, max_buffers: usize) -> BufferPool {
+        BufferPool {
+            inner: Arc::new(Mutex::new(Inner {
+                buffers: Vec::new(),
+                capacity,
+                max_buffers,
+            })),
+        }
+    }
+
+    /// Takes an empty buffer from the pool, allocating one if it is empty.
+    pub fn get(&self) -> BytesMut {
+        let mut inner = self.inner.lock().unwrap();
+        let capacity = inner.capacity;
+        inner
+            .buffers
+            .pop()
+            .unwrap_or_else(|| BytesMut::with_capacity(capacity))
+    }
+
+    /// Returns `buf` to the pool.
+    ///
+    /// The buffer is dropped instead if it has grown past the capacity of the
+    /// pool, or if the pool is full.
+    pub fn put(&self, mut buf: BytesMut) {
+        let mut inner = self.inner.lock().unwrap();
+        if buf.capacity() > inner.capacity || inner.buffers.len() >= inner.max_buffers {
+            return;
+        }
+        buf.clear();
+        buf.reserve(inner.capacity);
+        inner.buffers.push(buf);
+    }
+
+    /// Returns the number of idle buffers in the pool.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().buffers.len()
+    }
+
+    /// Returns whether the pool holds no idle buffers.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.lock().unwrap();
+        f.debug_struct("BufferPool")
+            .field("buffers", &inner.buffers.len())
+            .field("capacity", &inner.capacity)
+            .field("max_buffers", &inner.max_buffers)
+            .finish()
+    }
+}
+
+/// How long a drained buffer waits in `Reclaim::idle_for` by default.
+const DEFAULT_IDLE_DELAY_MS: u64 = 100;
+
+/// What a framed transport does with a buffer when it becomes idle.
+#[derive(Debug)]
+pub(crate) struct Reclaim {
+    pool: Option<BufferPool>,
+    max_idle_capacity: Option<usize>,
+    idle_delay: Duration,
+    released: bool,
+    idle_since: Option<Instant>,
+}
+
+impl Default for Reclaim {
+    fn default() -> Reclaim {
+        Reclaim {
+            pool: None,
+            max_idle_capacity: None,
+            idle_delay: Duration::from_millis(DEFAULT_IDLE_DELAY_MS),
+            released: false,
+            idle_since: None,
+        }
+    }
+}
+
+impl Reclaim {
+    pub fn set_pool(&mut self, pool: Option<BufferPool>) {
+        self.pool = pool;
+    }
+
+    pub fn set_max_idle_capacity(&mut self, capacity: Option<usize>) {
+        self.max_idle_capacity = capacity;
+    }
+
+    /// Gives up the memory of the empty `buf`, either back to the pool or by
+    /// shrinking it to `max_idle_capacity`.
+    pub fn idle(&mut self, buf: &mut BytesMut) {
+        debug_assert!(buf.is_empty());
+        if let Some(ref pool) = self.pool {
+            if !self.released {
+                trace!("returning idle buffer to pool; capacity={}", buf.capacity());
+                pool.put(::std::mem::replace(buf, BytesMut::new()));
+                self.released = true;
+            }
+        } else if let Some(max) = self.max_idle_capacity {
+            if buf.capacity() > max {
+                trace!("shrinking idle buffer; capacity={}", buf.capacity());
+                *buf = BytesMut::with_capacity(max);
+            }
+        }
+    }
+
+    /// Sets how long `idle_for` waits before giving up a drained buffer.
+    pub fn set_idle_delay(&mut self, delay: Duration) {
+        self.idle_delay = delay;
+    }
+
+    /// Calls `idle` once `buf` has stayed empty for the idle delay.
+    ///
+    /// Until then `waker` is woken at the end of the delay, so a transport
+    /// which is not polled again still gives up its buffer.
+    pub fn idle_for(&mut self, buf: &mut BytesMut, waker: &Waker) {
+        let delay = self.idle_delay;
+        let now = Instant::now();
+        match self.idle_since {
+            Some(since) if now - since >= delay => self.idle(buf),
+            Some(_) => (),
+            None => {
+                self.idle_since = Some(now);
+                if self.pool.is_some() || self.max_idle_capacity.is_some() {
+                    timer::wake_at(now + delay, waker.clone());
+                }
+            }
+        }
+    }
+
+    /// Ends the idle period started by `idle_for`.
+    pub fn active(&mut self) {
+        self.idle_since = None;
+    }
+
+    /// Makes sure `buf` is backed by memory again after `idle`.
+    pub fn acquire(&mut self, buf: &mut BytesMut) {
+        if self.released {
+            self.released = false;
+            if let Some(ref pool) = self.pool {
+                let mut pooled = pool.get();
+                pooled.extend_from_slice(buf);
+                *buf = pooled;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reuse_buffers() {
+        let pool = BufferPool::new(64, 1);
+        let mut buf = pool.get();
+        buf.extend_from_slice(b"frame");
+        pool.put(buf);
+        assert_eq!(pool.len(), 1);
+
+        let buf = pool.get();
+        assert!(buf.is_empty());
+        assert!(buf.capacity() >= 64);
+        assert!(pool.is_empty());
+
+        pool.put(BytesMut::with_capacity(1024));
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn shrink_idle_buffer() {
+        let mut reclaim = Reclaim::default();
+        reclaim.set_max_idle_capacity(Some(64));
+        let mut buf = BytesMut::with_capacity(1024);
+        reclaim.idle(&mut buf);
+        assert!(buf.capacity() < 1024);
+    }
+}
@@ -0,0 +1,190 @@
+//! Wakes tasks at a deadline.
+//!
+//! There is no timer in the executor, so a single background thread, started
+//! on first use, is shared by every deadline of the process.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::prelude::*;
+use futures::task::Waker;
+
+enum Message {
+    /// Wakes the waker at the deadline, replacing the previous entry with the same id.
+    Set(usize, Instant, Waker),
+    Cancel(usize),
+}
+
+struct Timer {
+    tx: Mutex<mpsc::Sender<Message>>,
+}
+
+lazy_static! {
+    static ref TIMER: Timer = Timer::new();
+}
+
+/// Source of the ids of the timer entries.
+static NEXT_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+impl Timer {
+    fn new() -> Timer {
+        let (tx, rx) = mpsc::channel::<Message>();
+        thread::spawn(move || {
+            // Keyed by deadline and id, as deadlines may be equal.
+            let mut pending: BTreeMap<(Instant, usize), Waker> = BTreeMap::new();
+            let mut deadlines: HashMap<usize, Instant> = HashMap::new();
+            loop {
+                let received = match pending.keys().next().cloned() {
+                    Some(key) => {
+                        let now = Instant::now();
+                        if key.0 <= now {
+                            deadlines.remove(&key.1);
+                            if let Some(waker) = pending.remove(&key) {
+                                waker.wake();
+                            }
+                            continue;
+                        }
+                        rx.recv_timeout(key.0 - now)
+                    }
+                    None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match received {
+                    Ok(Message::Set(id, deadline, waker)) => {
+                        if let Some(previous) = deadlines.insert(id, deadline) {
+                            pending.remove(&(previous, id));
+                        }
+                        pending.insert((deadline, id), waker);
+                    }
+                    Ok(Message::Cancel(id)) => {
+                        if let Some(previous) = deadlines.remove(&id) {
+                            pending.remove(&(previous, id));
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => (),
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+        });
+        Timer { tx: Mutex::new(tx) }
+    }
+
+    fn send(&self, message: Message) {
+        if let Ok(tx) = self.tx.lock() {
+            let _ = tx.send(message);
+        }
+    }
+}
+
+fn next_id() -> usize {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Wakes `waker` once `deadline` is reached.
+///
+/// Every call registers a wake-up, a task polled again before `deadline`
+/// may be woken more than once. See `Wakeup` for a wake-up which is moved
+/// instead.
+pub fn wake_at(deadline: Instant, waker: Waker) {
+    TIMER.send(Message::Set(next_id(), deadline, waker));
+}
+
+/// A single wake-up, moved to the latest deadline it is set to.
+///
+/// It is canceled when dropped.
+pub struct Wakeup {
+    id: usize,
+    armed: Option<(Instant, Waker)>,
+}
+
+impl Wakeup {
+    pub fn new() -> Wakeup {
+        Wakeup {
+            id: next_id(),
+            armed: None,
+        }
+    }
+
+    /// Wakes `waker` once `deadline` is reached, instead of the deadline and
+    /// waker set before.
+    pub fn set(&mut self, deadline: Instant, waker: &Waker) {
+        if let Some((armed, ref armed_waker)) = self.armed {
+            if armed == deadline && armed_waker.will_wake(waker) {
+                return;
+            }
+        }
+        self.armed = Some((deadline, waker.clone()));
+        TIMER.send(Message::Set(self.id, deadline, waker.clone()));
+    }
+}
+
+impl Drop for Wakeup {
+    fn drop(&mut self) {
+        if self.armed.is_some() {
+            TIMER.send(Message::Cancel(self.id));
+        }
+    }
+}
+
+/// A future which completes after the given duration.
+pub struct Delay {
+    deadline: Instant,
+    wakeup: Wakeup,
+}
+
+impl Delay {
+    pub fn new(duration: Duration) -> Delay {
+        Delay {
+            deadline: Instant::now() + duration,
+            wakeup: Wakeup::new(),
+        }
+    }
+}
+
+impl Future for Delay {
+    type Item = ();
+    type Error = Never;
+
+    fn poll(&mut self, cx: &mut task::Context) -> Poll<Self::Item, Self::Error> {
+        if self.deadline <= Instant::now() {
+            return Ok(Async::Ready(()));
+        }
+        self.wakeup.set(self.deadline, cx.waker());
+        Ok(Async::Pending)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::future::poll_fn;
+
+    #[test]
+    fn wake_every_deadline() {
+        let start = Instant::now();
+        let wait = |delay: Duration| {
+            let deadline = start + delay;
+            poll_fn(move |cx| {
+                if deadline <= Instant::now() {
+                    return Ok::<_, ()>(Async::Ready(()));
+                }
+                wake_at(deadline, cx.waker().clone());
+                Ok(Async::Pending)
+            })
+        };
+        block_on(wait(Duration::from_millis(10)).join(wait(Duration::from_millis(20)))).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn delay() {
+        let start = Instant::now();
+        let first = Delay::new(Duration::from_millis(10));
+        block_on(first.join(Delay::new(Duration::from_millis(20)))).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,151 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+use super::Codec;
+use super::{Frame, TypeLabel};
+
+use bytes::Bytes;
+use framed::blocking::Framed;
+
+/// A synchronous dialog peer over blocking `std::io` objects.
+///
+/// Requests and pings from the other side are only answered while the client is blocked in
+/// `call` or `serve`. Frames on channels other than 0 are ignored.
+pub struct BlockingClient<R, W, F> {
+    reader: Framed<R, Codec>,
+    writer: Framed<W, Codec>,
+    next_id: u64,
+    f: F,
+}
+
+impl<F> BlockingClient<TcpStream, TcpStream, F>
+where
+    F: FnMut(Bytes) -> io::Result<Bytes>,
+{
+    /// Creates a client over both directions of a TCP stream.
+    pub fn from_tcp(stream: TcpStream, f: F) -> io::Result<Self> {
+        let reader = stream.try_clone()?;
+        Ok(BlockingClient::new(reader, stream, f))
+    }
+}
+
+impl<R, W, F> BlockingClient<R, W, F>
+where
+    R: Read,
+    W: Write,
+    F: FnMut(Bytes) -> io::Result<Bytes>,
+{
+    /// Creates a client reading frames from `reader` and writing frames to `writer`, such as
+    /// stdin and stdout. Incoming requests are answered with `f`.
+    pub fn new(reader: R, writer: W, f: F) -> Self {
+        BlockingClient {
+            reader: Framed::new(reader, Codec),
+            writer: Framed::new(writer, Codec),
+            next_id: 0,
+            f,
+        }
+    }
+
+    /// Sends a request and blocks until its response arrives.
+    pub fn call(&mut self, request: Bytes) -> io::Result<Bytes> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.writer.send(Frame::new(TypeLabel::Request, id, request))?;
+        loop {
+            match self.reader.read_frame()? {
+                Some(frame) => if let Some(response) = self.receive(frame, Some(id))? {
+                    return Ok(response);
+                },
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "connection closed before response",
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Answers requests and pings until the connection is closed.
+    pub fn serve(&mut self) -> io::Result<()> {
+        while let Some(frame) = self.reader.read_frame()? {
+            self.receive(frame, None)?;
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, message: Frame, waiting: Option<u64>) -> io::Result<Option<Bytes>> {
+        if message.channel() != 0 {
+            return Ok(None);
+        }
+        let (t, id, payload) = message.into();
+        match t {
+            TypeLabel::Request => {
+                let response = (self.f)(payload)?;
+                self.writer.send(Frame::new(TypeLabel::Response, id, response))?;
+                Ok(None)
+            }
+            // Responses to earlier calls which were abandoned are dropped.
+            TypeLabel::Response if waiting == Some(id) => Ok(Some(payload)),
+            TypeLabel::Ping => {
+                self.writer.send(Frame::new(TypeLabel::Pong, id, payload))?;
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns a reference to the underlying reader.
+    pub fn reader(&self) -> &R {
+        self.reader.get_ref()
+    }
+
+    /// Returns a reference to the underlying writer.
+    pub fn writer(&self) -> &W {
+        self.writer.get_ref()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::BytesMut;
+    use framed::codec::{Decoder, Encoder};
+    use std::io::Cursor;
+
+    #[test]
+    fn call_answers_peer() {
+        let mut incoming = BytesMut::new();
+        let frames = vec![
+            Frame::new(TypeLabel::Ping, 5, Bytes::from(&b"ping"[..])),
+            Frame::new(TypeLabel::Request, 0, Bytes::from(&b"req"[..])),
+            Frame::new(TypeLabel::Response, 0, Bytes::from(&b"resp"[..])),
+        ];
+        for frame in frames {
+            Codec.encode(frame, &mut incoming).unwrap();
+        }
+
+        let reader = Cursor::new(incoming.to_vec());
+        let mut client = BlockingClient::new(reader, Vec::new(), |req: Bytes| {
+            let mut resp = req.to_vec();
+            resp.reverse();
+            Ok(Bytes::from(resp))
+        });
+        let resp = client.call(Bytes::from(&b"hello"[..])).unwrap();
+        assert_eq!(resp, Bytes::from(&b"resp"[..]));
+
+        let mut outgoing = BytesMut::from(&client.writer()[..]);
+        let expected = vec![
+            (0, 0, &b"hello"[..]),
+            (3, 5, &b"ping"[..]),
+            (1, 0, &b"qer"[..]),
+        ];
+        for (t, id, payload) in expected {
+            let (ft, fid, fpayload) = Codec.decode(&mut outgoing).unwrap().unwrap().into();
+            assert_eq!(u8::from(ft), t);
+            assert_eq!(fid, id);
+            assert_eq!(fpayload, Bytes::from(payload));
+        }
+        assert!(outgoing.is_empty());
+    }
+}
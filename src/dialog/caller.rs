@@ -32,7 +32,7 @@ impl Caller {
         Box::new(
             handler_ch_fut.map_err(|_| io::Error::new(io::ErrorKind::Other, "send failed from caller"))
             .and_then(|handler_ch|{
-                 rx.map_err(|_| panic!("oneshot tx dropped"))
+                 rx.map_err(|_| io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed before response"))
                 .map(|resp| (Caller{handler_ch, next_id}, resp))}
             )
         )
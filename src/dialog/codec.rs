@@ -1,10 +1,23 @@
 use super::frame::{Frame, TypeLabel};
 use bytes::{BufMut, ByteOrder, BytesMut, LittleEndian};
-use framed::codec::{Decoder, Encoder};
+use framed::codec::{CobsCodec, CodecExt, Decoder, Encoder, Layered};
 use std::{io, mem};
 
 pub struct Codec;
 
+/// Bit of `T` set when the message carries a channel other than 0.
+const CHANNEL_FLAG: u8 = 0x80;
+const CHANNEL_LEN: usize = mem::size_of::<u16>();
+
+/// Returns `T` with the channel flag set if `channel` has to be sent.
+fn type_byte(t: TypeLabel, channel: u16) -> u8 {
+    if channel == 0 {
+        t.into()
+    } else {
+        u8::from(t) | CHANNEL_FLAG
+    }
+}
+
 impl Decoder for Codec {
     type Item = Frame;
     type Error = io::Error;
@@ -12,13 +25,29 @@ impl Decoder for Codec {
     fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Self::Item>> {
         const HEADER_LEN: usize = 1 + 2 * mem::size_of::<u64>();
         if buf.len() >= HEADER_LEN {
+            let has_channel = buf[0] & CHANNEL_FLAG != 0;
+            let header_len = if has_channel {
+                HEADER_LEN + CHANNEL_LEN
+            } else {
+                HEADER_LEN
+            };
             let message_len = LittleEndian::read_u64(&buf[1..9]);
-            if buf.len() >= message_len as usize + HEADER_LEN {
-                let message_type = TypeLabel::from(buf[0]);
+            if buf.len() >= message_len as usize + header_len {
+                let message_type = TypeLabel::from(buf[0] & !CHANNEL_FLAG);
                 let id = LittleEndian::read_u64(&buf[9..17]);
-                buf.split_to(HEADER_LEN);
+                let channel = if has_channel {
+                    LittleEndian::read_u16(&buf[17..19])
+                } else {
+                    0
+                };
+                buf.split_to(header_len);
                 let payload = buf.split_to(message_len as usize);
-                Ok(Some(Frame::new(message_type, id, payload.freeze())))
+                Ok(Some(Frame::with_channel(
+                    message_type,
+                    channel,
+                    id,
+                    payload.freeze(),
+                )))
             } else {
                 Ok(None)
             }
@@ -33,17 +62,112 @@ impl Encoder for Codec {
     type Error = io::Error;
 
     fn encode(&mut self, frame: Self::Item, buf: &mut BytesMut) -> io::Result<()> {
+        let channel = frame.channel();
         let (t, id, payload) = frame.into();
-        let len = payload.len() as usize + 17;
+        let len = payload.len() as usize + 17 + CHANNEL_LEN;
         buf.reserve(len);
-        buf.put_u8(t.into());
+        buf.put_u8(type_byte(t, channel));
         buf.put_u64_le(payload.len() as u64);
         buf.put_u64_le(id);
+        if channel != 0 {
+            buf.put_u16_le(channel);
+        }
         buf.put_slice(&payload);
         Ok(())
     }
 }
 
+/// Codec for dialog messages over unreliable byte streams such as serial links.
+///
+/// Each message is a COBS frame, so the stream resynchronises on the next frame after lost or
+/// corrupted bytes.
+pub type SerialCodec = Layered<CobsCodec, PacketCodec>;
+
+/// Creates a `SerialCodec`.
+pub fn serial_codec() -> SerialCodec {
+    CobsCodec::new().layer(PacketCodec)
+}
+
+/// Codec for dialog messages without the `len` field, for transports which already delimit
+/// packets.
+///
+/// Each packet ends with a CRC-16 of the message. Each call to `decode` consumes the whole buffer
+/// as one packet, packets which are too short or fail the check are dropped.
+pub struct PacketCodec;
+
+const PACKET_HEADER_LEN: usize = 1 + mem::size_of::<u64>();
+const PACKET_CRC_LEN: usize = mem::size_of::<u16>();
+
+/// CRC-16/CCITT-FALSE of `data`.
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |crc, b| {
+        (0..8).fold(crc ^ (u16::from(*b) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+impl Decoder for PacketCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Self::Item>> {
+        let mut packet = buf.take();
+        if packet.len() < PACKET_HEADER_LEN + PACKET_CRC_LEN {
+            return Ok(None);
+        }
+        let crc_index = packet.len() - PACKET_CRC_LEN;
+        if crc16(&packet[..crc_index]) != LittleEndian::read_u16(&packet[crc_index..]) {
+            return Ok(None);
+        }
+        packet.truncate(crc_index);
+        let has_channel = packet[0] & CHANNEL_FLAG != 0;
+        if has_channel && packet.len() < PACKET_HEADER_LEN + CHANNEL_LEN {
+            return Ok(None);
+        }
+        let message_type = TypeLabel::from(packet[0] & !CHANNEL_FLAG);
+        let id = LittleEndian::read_u64(&packet[1..9]);
+        let (channel, header_len) = if has_channel {
+            let channel = LittleEndian::read_u16(&packet[9..11]);
+            (channel, PACKET_HEADER_LEN + CHANNEL_LEN)
+        } else {
+            (0, PACKET_HEADER_LEN)
+        };
+        let payload = packet.split_off(header_len);
+        Ok(Some(Frame::with_channel(
+            message_type,
+            channel,
+            id,
+            payload.freeze(),
+        )))
+    }
+}
+
+impl Encoder for PacketCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Self::Item, buf: &mut BytesMut) -> io::Result<()> {
+        let channel = frame.channel();
+        let (t, id, payload) = frame.into();
+        let start = buf.len();
+        buf.reserve(payload.len() + PACKET_HEADER_LEN + CHANNEL_LEN + PACKET_CRC_LEN);
+        buf.put_u8(type_byte(t, channel));
+        buf.put_u64_le(id);
+        if channel != 0 {
+            buf.put_u16_le(channel);
+        }
+        buf.put_slice(&payload);
+        let crc = crc16(&buf[start..]);
+        buf.put_u16_le(crc);
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -73,4 +197,49 @@ mod test {
         let _ = Codec.encode(decoded, &mut encoded_again).unwrap();
         assert_eq!(&encoded_again[..], ENCODED);
     }
+    #[test]
+    fn decode_channel() {
+        let frame = Frame::with_channel(TypeLabel::Request, 0x0102, 12, Bytes::new());
+        let mut encoded = BytesMut::new();
+        let _ = Codec.encode(frame, &mut encoded).unwrap();
+        assert_eq!(encoded[0], CHANNEL_FLAG);
+        assert_eq!(&encoded[17..19], &[2, 1]);
+        let decoded = Codec.decode(&mut encoded).unwrap().unwrap();
+        assert_eq!(decoded.channel(), 0x0102);
+        assert_eq!(u8::from(decoded.into().0), 0);
+
+        let frame = Frame::with_channel(TypeLabel::Request, 0x0102, 12, Bytes::new());
+        let mut encoded = BytesMut::new();
+        let _ = PacketCodec.encode(frame, &mut encoded).unwrap();
+        let decoded = PacketCodec.decode(&mut encoded).unwrap().unwrap();
+        assert_eq!(decoded.channel(), 0x0102);
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn serial_resync() {
+        let mut codec = serial_codec();
+        let mut first = BytesMut::new();
+        codec.encode(sample_frame(), &mut first).unwrap();
+        let mut second = BytesMut::new();
+        let frame = Frame::new(TypeLabel::Request, 7, Bytes::from(&b"ok"[..]));
+        codec.encode(frame, &mut second).unwrap();
+
+        // Lose any byte but the delimiters of the first message.
+        for lost in 1..first.len() - 1 {
+            let mut encoded = BytesMut::new();
+            encoded.extend_from_slice(&first[..lost]);
+            encoded.extend_from_slice(&first[lost + 1..]);
+            encoded.extend_from_slice(&second);
+            let (t, id, payload) = codec.decode(&mut encoded).unwrap().unwrap().into();
+            assert_eq!(u8::from(t), 0);
+            assert_eq!(id, 7);
+            assert_eq!(payload, Bytes::from(&b"ok"[..]));
+            assert!(codec.decode(&mut encoded).unwrap().is_none());
+        }
+    }
 }
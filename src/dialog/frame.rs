@@ -33,17 +33,31 @@ impl From<u8> for TypeLabel {
 
 pub struct Frame {
     t: TypeLabel,
+    channel: u16,
     id: u64,
     payload: Bytes,
 }
 
 impl Frame {
     pub fn new(t: TypeLabel, id: u64, payload: Bytes) -> Frame {
-        Frame { t, id, payload }
+        Frame::with_channel(t, 0, id, payload)
+    }
+
+    pub fn with_channel(t: TypeLabel, channel: u16, id: u64, payload: Bytes) -> Frame {
+        Frame {
+            t,
+            channel,
+            id,
+            payload,
+        }
+    }
+
+    pub fn channel(&self) -> u16 {
+        self.channel
     }
 
     pub fn into(self) -> (TypeLabel, u64, Bytes) {
-        let Frame { t, id, payload } = self;
+        let Frame { t, id, payload, .. } = self;
         (t, id, payload)
     }
 }
@@ -2,15 +2,18 @@ use std::collections::HashMap;
 use std::io;
 use std::sync::Arc;
 
-use super::Codec;
+use super::{Codec, PacketCodec};
 use super::{Frame, TypeLabel};
 
 use bytes::Bytes;
-use framed::framed::framed;
-use futures::channel::{mpsc, oneshot};
+use framed::codec::{Decoder, Encoder};
+use framed::datagram::{AsyncDatagram, FramedDatagram};
+use framed::framed::{framed, Framed};
+use futures::channel::oneshot;
 use futures::future::ok;
 use futures::io::{AsyncRead, AsyncWrite};
 use futures::prelude::*;
+use futures::stream;
 use parking_lot::Mutex;
 
 pub struct Handler {
@@ -18,18 +21,128 @@ pub struct Handler {
 }
 
 impl Handler {
-    pub fn new<F, A>(
-        dialog_io: A,
-        caller_ch: mpsc::Receiver<(usize, oneshot::Sender<Bytes>, Bytes)>,
-        mut f: F,
-    ) -> Handler
+    pub fn new<F, A, C>(dialog_io: A, caller_ch: C, f: F) -> Handler
     where
+        C: Stream<Item = (usize, oneshot::Sender<Bytes>, Bytes), Error = Never>,
+        C: Send + Sync + 'static,
         F: FnMut(Bytes) -> Box<Future<Item = Bytes, Error = io::Error> + Send + Sync>,
         F: Send + Sync + 'static,
         A: AsyncRead + AsyncWrite + Send + Sync + 'static,
     {
-        let (dialog_sink, dialog_stream) = framed(dialog_io, Codec).split();
+        Handler::with_codec(dialog_io, Codec, caller_ch, f)
+    }
 
+    /// Creates a handler framing messages on `dialog_io` with `codec` instead of `Codec`.
+    pub fn with_codec<F, A, D, C>(dialog_io: A, codec: D, caller_ch: C, f: F) -> Handler
+    where
+        C: Stream<Item = (usize, oneshot::Sender<Bytes>, Bytes), Error = Never>,
+        C: Send + Sync + 'static,
+        F: FnMut(Bytes) -> Box<Future<Item = Bytes, Error = io::Error> + Send + Sync>,
+        F: Send + Sync + 'static,
+        A: AsyncRead + AsyncWrite + Send + Sync + 'static,
+        D: Decoder<Item = Frame, Error = io::Error> + Encoder<Item = Frame, Error = io::Error>,
+        D: Send + Sync + 'static,
+    {
+        Handler::with_framed(framed(dialog_io, codec), caller_ch, f)
+    }
+
+    /// Creates a handler on an already framed `dialog_io`, such as one with a flush policy set.
+    pub fn with_framed<F, A, D, C>(dialog_io: Framed<A, D>, caller_ch: C, f: F) -> Handler
+    where
+        C: Stream<Item = (usize, oneshot::Sender<Bytes>, Bytes), Error = Never>,
+        C: Send + Sync + 'static,
+        F: FnMut(Bytes) -> Box<Future<Item = Bytes, Error = io::Error> + Send + Sync>,
+        F: Send + Sync + 'static,
+        A: AsyncRead + AsyncWrite + Send + Sync + 'static,
+        D: Decoder<Item = Frame, Error = io::Error> + Encoder<Item = Frame, Error = io::Error>,
+        D: Send + Sync + 'static,
+    {
+        let (dialog_sink, dialog_stream) = dialog_io.split();
+        Handler::from_parts(dialog_sink, dialog_stream, 0, caller_ch, f)
+    }
+
+    /// Creates a handler sending one message per datagram on `dialog_io`.
+    ///
+    /// Requests are sent to `peer`, or to the connected peer if it is `None`. When `peer` is
+    /// set, datagrams from other addresses are ignored. Responses are sent to the address the
+    /// request came from, so a handler without `peer` can serve many unconnected clients.
+    /// Frames of other channels than 0 are ignored.
+    pub fn with_datagram<F, T, C>(
+        dialog_io: T,
+        peer: Option<T::Addr>,
+        caller_ch: C,
+        f: F,
+    ) -> Handler
+    where
+        C: Stream<Item = (usize, oneshot::Sender<Bytes>, Bytes), Error = Never>,
+        C: Send + Sync + 'static,
+        F: FnMut(Bytes) -> Box<Future<Item = Bytes, Error = io::Error> + Send + Sync>,
+        F: Send + Sync + 'static,
+        T: AsyncDatagram + Send + Sync + 'static,
+        T::Addr: Clone + PartialEq + Send + Sync + 'static,
+    {
+        let (dialog_sink, dialog_stream) = FramedDatagram::new(dialog_io, PacketCodec).split();
+        let senders = Arc::new(Mutex::new(Senders {
+            next_id: 0,
+            ids: HashMap::new(),
+        }));
+        let dialog_sink = {
+            let peer = peer.clone();
+            let senders = Arc::clone(&senders);
+            dialog_sink.with_flat_map(move |frame: Frame| {
+                let channel = frame.channel();
+                let (t, id, payload) = frame.into();
+                let sent = match t {
+                    TypeLabel::Response | TypeLabel::Pong => match senders.lock().remove(id) {
+                        Some((id, addr)) => Some((id, Some(addr))),
+                        // Expired, and there is no peer to fall back to.
+                        None if peer.is_none() => None,
+                        None => Some((id, peer.clone())),
+                    },
+                    _ => Some((id, peer.clone())),
+                };
+                let frame =
+                    sent.map(|(id, addr)| (Frame::with_channel(t, channel, id, payload), addr));
+                stream::iter_ok::<_, io::Error>(frame)
+            })
+        };
+        let dialog_stream = dialog_stream
+            .filter(move |&(ref frame, ref addr)| {
+                // Other channels are ignored by the handler, so their requests are never answered.
+                let from_peer = peer.as_ref().map_or(true, |peer| peer == addr);
+                Ok(from_peer && frame.channel() == 0)
+            })
+            .map(move |(frame, addr)| {
+                let channel = frame.channel();
+                let (t, id, payload) = frame.into();
+                let id = match t {
+                    TypeLabel::Request | TypeLabel::Ping => senders.lock().insert(id, addr),
+                    _ => id,
+                };
+                Frame::with_channel(t, channel, id, payload)
+            });
+        Handler::from_parts(dialog_sink, dialog_stream, 0, caller_ch, f)
+    }
+
+    /// Creates a handler for a single channel on top of an already framed sink and stream.
+    ///
+    /// Requests from `caller_ch` are sent on `channel` and frames from other channels are
+    /// ignored. The handler finishes when `dialog_stream` ends.
+    pub fn from_parts<Si, St, C, F>(
+        dialog_sink: Si,
+        dialog_stream: St,
+        channel: u16,
+        caller_ch: C,
+        mut f: F,
+    ) -> Handler
+    where
+        C: Stream<Item = (usize, oneshot::Sender<Bytes>, Bytes), Error = Never>,
+        C: Send + Sync + 'static,
+        Si: Sink<SinkItem = Frame, SinkError = io::Error> + Send + Sync + 'static,
+        St: Stream<Item = Frame, Error = io::Error> + Send + Sync + 'static,
+        F: FnMut(Bytes) -> Box<Future<Item = Bytes, Error = io::Error> + Send + Sync>,
+        F: Send + Sync + 'static,
+    {
         let caller_resp_map: Arc<Mutex<HashMap<usize, oneshot::Sender<_>>>> =
             Arc::new(Mutex::new(HashMap::new()));
 
@@ -39,18 +152,29 @@ impl Handler {
                 .map(move |data| {
                     let (id, oneshot, message) = data;
                     caller_resp_map.lock().insert(id, oneshot);
-                    Frame::new(TypeLabel::Request, id as u64, message)
+                    Some(Some(Frame::with_channel(
+                        TypeLabel::Request,
+                        channel,
+                        id as u64,
+                        message,
+                    )))
                 })
                 .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "caller channel broken"))
         };
 
         let dialog_stream = dialog_stream
+            .filter(move |message| Ok(message.channel() == channel))
             .and_then(move |message| Handler::receiver(message, &caller_resp_map, &mut f))
-            .filter_map(|x| Ok(x));
+            .map(Some)
+            // Marks the end of the dialog stream, after which the handler stops.
+            .chain(stream::iter_ok(vec![None]));
+
+        let frames = caller_ch_stream
+            .select(dialog_stream)
+            .take_while(|x| Ok(x.is_some()))
+            .filter_map(|x| Ok(x.and_then(|x| x)));
 
-        let fut = dialog_sink
-            .send_all(caller_ch_stream.select(dialog_stream))
-            .map(|_| ());
+        let fut = dialog_sink.send_all(frames).map(|_| ());
 
         Handler { f: Box::new(fut) }
     }
@@ -63,11 +187,13 @@ impl Handler {
     where
         F: FnMut(Bytes) -> Box<Future<Item = Bytes, Error = io::Error> + Send + Sync> + Send,
     {
+        let channel = message.channel();
         let (t, id, payload) = message.into();
         match t {
             TypeLabel::Request => {
-                let send_fut =
-                    f(payload).map(move |resp| Some(Frame::new(TypeLabel::Response, id, resp)));
+                let send_fut = f(payload).map(move |resp| {
+                    Some(Frame::with_channel(TypeLabel::Response, channel, id, resp))
+                });
                 Box::new(send_fut)
             }
             TypeLabel::Response => {
@@ -77,7 +203,7 @@ impl Handler {
                 Box::new(ok(None))
             }
             TypeLabel::Ping => {
-                let pong = Frame::new(TypeLabel::Pong, id, payload);
+                let pong = Frame::with_channel(TypeLabel::Pong, channel, id, payload);
                 Box::new(ok(Some(pong)))
             }
             _ => Box::new(ok(None)),
@@ -85,6 +211,34 @@ impl Handler {
     }
 }
 
+/// Most senders kept by `Senders`, beyond which the oldest are forgotten.
+const MAX_SENDERS: u64 = 1024;
+
+/// Senders of the requests being handled on a datagram socket.
+///
+/// Requests get a local id as they arrive, since the ids of different senders may collide, and
+/// their responses are sent back with the original id. Requests are handled one at a time, so
+/// only the senders of the last `MAX_SENDERS` requests are kept.
+struct Senders<A> {
+    next_id: u64,
+    /// Original id and sender by local id.
+    ids: HashMap<u64, (u64, A)>,
+}
+
+impl<A> Senders<A> {
+    fn insert(&mut self, id: u64, addr: A) -> u64 {
+        let local_id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.ids.remove(&local_id.wrapping_sub(MAX_SENDERS));
+        self.ids.insert(local_id, (id, addr));
+        local_id
+    }
+
+    fn remove(&mut self, local_id: u64) -> Option<(u64, A)> {
+        self.ids.remove(&local_id)
+    }
+}
+
 impl Future for Handler {
     type Item = ();
     type Error = io::Error;
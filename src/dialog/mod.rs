@@ -1,16 +1,23 @@
+mod blocking;
 mod caller;
 mod codec;
 mod frame;
 mod handler;
+mod mux;
 
 use std::io;
 
+pub use self::blocking::BlockingClient;
 pub use self::caller::Caller;
-pub use self::codec::Codec;
+pub use self::codec::{serial_codec, Codec, PacketCodec, SerialCodec};
 pub use self::frame::{Frame, TypeLabel};
 pub use self::handler::Handler;
+pub use self::mux::{channel_id, Mux, MuxHandler};
 
 use bytes::Bytes;
+use framed::codec::{Decoder, Encoder};
+use framed::datagram::AsyncDatagram;
+use framed::framed::{framed, FlushPolicy};
 use futures::channel::mpsc;
 use futures::io::{AsyncRead, AsyncWrite};
 use futures::prelude::*;
@@ -20,6 +27,23 @@ pub trait Dialog {
     where
         F: Fn(Bytes) -> Box<Future<Item = Bytes, Error = io::Error> + Send + Sync>,
         F: Send + Sync + 'static;
+
+    /// Starts a dialog framing messages with `codec`, such as `serial_codec()` for serial links.
+    fn dialog_with_codec<D, F>(self, codec: D, f: F) -> (Caller, Handler)
+    where
+        D: Decoder<Item = Frame, Error = io::Error> + Encoder<Item = Frame, Error = io::Error>,
+        D: Send + Sync + 'static,
+        F: Fn(Bytes) -> Box<Future<Item = Bytes, Error = io::Error> + Send + Sync>,
+        F: Send + Sync + 'static;
+
+    /// Starts a dialog whose outgoing frames are written according to `policy`.
+    ///
+    /// Deferred flushes let many small requests and responses share one write, at the cost of
+    /// latency.
+    fn dialog_with_flush_policy<F>(self, policy: FlushPolicy, f: F) -> (Caller, Handler)
+    where
+        F: Fn(Bytes) -> Box<Future<Item = Bytes, Error = io::Error> + Send + Sync>,
+        F: Send + Sync + 'static;
 }
 
 impl<A> Dialog for A
@@ -34,6 +58,52 @@ where
         let (tx, rx) = mpsc::channel(1);
         (Caller::new(tx), Handler::new(self, rx, f))
     }
+
+    fn dialog_with_codec<D, F>(self, codec: D, f: F) -> (Caller, Handler)
+    where
+        D: Decoder<Item = Frame, Error = io::Error> + Encoder<Item = Frame, Error = io::Error>,
+        D: Send + Sync + 'static,
+        F: Fn(Bytes) -> Box<Future<Item = Bytes, Error = io::Error> + Send + Sync>,
+        F: Send + Sync + 'static,
+    {
+        let (tx, rx) = mpsc::channel(1);
+        (Caller::new(tx), Handler::with_codec(self, codec, rx, f))
+    }
+
+    fn dialog_with_flush_policy<F>(self, policy: FlushPolicy, f: F) -> (Caller, Handler)
+    where
+        F: Fn(Bytes) -> Box<Future<Item = Bytes, Error = io::Error> + Send + Sync>,
+        F: Send + Sync + 'static,
+    {
+        let mut dialog_io = framed(self, Codec);
+        dialog_io.set_flush_policy(policy);
+        let (tx, rx) = mpsc::channel(1);
+        (Caller::new(tx), Handler::with_framed(dialog_io, rx, f))
+    }
+}
+
+/// Dialog over datagram sockets, one message per datagram.
+pub trait DatagramDialog: AsyncDatagram {
+    /// Starts a dialog with `peer`, or with the connected peer if it is `None`.
+    fn datagram_dialog<F>(self, peer: Option<Self::Addr>, f: F) -> (Caller, Handler)
+    where
+        F: Fn(Bytes) -> Box<Future<Item = Bytes, Error = io::Error> + Send + Sync>,
+        F: Send + Sync + 'static;
+}
+
+impl<T> DatagramDialog for T
+where
+    T: AsyncDatagram + Send + Sync + 'static,
+    T::Addr: Clone + PartialEq + Send + Sync + 'static,
+{
+    fn datagram_dialog<F>(self, peer: Option<Self::Addr>, f: F) -> (Caller, Handler)
+    where
+        F: Fn(Bytes) -> Box<Future<Item = Bytes, Error = io::Error> + Send + Sync>,
+        F: Send + Sync + 'static,
+    {
+        let (tx, rx) = mpsc::channel(1);
+        (Caller::new(tx), Handler::with_datagram(self, peer, rx, f))
+    }
 }
 
 #[cfg(test)]
@@ -44,7 +114,8 @@ mod test {
     use futures::future::ok;
     use std::cell::Cell;
     use std::rc::Rc;
-    use util::PairIO;
+    use std::time::Duration;
+    use util::{PairDatagram, PairIO};
 
     fn is_sync<T: Sync>() {}
     fn is_send<T: Send>() {}
@@ -89,4 +160,46 @@ mod test {
         let _ = block_on(f1.join(f2)).unwrap();
         assert_eq!(assert_count.get(), 2);
     }
+
+    #[test]
+    fn serial_call() {
+        let (s1, s2) = PairIO::new();
+        let (_, fut_echo) = s1.dialog_with_codec(serial_codec(), |req| Box::new(ok(req)));
+        let (caller, fut_caller) =
+            s2.dialog_with_codec(serial_codec(), |_| Box::new(ok(Bytes::new())));
+        block_on(spawn(fut_echo.map_err(|_| panic!("fut_echo panic")))).unwrap();
+        block_on(spawn(fut_caller.map_err(|_| panic!("fut_caller panic")))).unwrap();
+
+        let buf = Bytes::from(&b"\0serial\0"[..]);
+        let (_, resp) = block_on(caller.call(buf.clone())).unwrap();
+        assert_eq!(resp, buf);
+    }
+
+    #[test]
+    fn delayed_flush_call() {
+        let (s1, s2) = PairIO::new();
+        let policy = FlushPolicy::Delay(Duration::from_millis(5));
+        let (_, fut_echo) = s1.dialog_with_flush_policy(policy, |req| Box::new(ok(req)));
+        let (caller, fut_caller) =
+            s2.dialog_with_flush_policy(policy, |_| Box::new(ok(Bytes::new())));
+        block_on(spawn(fut_echo.map_err(|_| panic!("fut_echo panic")))).unwrap();
+        block_on(spawn(fut_caller.map_err(|_| panic!("fut_caller panic")))).unwrap();
+
+        let buf = Bytes::from(&b"delayed"[..]);
+        let (_, resp) = block_on(caller.call(buf.clone())).unwrap();
+        assert_eq!(resp, buf);
+    }
+
+    #[test]
+    fn datagram_call() {
+        let (s1, s2) = PairDatagram::new();
+        let (_, fut_echo) = s1.datagram_dialog(None, |req| Box::new(ok(req)));
+        let (caller, fut_caller) = s2.datagram_dialog(Some(()), |_| Box::new(ok(Bytes::new())));
+        block_on(spawn(fut_echo.map_err(|_| panic!("fut_echo panic")))).unwrap();
+        block_on(spawn(fut_caller.map_err(|_| panic!("fut_caller panic")))).unwrap();
+
+        let buf = Bytes::from(&b"datagram"[..]);
+        let (_, resp) = block_on(caller.call(buf.clone())).unwrap();
+        assert_eq!(resp, buf);
+    }
 }
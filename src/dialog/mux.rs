@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use super::{Caller, Codec, Frame, Handler};
+
+use bytes::Bytes;
+use framed::framed::framed;
+use futures::channel::mpsc;
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::prelude::*;
+use futures::stream;
+use parking_lot::Mutex;
+
+/// Number of incoming frames buffered for a channel before it is closed.
+const CHANNEL_BUFFER: usize = 64;
+
+type ChannelMap = Arc<Mutex<HashMap<u16, ChannelEntry>>>;
+
+struct ChannelEntry {
+    sender: mpsc::Sender<Frame>,
+    /// Set when the channel is closed for not reading its frames fast enough.
+    overflowed: Arc<AtomicBool>,
+}
+
+/// Opens logical sub-channels multiplexed over one dialog connection.
+///
+/// Every channel has its own request handler and its own dialog ids, so each
+/// `(Caller, Handler)` pair behaves like a separate connection. Dropping a
+/// channel's `Handler` closes only that channel.
+///
+/// Incoming frames are buffered per channel, so a channel which isn't read
+/// doesn't hold back the others. A channel whose buffer is full when a frame
+/// arrives is closed, its `Handler` failing.
+#[derive(Clone)]
+pub struct Mux {
+    channels: ChannelMap,
+    outgoing: mpsc::Sender<Frame>,
+}
+
+/// Drives the connection shared by all channels of a `Mux`.
+pub struct MuxHandler {
+    f: Box<Future<Item = (), Error = io::Error> + Send + Sync>,
+}
+
+impl Mux {
+    pub fn new<A>(dialog_io: A) -> (Mux, MuxHandler)
+    where
+        A: AsyncRead + AsyncWrite + Send + Sync + 'static,
+    {
+        let (dialog_sink, dialog_stream) = framed(dialog_io, Codec).split();
+        let channels: ChannelMap = Arc::new(Mutex::new(HashMap::new()));
+        let (outgoing, outgoing_ch) = mpsc::channel(1);
+
+        let outgoing_ch = outgoing_ch
+            .map(|frame| Some(Some(frame)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "channel sink broken"));
+
+        let dialog_stream = {
+            let channels = Arc::clone(&channels);
+            dialog_stream
+                .map(move |frame| {
+                    Mux::route(&channels, frame);
+                    Some(None)
+                })
+                // Marks the end of the connection, see `Handler::from_parts`.
+                .chain(stream::iter_ok(vec![None]))
+        };
+
+        let frames = outgoing_ch
+            .select(dialog_stream)
+            .take_while(|x| Ok(x.is_some()))
+            .filter_map(|x| Ok(x.and_then(|x| x)));
+
+        let fut = {
+            let channels = Arc::clone(&channels);
+            dialog_sink
+                .send_all(frames)
+                .then(move |result| {
+                    // Ends the handlers of all open channels along with the connection.
+                    channels.lock().clear();
+                    result.map(|_| ())
+                })
+        };
+
+        (
+            Mux { channels, outgoing },
+            MuxHandler { f: Box::new(fut) },
+        )
+    }
+
+    /// Opens the numbered channel `channel` with `f` handling its requests.
+    ///
+    /// Returns `None` if the channel is already open on this side.
+    pub fn channel<F>(&self, channel: u16, f: F) -> Option<(Caller, Handler)>
+    where
+        F: Fn(Bytes) -> Box<Future<Item = Bytes, Error = io::Error> + Send + Sync>,
+        F: Send + Sync + 'static,
+    {
+        let (frame_tx, frame_rx) = mpsc::channel(CHANNEL_BUFFER);
+        let overflowed = Arc::new(AtomicBool::new(false));
+        {
+            let mut channels = self.channels.lock();
+            if channels.contains_key(&channel) {
+                return None;
+            }
+            let entry = ChannelEntry {
+                sender: frame_tx,
+                overflowed: Arc::clone(&overflowed),
+            };
+            channels.insert(channel, entry);
+        }
+        let stream = ChannelStream {
+            channel,
+            channels: Arc::clone(&self.channels),
+            inner: frame_rx,
+            overflowed,
+        };
+        let sink = self
+            .outgoing
+            .clone()
+            .sink_map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "connection closed"));
+        let (tx, rx) = mpsc::channel(1);
+        Some((
+            Caller::new(tx),
+            Handler::from_parts(sink, stream, channel, rx, f),
+        ))
+    }
+
+    /// Opens the channel named `name`, see `channel_id`.
+    pub fn named_channel<F>(&self, name: &str, f: F) -> Option<(Caller, Handler)>
+    where
+        F: Fn(Bytes) -> Box<Future<Item = Bytes, Error = io::Error> + Send + Sync>,
+        F: Send + Sync + 'static,
+    {
+        self.channel(channel_id(name), f)
+    }
+
+    /// Passes `frame` on to its channel without waiting, dropping it if the
+    /// channel isn't open and closing the channel if its buffer is full.
+    fn route(channels: &ChannelMap, frame: Frame) {
+        let channel = frame.channel();
+        let mut channels = channels.lock();
+        let full = match channels.get_mut(&channel) {
+            // A closed channel drops its frames, the entry is removed by `ChannelStream`.
+            Some(entry) => match entry.sender.try_send(frame) {
+                Err(ref e) => e.is_full(),
+                Ok(()) => false,
+            },
+            None => false,
+        };
+        if full {
+            if let Some(entry) = channels.remove(&channel) {
+                entry.overflowed.store(true, Ordering::Release);
+            }
+        }
+    }
+}
+
+/// Maps a channel name to a channel id.
+///
+/// Both peers derive the same id for the same name. Different names may map
+/// to the same id, in which case only the first of them can be opened.
+pub fn channel_id(name: &str) -> u16 {
+    let hash = name
+        .bytes()
+        .fold(0x811c_9dc5u32, |h, b| (h ^ u32::from(b)).wrapping_mul(0x0100_0193));
+    ((hash >> 16) ^ (hash & 0xffff)) as u16
+}
+
+impl Future for MuxHandler {
+    type Item = ();
+    type Error = io::Error;
+    fn poll(&mut self, cx: &mut task::Context) -> Poll<Self::Item, Self::Error> {
+        self.f.poll(cx)
+    }
+}
+
+struct ChannelStream {
+    channel: u16,
+    channels: ChannelMap,
+    inner: mpsc::Receiver<Frame>,
+    overflowed: Arc<AtomicBool>,
+}
+
+impl Stream for ChannelStream {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn poll_next(&mut self, cx: &mut task::Context) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.inner.poll_next(cx).map_err(Never::never_into)? {
+            Async::Ready(None) if self.overflowed.load(Ordering::Acquire) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "channel closed, too many frames buffered",
+            )),
+            x => Ok(x),
+        }
+    }
+}
+
+impl Drop for ChannelStream {
+    fn drop(&mut self) {
+        let mut channels = self.channels.lock();
+        // The id may already be used by a channel opened after this one overflowed.
+        let ours = match channels.get(&self.channel) {
+            Some(entry) => Arc::ptr_eq(&entry.overflowed, &self.overflowed),
+            None => false,
+        };
+        if ours {
+            channels.remove(&self.channel);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::executor::{block_on, spawn};
+    use dialog::TypeLabel;
+    use futures::future::ok;
+    use util::PairIO;
+
+    #[test]
+    fn isolated_channels() {
+        let (s1, s2) = PairIO::new();
+        let (mux1, fut) = Mux::new(s1);
+        block_on(spawn(fut.map_err(|_| panic!("mux1 panic")))).unwrap();
+        let (mux2, fut) = Mux::new(s2);
+        block_on(spawn(fut.map_err(|_| panic!("mux2 panic")))).unwrap();
+
+        let (caller_echo, fut) = mux1.channel(1, |_| Box::new(ok(Bytes::new()))).unwrap();
+        block_on(spawn(fut.map_err(|_| panic!("channel 1 panic")))).unwrap();
+        let (_, fut) = mux2.channel(1, |req| Box::new(ok(req))).unwrap();
+        block_on(spawn(fut.map_err(|_| panic!("channel 1 panic")))).unwrap();
+
+        let del = Bytes::from(&b"del"[..]);
+        let (caller_del, fut) = mux1.named_channel("del", |_| Box::new(ok(Bytes::new()))).unwrap();
+        block_on(spawn(fut.map_err(|_| panic!("channel del panic")))).unwrap();
+        let (_, fut) = {
+            let del = del.clone();
+            mux2.named_channel("del", move |_| Box::new(ok(del.clone())))
+                .unwrap()
+        };
+        block_on(spawn(fut.map_err(|_| panic!("channel del panic")))).unwrap();
+
+        assert!(mux1.channel(1, |req| Box::new(ok(req))).is_none());
+
+        // Closing a channel frees its id and leaves the others working.
+        let closed = mux1.channel(3, |req| Box::new(ok(req))).unwrap();
+        drop(closed);
+        assert!(mux1.channel(3, |req| Box::new(ok(req))).is_some());
+
+        let hello = Bytes::from(&b"hello"[..]);
+        let f1 = caller_echo
+            .call(hello.clone())
+            .map(move |(_, resp)| assert_eq!(resp, hello));
+        let f2 = caller_del
+            .call(Bytes::new())
+            .map(move |(_, resp)| assert_eq!(resp, del));
+        let _ = block_on(f1.join(f2)).unwrap();
+    }
+
+    #[test]
+    fn unread_channel() {
+        let (s1, s2) = PairIO::new();
+        let (mux1, fut) = Mux::new(s1);
+        block_on(spawn(fut.map_err(|_| panic!("mux1 panic")))).unwrap();
+        let (mux2, fut) = Mux::new(s2);
+        block_on(spawn(fut.map_err(|_| panic!("mux2 panic")))).unwrap();
+
+        let (caller_echo, fut) = mux1.channel(1, |_| Box::new(ok(Bytes::new()))).unwrap();
+        block_on(spawn(fut.map_err(|_| panic!("channel 1 panic")))).unwrap();
+        let (_, fut) = mux2.channel(1, |req| Box::new(ok(req))).unwrap();
+        block_on(spawn(fut.map_err(|_| panic!("channel 1 panic")))).unwrap();
+
+        let (caller_unread, fut) = mux1.channel(2, |_| Box::new(ok(Bytes::new()))).unwrap();
+        block_on(spawn(fut.map_err(|_| panic!("channel 2 panic")))).unwrap();
+        // Never polled, so the frames of channel 2 are never read.
+        let _unread = mux2.channel(2, |req| Box::new(ok(req))).unwrap();
+        for _ in 0..4 {
+            let call = caller_unread.clone().call(Bytes::new());
+            block_on(spawn(call.then(|_| Ok::<_, Never>(())))).unwrap();
+        }
+
+        let hello = Bytes::from(&b"hello"[..]);
+        let (_, resp) = block_on(caller_echo.call(hello.clone())).unwrap();
+        assert_eq!(resp, hello);
+    }
+
+    #[test]
+    fn close_overflowing_channel() {
+        let (s1, _s2) = PairIO::new();
+        let (mux, _fut) = Mux::new(s1);
+        let (_caller, handler) = mux.channel(2, |req| Box::new(ok(req))).unwrap();
+        for id in 0..CHANNEL_BUFFER as u64 + 2 {
+            Mux::route(&mux.channels, Frame::with_channel(TypeLabel::Pong, 2, id, Bytes::new()));
+        }
+        assert!(!mux.channels.lock().contains_key(&2));
+        assert!(block_on(handler).is_err());
+
+        // The id is free again.
+        assert!(mux.channel(2, |req| Box::new(ok(req))).is_some());
+    }
+}
@@ -4,6 +4,32 @@ extern crate framed;
 extern crate futures;
 extern crate parking_lot;
 
+#[cfg(feature = "typed")]
+extern crate bincode;
+#[cfg(feature = "typed")]
+extern crate serde;
+#[cfg(all(test, feature = "typed"))]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(feature = "typed")]
+extern crate serde_json;
+#[cfg(feature = "service")]
+extern crate simproto_derive;
+
 pub mod dialog;
 pub mod sim;
 pub mod util;
+
+#[cfg(feature = "service")]
+pub use simproto_derive::service;
+
+/// Items used by the code generated by `service`.
+#[cfg(feature = "service")]
+#[doc(hidden)]
+pub mod __service {
+    pub use bytes::Bytes;
+    pub use futures::future::{join_all, ok};
+    pub use futures::{Future, Never, Sink, Stream};
+    pub use std::io;
+    pub use std::sync::Arc;
+}
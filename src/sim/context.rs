@@ -0,0 +1,161 @@
+use super::message::Headers;
+use bytes::Bytes;
+use futures::prelude::*;
+use parking_lot::Mutex;
+use std::cell::RefCell;
+use std::sync::Arc;
+use std::time::Instant;
+
+thread_local! {
+    static CURRENT: RefCell<Option<RequestContext>> = RefCell::new(None);
+}
+
+/// Why an unsubscribe handler is called.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnsubscribeReason {
+    /// The peer sent an unsubscription request.
+    Requested,
+    /// The connection to the peer ended.
+    Disconnected,
+    /// The subscriber was removed by the `Disconnect` policy, its queue being full.
+    Slow,
+}
+
+/// Properties of the request being handled by a `Handler`.
+///
+/// The context is current while a request handler is called and while its
+/// future is polled. Requests made through a `Requestor` in that time inherit
+/// from it.
+#[derive(Clone, Debug)]
+pub struct RequestContext {
+    pub deadline: Option<Instant>,
+    pub headers: Headers,
+    pub unsubscribe_reason: Option<UnsubscribeReason>,
+    response_headers: Arc<Mutex<Headers>>,
+    matched_segments: Arc<Mutex<Vec<Bytes>>>,
+}
+
+impl RequestContext {
+    pub fn new(deadline: Option<Instant>, headers: Headers) -> RequestContext {
+        RequestContext {
+            deadline,
+            headers,
+            unsubscribe_reason: None,
+            response_headers: Arc::new(Mutex::new(Headers::new())),
+            matched_segments: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Marks the context as the one of an unsubscribe handler called for `reason`.
+    pub fn with_unsubscribe_reason(mut self, reason: UnsubscribeReason) -> RequestContext {
+        self.unsubscribe_reason = Some(reason);
+        self
+    }
+
+    /// Headers set by the handler for the response.
+    pub fn response_headers(&self) -> Headers {
+        self.response_headers.lock().clone()
+    }
+
+    /// Calls `f` with this context as the current one and keeps it current
+    /// while the returned future is polled.
+    pub fn run<F, Fut>(self, f: F) -> WithContext<Fut>
+    where
+        F: FnOnce() -> Fut,
+    {
+        let inner = {
+            let _guard = self.enter();
+            f()
+        };
+        WithContext {
+            context: self,
+            inner,
+        }
+    }
+
+    fn enter(&self) -> Guard {
+        let previous = CURRENT.with(|c| c.replace(Some(self.clone())));
+        Guard { previous }
+    }
+}
+
+struct Guard {
+    previous: Option<RequestContext>,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with(|c| *c.borrow_mut() = previous);
+    }
+}
+
+/// Future polled within a `RequestContext`.
+pub struct WithContext<F> {
+    context: RequestContext,
+    inner: F,
+}
+
+impl<F: Future> Future for WithContext<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self, cx: &mut task::Context) -> Poll<Self::Item, Self::Error> {
+        let _guard = self.context.enter();
+        self.inner.poll(cx)
+    }
+}
+
+/// Returns the deadline of the request being handled, if it has one.
+pub fn deadline() -> Option<Instant> {
+    CURRENT.with(|c| c.borrow().as_ref().and_then(|c| c.deadline))
+}
+
+/// Returns the headers of the request being handled.
+pub fn headers() -> Headers {
+    CURRENT.with(|c| {
+        c.borrow()
+            .as_ref()
+            .map(|c| c.headers.clone())
+            .unwrap_or_default()
+    })
+}
+
+/// Returns the topic levels matched by the wildcards of the pattern the
+/// request being handled was routed to.
+///
+/// For the pattern `sensor/+/temp`, a request on `sensor/1/temp` gives `1`.
+pub fn matched_segments() -> Vec<Bytes> {
+    CURRENT.with(|c| {
+        c.borrow()
+            .as_ref()
+            .map(|c| c.matched_segments.lock().clone())
+            .unwrap_or_default()
+    })
+}
+
+/// Records the levels matched by the pattern the current request is routed to.
+pub fn set_matched_segments(segments: Vec<Bytes>) {
+    CURRENT.with(|c| {
+        if let Some(ref c) = *c.borrow() {
+            *c.matched_segments.lock() = segments;
+        }
+    })
+}
+
+/// Returns why the unsubscribe handler being called was called, or `None`
+/// outside of unsubscribe handlers.
+pub fn unsubscribe_reason() -> Option<UnsubscribeReason> {
+    CURRENT.with(|c| c.borrow().as_ref().and_then(|c| c.unsubscribe_reason))
+}
+
+/// Sets a header on the response to the request being handled.
+///
+/// Returns `false` when no request is being handled or when the header
+/// doesn't fit, see `Headers::insert`.
+pub fn set_response_header(key: Bytes, value: Bytes) -> bool {
+    CURRENT.with(|c| match *c.borrow() {
+        Some(ref c) => c.response_headers.lock().insert(key, value),
+        None => false,
+    })
+}
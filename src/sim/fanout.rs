@@ -0,0 +1,836 @@
+//! Delivery of the notifications of a topic to its subscribers.
+//!
+//! A topic pattern may match many topics, each notification is sent with the
+//! topic it is for and only reaches the subscribers of that topic.
+//!
+//! Every subscriber has its own bounded queue, from which one notification at
+//! a time is sent to it. A slow subscriber only fills its own queue, what
+//! happens then is decided by the `SlowSubscriberPolicy` of the topic.
+//!
+//! A topic in retained mode also keeps the last notification of every topic,
+//! which is queued for its new subscribers, so it is sent to them as their
+//! first notification right after they are accepted.
+//!
+//! A topic with a `NotificationLog` appends every notification to it before
+//! queueing it, sending its sequence number in the `SEQUENCE_HEADER`. The
+//! appends are done by the writer thread of the log, so a few notifications
+//! are taken from the channel at once to be written together. A subscriber
+//! resuming from a sequence number is first sent the logged notifications
+//! from there, which that thread reads a chunk at a time as they are sent.
+
+use super::context::{set_matched_segments, RequestContext, UnsubscribeReason};
+use super::handler::{Filter, RequestHandler};
+use super::log::{encode_sequence, NotificationLog, SEQUENCE_HEADER};
+use super::message::{Headers, Request, RequestType};
+use super::topic::matches;
+use bytes::{Bytes, BytesMut};
+use dialog::Caller;
+use futures::channel::{mpsc, oneshot};
+use futures::future::ok;
+use futures::prelude::*;
+use futures::task::Waker;
+use parking_lot::{Mutex, RwLock};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::{cmp, io, mem};
+
+/// What is done with a notification for a subscriber whose queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlowSubscriberPolicy {
+    /// Waits for room in the queue, holding back the notifications of every
+    /// subscriber of the topic.
+    Block,
+    /// Drops the oldest queued notification to make room.
+    DropOldest,
+    /// Drops the notification.
+    DropNewest,
+    /// Ends the subscription, as if the topic was removed, and calls the
+    /// unsubscribe handler of the topic.
+    Disconnect,
+}
+
+/// Most notifications a `Fanout` with a log waits to be appended at once.
+const MAX_APPENDS: usize = 64;
+/// Most logged notifications read at once to be replayed.
+const REPLAY_CHUNK: usize = 64;
+
+/// Delivery settings of a subscription topic.
+#[derive(Clone, Debug)]
+pub struct SubscriptionConfig {
+    queue_capacity: usize,
+    policy: SlowSubscriberPolicy,
+    retained: bool,
+    log: Option<NotificationLog>,
+}
+
+impl Default for SubscriptionConfig {
+    fn default() -> SubscriptionConfig {
+        SubscriptionConfig {
+            queue_capacity: 16,
+            policy: SlowSubscriberPolicy::Block,
+            retained: false,
+            log: None,
+        }
+    }
+}
+
+impl SubscriptionConfig {
+    /// Returns the default settings: queues of 16 notifications, the `Block`
+    /// policy, no retained notification and no log.
+    pub fn new() -> SubscriptionConfig {
+        SubscriptionConfig::default()
+    }
+
+    /// Sets the number of notifications queued for each subscriber, at least one.
+    pub fn queue_capacity(mut self, capacity: usize) -> SubscriptionConfig {
+        self.queue_capacity = cmp::max(capacity, 1);
+        self
+    }
+
+    pub fn policy(mut self, policy: SlowSubscriberPolicy) -> SubscriptionConfig {
+        self.policy = policy;
+        self
+    }
+
+    /// Sets whether the last notification of every topic is kept and sent to
+    /// every new subscriber of that topic right after it is accepted.
+    pub fn retained(mut self, retained: bool) -> SubscriptionConfig {
+        self.retained = retained;
+        self
+    }
+
+    /// Sets the log every notification is appended to, from which subscribers
+    /// can resume.
+    ///
+    /// Notifications which fail to be appended are still delivered, without
+    /// a sequence number.
+    pub fn log(mut self, log: NotificationLog) -> SubscriptionConfig {
+        self.log = Some(log);
+        self
+    }
+}
+
+/// Delivery state of a subscriber.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SubscriberStats {
+    /// The topic it subscribed to.
+    pub topic: Bytes,
+    /// Number of notifications waiting to be sent to it.
+    pub queued: usize,
+    /// Number of notifications dropped because its queue was full.
+    pub dropped: usize,
+}
+
+pub struct Subscriber {
+    filter: Option<Filter>,
+    /// Notifications waiting to be sent, with their sequence number if logged.
+    queue: Mutex<VecDeque<(Option<u64>, Bytes)>>,
+    /// Logged notifications being replayed, sent before the queued ones.
+    replay: Mutex<Option<Replay>>,
+    dropped: AtomicUsize,
+}
+
+/// Logged notifications to replay to a subscriber, read a chunk at a time.
+struct Replay {
+    /// Sequence number to read from next.
+    next: u64,
+    /// Sequence number of the last notification to replay.
+    last: u64,
+    /// Notifications read and not sent yet.
+    read: VecDeque<(u64, Bytes)>,
+}
+
+impl Subscriber {
+    pub fn new(filter: Option<Filter>) -> Subscriber {
+        Subscriber {
+            filter,
+            queue: Mutex::new(VecDeque::new()),
+            replay: Mutex::new(None),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the next notification to send, unless the next one to replay
+    /// has to be read first.
+    fn pop(&self) -> Option<(Option<u64>, Bytes)> {
+        let mut replay = self.replay.lock();
+        if let Some(ref mut replay) = *replay {
+            if let Some((seq, notification)) = replay.read.pop_front() {
+                return Some((Some(seq), notification));
+            }
+            if replay.next <= replay.last {
+                return None;
+            }
+        }
+        *replay = None;
+        self.queue.lock().pop_front()
+    }
+
+    /// Returns the sequence number to read the next notifications to replay
+    /// from, if all the read ones were sent.
+    fn next_read(&self) -> Option<u64> {
+        match *self.replay.lock() {
+            Some(ref replay) if replay.read.is_empty() && replay.next <= replay.last => {
+                Some(replay.next)
+            }
+            _ => None,
+        }
+    }
+
+    /// Adds the notifications of `topic` read from the log to those to replay.
+    ///
+    /// The replay ends once nothing is read, as the rest was dropped by the
+    /// retention or reading failed.
+    fn replayed(&self, topic: &Bytes, read: Vec<(u64, Bytes, Bytes)>) {
+        let mut replay = self.replay.lock();
+        let replay = match *replay {
+            Some(ref mut replay) => replay,
+            None => return,
+        };
+        if read.is_empty() {
+            replay.next = replay.last + 1;
+        }
+        for (seq, notification_topic, notification) in read {
+            replay.next = seq + 1;
+            if seq <= replay.last && notification_topic == *topic && self.matches(&notification) {
+                replay.read.push_back((seq, notification));
+            }
+        }
+    }
+
+    pub fn matches(&self, notification: &Bytes) -> bool {
+        match self.filter {
+            Some(ref filter) => filter.matches(notification),
+            None => true,
+        }
+    }
+
+    pub fn stats(&self, topic: Bytes) -> SubscriberStats {
+        let replaying = self.replay.lock().as_ref().map_or(0, |replay| replay.read.len());
+        SubscriberStats {
+            topic,
+            queued: self.queue.lock().len() + replaying,
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Outcome of `Topic::subscribe`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Subscribed {
+    /// The subscriber was added.
+    Added,
+    /// It was subscribed already.
+    Already,
+}
+
+/// Subscribers and shared state of a topic pattern.
+pub struct Topic {
+    /// Subscribers by caller and subscribed topic.
+    subscribers: RwLock<HashMap<(Caller, Bytes), Arc<Subscriber>>>,
+    /// Last notification by topic, in retained mode.
+    retained: Option<Mutex<HashMap<Bytes, Bytes>>>,
+    log: Option<NotificationLog>,
+    /// Sequence number of the last logged notification queued, updated under
+    /// the subscribers lock.
+    queued_seq: Mutex<u64>,
+    /// Waker of the `Fanout` of the topic.
+    waker: Mutex<Option<Waker>>,
+}
+
+impl Topic {
+    pub fn new(config: &SubscriptionConfig) -> Topic {
+        Topic {
+            subscribers: RwLock::new(HashMap::new()),
+            retained: if config.retained {
+                Some(Mutex::new(HashMap::new()))
+            } else {
+                None
+            },
+            log: config.log.clone(),
+            // Notifications logged beforehand are replayed like queued ones.
+            queued_seq: Mutex::new(
+                config
+                    .log
+                    .as_ref()
+                    .and_then(|log| log.range())
+                    .map_or(0, |(_, last)| last),
+            ),
+            waker: Mutex::new(None),
+        }
+    }
+
+    /// Adds `caller` as a subscriber of `topic`.
+    ///
+    /// With `resume`, the logged notifications from that sequence number on
+    /// are sent to it first, read from the log as they are sent. Otherwise
+    /// the retained notification of `topic` matching its filter is queued for
+    /// it.
+    pub fn subscribe(
+        &self,
+        caller: Caller,
+        topic: Bytes,
+        filter: Option<Filter>,
+        resume: Option<u64>,
+    ) -> Subscribed {
+        let mut subscribers = self.subscribers.write();
+        let key = (caller, topic);
+        if subscribers.contains_key(&key) {
+            return Subscribed::Already;
+        }
+        let subscriber = Subscriber::new(filter);
+        // Read under the lock, as `Fanout::queue` updates both: the later
+        // notifications are queued for the subscriber once it is added.
+        let queued_seq = *self.queued_seq.lock();
+        if let Some(seq) = resume {
+            if self.log.is_some() && seq <= queued_seq {
+                *subscriber.replay.lock() = Some(Replay {
+                    next: seq,
+                    last: queued_seq,
+                    read: VecDeque::new(),
+                });
+            }
+        } else if let Some(notification) = self.retained(&key.1) {
+            if subscriber.matches(&notification) {
+                subscriber.queue.lock().push_back((None, notification));
+            }
+        }
+        let pending = subscriber.replay.lock().is_some() || !subscriber.queue.lock().is_empty();
+        subscribers.insert(key, Arc::new(subscriber));
+        if pending {
+            self.wake();
+        }
+        Subscribed::Added
+    }
+
+    /// Removes `caller` from the subscribers of `topic`, returning whether it
+    /// was subscribed.
+    pub fn unsubscribe(&self, caller: Caller, topic: Bytes) -> bool {
+        self.subscribers.write().remove(&(caller, topic)).is_some()
+    }
+
+    /// Removes `caller` from the subscribers, returning the topics it was
+    /// subscribed to.
+    pub fn remove_caller(&self, caller: &Caller) -> Vec<Bytes> {
+        let mut topics = Vec::new();
+        self.subscribers.write().retain(|&(ref c, ref topic), _| {
+            if c == caller {
+                topics.push(topic.clone());
+            }
+            c != caller
+        });
+        topics
+    }
+
+    /// Removes all subscribers, returning them.
+    pub fn drain(&self) -> Vec<(Caller, Bytes)> {
+        self.subscribers
+            .write()
+            .drain()
+            .map(|(subscriber, _)| subscriber)
+            .collect()
+    }
+
+    pub fn stats(&self) -> Vec<SubscriberStats> {
+        self.subscribers
+            .read()
+            .iter()
+            .map(|(&(_, ref topic), subscriber)| subscriber.stats(topic.clone()))
+            .collect()
+    }
+
+    /// Returns the retained notification of `topic`, if in retained mode and
+    /// notified.
+    pub fn retained(&self, topic: &Bytes) -> Option<Bytes> {
+        self.retained.as_ref()?.lock().get(topic).cloned()
+    }
+
+    /// Replaces the retained notification of `topic`, returning `false` if
+    /// not in retained mode.
+    pub fn set_retained(&self, topic: Bytes, notification: Option<Bytes>) -> bool {
+        match self.retained {
+            Some(ref retained) => {
+                let mut retained = retained.lock();
+                match notification {
+                    Some(notification) => retained.insert(topic, notification),
+                    None => retained.remove(&topic),
+                };
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn wake(&self) {
+        if let Some(ref waker) = *self.waker.lock() {
+            waker.wake();
+        }
+    }
+}
+
+type Call = Box<Future<Item = (Caller, Bytes), Error = io::Error> + Send + Sync>;
+
+fn call(caller: &Caller, request: Request) -> Call {
+    let mut b = BytesMut::new();
+    request.write(&mut b);
+    caller.clone().call(b.freeze())
+}
+
+/// Calls `handler`, the unsubscribe handler of `pattern`, for a subscriber of
+/// `topic` removed by the `Disconnect` policy, once polled.
+///
+/// It is called with empty data, as when the connection of the subscriber
+/// ends, `unsubscribe_reason` returning `UnsubscribeReason::Slow`.
+fn unsubscribe(
+    pattern: &Bytes,
+    handler: &Arc<RequestHandler>,
+    topic: Bytes,
+) -> impl Future<Item = (), Error = io::Error> + Send + Sync {
+    let segments = matches(pattern, &topic).unwrap_or_default();
+    let handler = Arc::clone(handler);
+    ok::<_, io::Error>(()).and_then(move |_| {
+        let context = RequestContext::new(None, Headers::new())
+            .with_unsubscribe_reason(UnsubscribeReason::Slow);
+        context
+            .run(|| {
+                set_matched_segments(segments);
+                (handler)(Bytes::new())
+            })
+            .then(|_| Ok(()))
+    })
+}
+
+/// A notification taken from the channel, with the topic it is for.
+struct Pending {
+    topic: Bytes,
+    notification: Bytes,
+    /// Sequence number given by the log, while being appended.
+    append: Option<oneshot::Receiver<io::Result<u64>>>,
+    seq: Option<u64>,
+}
+
+/// Future delivering the notifications of a topic to its subscribers.
+///
+/// It completes once the sender of notifications is dropped and every queued
+/// notification has been sent.
+pub struct Fanout {
+    /// Notifications with the topic they are for.
+    notifications: mpsc::Receiver<(Bytes, Bytes)>,
+    topic: Arc<Topic>,
+    config: SubscriptionConfig,
+    /// Notifications being appended to the log or waiting for room in the
+    /// queues of their subscribers, in order.
+    pending: VecDeque<Pending>,
+    /// Notification being sent, by subscriber.
+    calls: HashMap<(Caller, Bytes), Call>,
+    /// Subscribers being told that their subscription ended.
+    removals: Vec<Call>,
+    /// Logged notifications being read to be replayed, by subscriber.
+    reads: HashMap<(Caller, Bytes), (Arc<Subscriber>, Read)>,
+    /// Pattern of the topic and its unsubscribe handler.
+    unsubscribe: Option<(Bytes, Arc<RequestHandler>)>,
+    closed: bool,
+}
+
+type Read = oneshot::Receiver<io::Result<Vec<(u64, Bytes, Bytes)>>>;
+
+impl Fanout {
+    pub fn new(
+        notifications: mpsc::Receiver<(Bytes, Bytes)>,
+        topic: Arc<Topic>,
+        config: SubscriptionConfig,
+    ) -> Fanout {
+        Fanout {
+            notifications,
+            topic,
+            config,
+            pending: VecDeque::new(),
+            calls: HashMap::new(),
+            removals: Vec::new(),
+            reads: HashMap::new(),
+            unsubscribe: None,
+            closed: false,
+        }
+    }
+
+    /// Calls `handler`, the unsubscribe handler of `pattern`, for the
+    /// subscribers removed by the `Disconnect` policy.
+    pub fn on_unsubscribe(mut self, pattern: Bytes, handler: Arc<RequestHandler>) -> Fanout {
+        self.unsubscribe = Some((pattern, handler));
+        self
+    }
+
+    /// Queues the pending notifications in order, as far as they are appended
+    /// and there is room, returning whether any was queued.
+    fn queue_pending(&mut self, cx: &mut task::Context) -> bool {
+        let mut progress = false;
+        while let Some(mut pending) = self.pending.pop_front() {
+            if let Some(mut append) = pending.append.take() {
+                let appended = append.poll(cx);
+                match appended {
+                    Ok(Async::Pending) => {
+                        pending.append = Some(append);
+                        self.pending.push_front(pending);
+                        break;
+                    }
+                    Ok(Async::Ready(seq)) => pending.seq = seq.ok(),
+                    Err(_) => (),
+                }
+            }
+            if !self.queue(&pending.topic, &pending.notification, pending.seq) {
+                self.pending.push_front(pending);
+                break;
+            }
+            progress = true;
+        }
+        progress
+    }
+
+    /// Queues `notification` for the subscribers of `topic`, returning `false`
+    /// if it has to wait for room.
+    fn queue(&mut self, topic: &Bytes, notification: &Bytes, seq: Option<u64>) -> bool {
+        let capacity = self.config.queue_capacity;
+        let mut subscribers = self.topic.subscribers.write();
+        let matching: Vec<_> = subscribers
+            .iter()
+            .filter(|&(key, subscriber)| key.1 == *topic && subscriber.matches(notification))
+            .map(|(key, subscriber)| (key.clone(), Arc::clone(subscriber)))
+            .collect();
+        if self.config.policy == SlowSubscriberPolicy::Block
+            && matching
+                .iter()
+                .any(|&(_, ref subscriber)| subscriber.queue.lock().len() >= capacity)
+        {
+            return false;
+        }
+        self.topic.set_retained(topic.clone(), Some(notification.clone()));
+        if let Some(seq) = seq {
+            *self.topic.queued_seq.lock() = seq;
+        }
+        for ((caller, topic), subscriber) in matching {
+            let mut queue = subscriber.queue.lock();
+            if queue.len() >= capacity {
+                match self.config.policy {
+                    SlowSubscriberPolicy::Block => unreachable!(),
+                    SlowSubscriberPolicy::DropOldest => {
+                        queue.pop_front();
+                        subscriber.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                    SlowSubscriberPolicy::DropNewest => {
+                        subscriber.dropped.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                    SlowSubscriberPolicy::Disconnect => {
+                        queue.clear();
+                        subscribers.remove(&(caller.clone(), topic.clone()));
+                        let request =
+                            Request::new(RequestType::TopicRemoved, topic.clone(), Bytes::new());
+                        let removal = call(&caller, request);
+                        let removal: Call = match self.unsubscribe {
+                            Some((ref pattern, ref handler)) => {
+                                let unsub = unsubscribe(pattern, handler, topic);
+                                Box::new(unsub.join(removal).map(|(_, removed)| removed))
+                            }
+                            None => removal,
+                        };
+                        self.removals.push(removal);
+                        continue;
+                    }
+                }
+            }
+            queue.push_back((seq, notification.clone()));
+        }
+        true
+    }
+
+    /// Sends the next queued notification to every idle subscriber and polls
+    /// the calls in flight, returning whether any of them completed.
+    fn poll_calls(&mut self, cx: &mut task::Context) -> bool {
+        let subscribers: Vec<_> = self.topic
+            .subscribers
+            .read()
+            .iter()
+            .map(|(key, subscriber)| (key.clone(), Arc::clone(subscriber)))
+            .collect();
+        for (key, subscriber) in subscribers {
+            if self.calls.contains_key(&key) {
+                continue;
+            }
+            let (seq, notification) = match subscriber.pop() {
+                Some(next) => next,
+                None => {
+                    let log = self.topic.log.as_ref();
+                    if let (Some(log), Some(seq)) = (log, subscriber.next_read()) {
+                        if !self.reads.contains_key(&key) {
+                            let read = log.read(seq, REPLAY_CHUNK);
+                            self.reads.insert(key, (subscriber, read));
+                        }
+                    }
+                    continue;
+                }
+            };
+            let mut request = Request::new(RequestType::Notification, key.1.clone(), notification);
+            if let Some(seq) = seq {
+                let mut headers = Headers::new();
+                headers.insert(Bytes::from_static(SEQUENCE_HEADER), encode_sequence(seq));
+                request = request.with_headers(headers);
+            }
+            let notify = call(&key.0, request);
+            self.calls.insert(key, notify);
+        }
+
+        let mut progress = false;
+        self.calls.retain(|_, notify| match notify.poll(cx) {
+            Ok(Async::Pending) => true,
+            _ => {
+                progress = true;
+                false
+            }
+        });
+        for mut removal in mem::replace(&mut self.removals, Vec::new()) {
+            if let Ok(Async::Pending) = removal.poll(cx) {
+                self.removals.push(removal);
+            }
+        }
+        self.reads.retain(|key, &mut (ref subscriber, ref mut read)| {
+            let read = match read.poll(cx) {
+                Ok(Async::Pending) => return true,
+                Ok(Async::Ready(read)) => read.unwrap_or_default(),
+                Err(_) => Vec::new(),
+            };
+            subscriber.replayed(&key.1, read);
+            progress = true;
+            false
+        });
+        progress
+    }
+
+    fn is_idle(&self) -> bool {
+        self.calls.is_empty()
+            && self.removals.is_empty()
+            && self.reads.is_empty()
+            && self.topic.subscribers.read().values().all(|subscriber| {
+                subscriber.queue.lock().is_empty() && subscriber.replay.lock().is_none()
+            })
+    }
+}
+
+impl Future for Fanout {
+    type Item = ();
+    type Error = Never;
+
+    fn poll(&mut self, cx: &mut task::Context) -> Poll<Self::Item, Self::Error> {
+        *self.topic.waker.lock() = Some(cx.waker().clone());
+        loop {
+            let mut progress = self.poll_calls(cx);
+            if self.queue_pending(cx) {
+                progress = true;
+            }
+            let limit = if self.topic.log.is_some() { MAX_APPENDS } else { 1 };
+            while self.pending.len() < limit && !self.closed {
+                match self.notifications.poll_next(cx) {
+                    Ok(Async::Ready(Some((topic, notification)))) => {
+                        let append = self.topic
+                            .log
+                            .as_ref()
+                            .map(|log| log.write(topic.clone(), notification.clone()));
+                        self.pending.push_back(Pending {
+                            topic,
+                            notification,
+                            append,
+                            seq: None,
+                        });
+                        progress = true;
+                    }
+                    Ok(Async::Ready(None)) => {
+                        self.closed = true;
+                        progress = true;
+                    }
+                    Ok(Async::Pending) => break,
+                    Err(e) => e.never_into(),
+                }
+            }
+            if !progress {
+                break;
+            }
+        }
+        if self.closed && self.pending.is_empty() && self.is_idle() {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::Pending)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::future::poll_fn;
+
+    /// Notifies three times a subscriber which never answers, with queues of one notification.
+    fn notify_slow_subscriber(policy: SlowSubscriberPolicy) -> Arc<Topic> {
+        let (handler_ch, _requests) = mpsc::channel(8);
+        let config = SubscriptionConfig::new().queue_capacity(1).policy(policy);
+        let topic = Arc::new(Topic::new(&config));
+        topic.subscribe(Caller::new(handler_ch), Bytes::from_static(b"topic"), None, None);
+        let (mut sink, stream) = mpsc::channel(8);
+        for notification in &[b"1", b"2", b"3"] {
+            let notification = Bytes::from_static(*notification);
+            sink.try_send((Bytes::from_static(b"topic"), notification)).unwrap();
+        }
+        let mut fanout = Fanout::new(stream, Arc::clone(&topic), config);
+        block_on(poll_fn(|cx| {
+            assert!(fanout.poll(cx).unwrap().is_pending());
+            Ok::<_, ()>(Async::Ready(()))
+        })).unwrap();
+        topic
+    }
+
+    #[test]
+    fn drop_notifications() {
+        for &policy in &[SlowSubscriberPolicy::DropOldest, SlowSubscriberPolicy::DropNewest] {
+            let topic = notify_slow_subscriber(policy);
+            let stats = topic.stats();
+            assert_eq!(stats[0].queued, 1);
+            assert_eq!(stats[0].dropped, 1);
+            let subscribers = topic.subscribers.read();
+            let (_, queued) = subscribers.values().next().unwrap().queue.lock()[0].clone();
+            match policy {
+                SlowSubscriberPolicy::DropOldest => assert_eq!(queued, Bytes::from_static(b"3")),
+                _ => assert_eq!(queued, Bytes::from_static(b"2")),
+            }
+        }
+    }
+
+    #[test]
+    fn block_notifications() {
+        let topic = notify_slow_subscriber(SlowSubscriberPolicy::Block);
+        let stats = topic.stats();
+        assert_eq!(stats[0].queued, 1);
+        assert_eq!(stats[0].dropped, 0);
+    }
+
+    #[test]
+    fn retain_queued_notifications() {
+        let (handler_ch, _requests) = mpsc::channel(8);
+        let config = SubscriptionConfig::new()
+            .queue_capacity(1)
+            .policy(SlowSubscriberPolicy::Block)
+            .retained(true);
+        let topic = Arc::new(Topic::new(&config));
+        let name = Bytes::from_static(b"topic");
+        topic.subscribe(Caller::new(handler_ch), name.clone(), None, None);
+        let (mut sink, stream) = mpsc::channel(8);
+        for notification in &[b"1", b"2", b"3"] {
+            sink.try_send((name.clone(), Bytes::from_static(*notification))).unwrap();
+        }
+        let mut fanout = Fanout::new(stream, Arc::clone(&topic), config);
+        block_on(poll_fn(|cx| {
+            assert!(fanout.poll(cx).unwrap().is_pending());
+            Ok::<_, ()>(Async::Ready(()))
+        })).unwrap();
+
+        // "1" is being sent, "2" is queued and "3" waits for room, so it isn't retained yet.
+        let (other_ch, _other_requests) = mpsc::channel(8);
+        let other = Caller::new(other_ch);
+        let subscribed = topic.subscribe(other.clone(), name.clone(), None, None);
+        assert_eq!(subscribed, Subscribed::Added);
+        assert_eq!(topic.retained(&name), Some(Bytes::from_static(b"2")));
+        let subscribers = topic.subscribers.read();
+        let queue = subscribers[&(other, name)].queue.lock();
+        assert_eq!(queue.front(), Some(&(None, Bytes::from_static(b"2"))));
+    }
+
+    #[test]
+    fn replay_written_notifications() {
+        use super::super::log::Retention;
+        use util::TempPath;
+
+        let path = TempPath::new("fanout.log");
+        let log = NotificationLog::open(&path, Retention::new()).unwrap();
+        let config = SubscriptionConfig::new().log(log);
+        let topic = Arc::new(Topic::new(&config));
+        let name = Bytes::from_static(b"topic");
+        let (mut sink, stream) = mpsc::channel(8);
+        for notification in &[b"1", b"2", b"3"] {
+            sink.try_send((name.clone(), Bytes::from_static(*notification))).unwrap();
+        }
+        let mut fanout = Fanout::new(stream, Arc::clone(&topic), config);
+        block_on(poll_fn(|cx| {
+            assert!(fanout.poll(cx).unwrap().is_pending());
+            if fanout.pending.is_empty() {
+                Ok::<_, ()>(Async::Ready(()))
+            } else {
+                Ok(Async::Pending)
+            }
+        })).unwrap();
+
+        // Replayed from the log before the notifications queued after subscribing.
+        let (handler_ch, mut requests) = mpsc::channel(8);
+        let subscribed = topic.subscribe(Caller::new(handler_ch), name.clone(), None, Some(2));
+        assert_eq!(subscribed, Subscribed::Added);
+        sink.try_send((name.clone(), Bytes::from_static(b"4"))).unwrap();
+        let mut notified = Vec::new();
+        block_on(poll_fn(|cx| {
+            assert!(fanout.poll(cx).unwrap().is_pending());
+            while let Async::Ready(Some((_, response, request))) = requests.poll_next(cx).unwrap() {
+                let request = Request::from_bytes(request).unwrap();
+                let seq = request.headers.get(SEQUENCE_HEADER).cloned();
+                notified.push((seq, request.message));
+                let _ = response.send(Bytes::new());
+            }
+            if notified.len() < 3 {
+                return Ok(Async::Pending);
+            }
+            Ok::<_, ()>(Async::Ready(()))
+        })).unwrap();
+        let expected: Vec<_> = [(2, b"2"), (3, b"3"), (4, b"4")]
+            .iter()
+            .map(|&(seq, notification)| {
+                (Some(encode_sequence(seq)), Bytes::from_static(notification))
+            })
+            .collect();
+        assert_eq!(notified, expected);
+    }
+
+    #[test]
+    fn disconnect_subscriber() {
+        let topic = notify_slow_subscriber(SlowSubscriberPolicy::Disconnect);
+        assert!(topic.stats().is_empty());
+    }
+
+    #[test]
+    fn unsubscribe_disconnected_subscriber() {
+        use super::super::context::{matched_segments, unsubscribe_reason};
+
+        let (handler_ch, _requests) = mpsc::channel(8);
+        let config = SubscriptionConfig::new()
+            .queue_capacity(1)
+            .policy(SlowSubscriberPolicy::Disconnect);
+        let topic = Arc::new(Topic::new(&config));
+        let name = Bytes::from_static(b"sensor/1");
+        topic.subscribe(Caller::new(handler_ch), name.clone(), None, None);
+        let (mut sink, stream) = mpsc::channel(8);
+        for notification in &[b"1", b"2", b"3"] {
+            sink.try_send((name.clone(), Bytes::from_static(*notification))).unwrap();
+        }
+        let called = Arc::new(Mutex::new(None));
+        let unsub_handler: RequestHandler = {
+            let called = Arc::clone(&called);
+            Box::new(move |req| {
+                *called.lock() = Some((unsubscribe_reason(), matched_segments()));
+                Box::new(ok(req))
+            })
+        };
+        let mut fanout = Fanout::new(stream, Arc::clone(&topic), config)
+            .on_unsubscribe(Bytes::from_static(b"sensor/+"), Arc::new(unsub_handler));
+        block_on(poll_fn(|cx| {
+            assert!(fanout.poll(cx).unwrap().is_pending());
+            Ok::<_, ()>(Async::Ready(()))
+        })).unwrap();
+        let expected = (Some(UnsubscribeReason::Slow), vec![Bytes::from_static(b"1")]);
+        assert_eq!(*called.lock(), Some(expected));
+    }
+}
@@ -1,4 +1,6 @@
+use super::fanout::{Fanout, SubscriberStats, Subscribed, SubscriptionConfig, Topic};
 use super::message::{Request, RequestType};
+use super::topic::{Match, TopicMap};
 use bytes::{Bytes, BytesMut};
 use dialog::Caller;
 use futures::channel::mpsc;
@@ -6,77 +8,264 @@ use futures::prelude::*;
 use futures::stream;
 use parking_lot::RwLock;
 use std::collections::HashMap;
-use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
 use std::io;
 use std::sync::Arc;
 
-type RequestHandler =
+pub type RequestHandler =
     Box<Fn(Bytes) -> Box<Future<Item = Bytes, Error = io::Error> + Send + Sync> + Send + Sync>;
 
+type SubscribeHandler = Box<
+    Fn(Bytes) -> Box<Future<Item = SubscribeDecision, Error = io::Error> + Send + Sync>
+        + Send
+        + Sync,
+>;
+
+/// Answer of a subscribe handler to a subscriber.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SubscribeDecision {
+    /// Adds the subscriber to the topic, answering with the data.
+    Accept(Bytes),
+    /// Adds the subscriber to the topic, answering with the data. Only the
+    /// notifications matching the filter are sent to it.
+    AcceptFiltered(Bytes, Filter),
+    /// Refuses the subscriber, answering with the data.
+    Reject(Bytes),
+}
+
+/// Predicate selecting the notifications sent to a subscriber.
+#[derive(Clone)]
+pub struct Filter(Arc<Fn(&Bytes) -> bool + Send + Sync>);
+
+impl Filter {
+    pub fn new<F>(f: F) -> Filter
+    where
+        F: Fn(&Bytes) -> bool + Send + Sync + 'static,
+    {
+        Filter(Arc::new(f))
+    }
+
+    /// Returns a filter matching the notifications which start with any of `prefixes`, such as
+    /// a set of keys.
+    pub fn prefixes(prefixes: Vec<Bytes>) -> Filter {
+        Filter::new(move |notification| prefixes.iter().any(|p| notification.starts_with(p)))
+    }
+
+    pub fn matches(&self, notification: &Bytes) -> bool {
+        (self.0)(notification)
+    }
+}
+
+impl fmt::Debug for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Filter")
+    }
+}
+
+impl PartialEq for Filter {
+    fn eq(&self, other: &Filter) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// Error of a handler given a request it can't decode.
+///
+/// A handler failing with an `io::Error` made by `InvalidRequest::error` is
+/// answered with an invalid data response holding the description of `e`,
+/// other errors end the connection.
+#[derive(Debug)]
+pub struct InvalidRequest(Box<Error + Send + Sync>);
+
+impl InvalidRequest {
+    pub fn error<E: Into<Box<Error + Send + Sync>>>(e: E) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, InvalidRequest(e.into()))
+    }
+
+    /// Returns whether `e` was made by `InvalidRequest::error`.
+    pub fn is(e: &io::Error) -> bool {
+        e.get_ref().map_or(false, |e| e.is::<InvalidRequest>())
+    }
+}
+
+impl fmt::Display for InvalidRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl Error for InvalidRequest {
+    fn description(&self) -> &str {
+        self.0.description()
+    }
+}
+
+/// Handlers of the RPC and subscription topics of a `Sim`.
+///
+/// Topics are registered as patterns, see `TopicMap` for how they are matched.
+/// The levels matched by wildcards are given by `matched_segments` while a
+/// handler runs.
+///
+/// Topics can be added and removed at any time, also while connections are
+/// live through `Sim::handler`.
 pub struct Handler {
-    call_handler: HashMap<Bytes, RequestHandler>,
-    sub_handler: HashMap<Bytes, (RequestHandler, RequestHandler)>,
-    notify_map: HashMap<Bytes, Arc<RwLock<HashSet<Caller>>>>,
+    call_handler: RwLock<TopicMap<Arc<RequestHandler>>>,
+    sub_handler: RwLock<TopicMap<(Arc<SubscribeHandler>, Arc<RequestHandler>)>>,
+    notify_map: RwLock<HashMap<Bytes, Arc<Topic>>>,
 }
 
 impl Handler {
     pub fn new() -> Handler {
         Handler {
-            call_handler: HashMap::new(),
-            sub_handler: HashMap::new(),
-            notify_map: HashMap::new(),
+            call_handler: RwLock::new(TopicMap::new()),
+            sub_handler: RwLock::new(TopicMap::new()),
+            notify_map: RwLock::new(HashMap::new()),
         }
     }
 
-    pub fn on_rpc(&mut self, topic: Bytes, handler: RequestHandler) {
-        self.call_handler.insert(topic, handler);
+    pub fn on_rpc(&self, topic: Bytes, handler: RequestHandler) {
+        self.call_handler.write().insert(topic, Arc::new(handler));
+    }
+
+    /// Removes the RPC handler of `topic`, returning whether there was one.
+    pub fn remove_rpc(&self, topic: &Bytes) -> bool {
+        self.call_handler.write().remove(topic).is_some()
     }
 
+    /// Handles subscriptions to the topics matching `topic`.
+    ///
+    /// Notifications are sent to the returned sink with the topic they are
+    /// for, such as `alarm/door` for the pattern `alarm/+`, and reach the
+    /// subscribers of that topic. The returned future delivers them with the
+    /// default `SubscriptionConfig`.
     pub fn on_subs(
-        &mut self,
+        &self,
+        topic: Bytes,
+        sub_handler: SubscribeHandler,
+        unsub_handler: RequestHandler,
+    ) -> (
+        impl Sink<SinkItem = (Bytes, Bytes), SinkError = mpsc::SendError> + Send + Sync,
+        impl Future<Item = (), Error = Never> + Send + Sync,
+    ) {
+        self.on_subs_with_config(topic, sub_handler, unsub_handler, SubscriptionConfig::default())
+    }
+
+    /// Handles subscriptions to the topics matching `topic`, delivering
+    /// notifications as set by `config`.
+    pub fn on_subs_with_config(
+        &self,
         topic: Bytes,
-        sub_handler: RequestHandler,
+        sub_handler: SubscribeHandler,
         unsub_handler: RequestHandler,
+        config: SubscriptionConfig,
     ) -> (
-        impl Sink<SinkItem = Bytes, SinkError = mpsc::SendError> + Send + Sync,
+        impl Sink<SinkItem = (Bytes, Bytes), SinkError = mpsc::SendError> + Send + Sync,
         impl Future<Item = (), Error = Never> + Send + Sync,
     ) {
-        self.sub_handler
-            .insert(topic.clone(), (sub_handler, unsub_handler));
+        let unsub_handler = Arc::new(unsub_handler);
+        self.sub_handler.write().insert(
+            topic.clone(),
+            (Arc::new(sub_handler), Arc::clone(&unsub_handler)),
+        );
         let (sink, stream) = mpsc::channel(1);
-        let callers = Arc::default();
-        self.notify_map.insert(topic.clone(), Arc::clone(&callers));
-        let fut = stream
-            .for_each(move |notification: Bytes| {
-                let mut notify_request = BytesMut::new();
-                Request::new(RequestType::Notification, topic.clone(), notification)
-                    .write(&mut notify_request);
-                let notify_request = notify_request.freeze();
-                let callers = callers.read().clone();
-                stream::iter_ok(callers.into_iter())
-                    .for_each_concurrent(move |x| {
-                        x.call(notify_request.clone()).map(|_| ()).recover(|_| ())
-                    })
-                    .map(|_| ())
+        let subscribers = Arc::new(Topic::new(&config));
+        self.notify_map.write().insert(topic.clone(), Arc::clone(&subscribers));
+        let fanout = Fanout::new(stream, subscribers, config).on_unsubscribe(topic, unsub_handler);
+        (sink, fanout)
+    }
+
+    /// Removes the subscription handlers of `topic`.
+    ///
+    /// Every current subscriber is told that its topic is removed, which ends
+    /// its subscription. The returned future completes once all of them have
+    /// been told.
+    pub fn remove_subs(&self, topic: &Bytes) -> impl Future<Item = (), Error = Never> + Send {
+        self.sub_handler.write().remove(topic);
+        let callers = match self.notify_map.write().remove(topic) {
+            Some(subscribers) => subscribers.drain(),
+            None => Vec::new(),
+        };
+        stream::iter_ok(callers)
+            .for_each_concurrent(|(caller, topic)| {
+                let mut request = BytesMut::new();
+                Request::new(RequestType::TopicRemoved, topic, Bytes::new()).write(&mut request);
+                caller.call(request.freeze()).map(|_| ()).recover(|_| ())
             })
-            .map(|_| ());
-        (sink, fut)
+            .map(|_| ())
     }
 
-    pub fn add_notify_caller(&self, topic: &Bytes, caller: Caller) -> Option<bool> {
-        let val = self.notify_map.get(topic)?.write().insert(caller);
-        Some(val)
+    /// Adds `caller` as a subscriber of `topic`, which matches `pattern`. Only notifications
+    /// matching `filter`, if any, are sent to it.
+    ///
+    /// With `resume`, the notifications logged from that sequence number on are sent to it
+    /// first.
+    pub fn add_notify_caller(
+        &self,
+        pattern: &Bytes,
+        caller: Caller,
+        topic: Bytes,
+        filter: Option<Filter>,
+        resume: Option<u64>,
+    ) -> Option<Subscribed> {
+        let subscribers = Arc::clone(self.notify_map.read().get(pattern)?);
+        Some(subscribers.subscribe(caller, topic, filter, resume))
+    }
+
+    /// Removes `caller` from the subscribers of `topic`, which matches `pattern`, returning
+    /// whether it was subscribed.
+    pub fn remove_notify_caller(
+        &self,
+        pattern: &Bytes,
+        caller: Caller,
+        topic: Bytes,
+    ) -> Option<bool> {
+        Some(self.notify_map.read().get(pattern)?.unsubscribe(caller, topic))
+    }
+
+    /// Removes `caller` from the subscribers of all topics, returning the topics it was
+    /// subscribed to.
+    pub fn remove_caller(&self, caller: &Caller) -> Vec<Bytes> {
+        self.notify_map
+            .read()
+            .values()
+            .flat_map(|subscribers| subscribers.remove_caller(caller))
+            .collect()
+    }
+
+    /// Returns the retained notification of `topic`, which matches `pattern`,
+    /// if it is in retained mode and was notified.
+    pub fn retained(&self, pattern: &Bytes, topic: &Bytes) -> Option<Bytes> {
+        self.notify_map.read().get(pattern)?.retained(topic)
+    }
+
+    /// Replaces the retained notification of `topic`, which matches `pattern`,
+    /// or clears it with `None`, without notifying the subscribers.
+    ///
+    /// Returns `false` if the topics matching `pattern` aren't in retained mode.
+    pub fn set_retained(&self, pattern: &Bytes, topic: Bytes, notification: Option<Bytes>) -> bool {
+        match self.notify_map.read().get(pattern) {
+            Some(subscribers) => subscribers.set_retained(topic, notification),
+            None => false,
+        }
+    }
+
+    /// Returns the delivery state of the subscribers of the topics matching `pattern`.
+    pub fn subscriber_stats(&self, pattern: &Bytes) -> Vec<SubscriberStats> {
+        match self.notify_map.read().get(pattern) {
+            Some(subscribers) => subscribers.stats(),
+            None => Vec::new(),
+        }
     }
 
-    pub fn get_rpc(&self, topic: &Bytes) -> Option<&RequestHandler> {
-        self.call_handler.get(topic)
+    pub fn get_rpc(&self, topic: &Bytes) -> Option<Match<Arc<RequestHandler>>> {
+        Some(self.call_handler.read().get(topic)?.map(Arc::clone))
     }
 
-    pub fn get_subs(&self, topic: &Bytes) -> Option<&RequestHandler> {
-        Some(&self.sub_handler.get(topic)?.0)
+    pub fn get_subs(&self, topic: &Bytes) -> Option<Match<Arc<SubscribeHandler>>> {
+        Some(self.sub_handler.read().get(topic)?.map(|h| Arc::clone(&h.0)))
     }
 
-    pub fn get_unsubs(&self, topic: &Bytes) -> Option<&RequestHandler> {
-        Some(&self.sub_handler.get(topic)?.1)
+    pub fn get_unsubs(&self, topic: &Bytes) -> Option<Match<Arc<RequestHandler>>> {
+        Some(self.sub_handler.read().get(topic)?.map(|h| Arc::clone(&h.1)))
     }
 }
@@ -0,0 +1,608 @@
+//! Append-only log of the notifications of a topic on local disk.
+//!
+//! The file starts with the sequence number given to the next notification
+//! when the log is empty, followed by a record per notification, all in
+//! little endian:
+//!
+//! `seq`|`time`|`topic_len`|`len`|`topic`    |`data`
+//! :---:|:----:|:---------:|:---:|:---------:|:----:
+//!  8   |  8   |  2        |  4  |`topic_len`| `len`
+//!
+//! `time` is the number of milliseconds since the Unix epoch at which the
+//! notification was appended, `topic` the topic it was sent to. Records
+//! dropped by the retention are removed by rewriting the file once they take
+//! more room than the kept ones.
+//!
+//! Notifications given to `NotificationLog::write` are appended by a thread
+//! of the log, which syncs the file once for all the notifications waiting.
+//! `NotificationLog::read` reads a chunk of notifications on that thread too.
+
+use bytes::{BufMut, ByteOrder, Bytes, LittleEndian};
+use futures::channel::oneshot;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{cmp, fmt, iter, mem};
+
+use parking_lot::Mutex;
+
+/// Notification header holding the sequence number of a logged notification.
+pub const SEQUENCE_HEADER: &[u8] = b"$seq";
+/// Subscription header holding the sequence number to replay notifications from.
+pub const RESUME_HEADER: &[u8] = b"$resume";
+
+const FILE_HEADER_LEN: u64 = 8;
+const RECORD_HEADER_LEN: u64 = 22;
+
+pub fn encode_sequence(seq: u64) -> Bytes {
+    let mut b = Vec::with_capacity(8);
+    b.put_u64_le(seq);
+    Bytes::from(b)
+}
+
+pub fn decode_sequence(b: &Bytes) -> Option<u64> {
+    if b.len() == 8 {
+        Some(LittleEndian::read_u64(b))
+    } else {
+        None
+    }
+}
+
+/// Which notifications a log keeps. By default all of them are kept.
+#[derive(Clone, Debug, Default)]
+pub struct Retention {
+    max_count: Option<usize>,
+    max_bytes: Option<u64>,
+    max_age: Option<Duration>,
+}
+
+impl Retention {
+    pub fn new() -> Retention {
+        Retention::default()
+    }
+
+    /// Keeps at most the last `count` notifications.
+    pub fn max_count(mut self, count: usize) -> Retention {
+        self.max_count = Some(count);
+        self
+    }
+
+    /// Keeps the last notifications whose records take at most `bytes`.
+    pub fn max_bytes(mut self, bytes: u64) -> Retention {
+        self.max_bytes = Some(bytes);
+        self
+    }
+
+    /// Keeps the notifications appended at most `age` ago.
+    pub fn max_age(mut self, age: Duration) -> Retention {
+        self.max_age = Some(age);
+        self
+    }
+}
+
+/// Log of the notifications of a topic, see `SubscriptionConfig::log`.
+///
+/// Cloning a `NotificationLog` gives another handle to the same log.
+#[derive(Clone)]
+pub struct NotificationLog {
+    inner: Arc<Mutex<Inner>>,
+    /// Sender to the thread of the log, started on first use.
+    jobs: Arc<Mutex<Option<mpsc::Sender<Job>>>>,
+}
+
+type Notifications = Vec<(u64, Bytes, Bytes)>;
+
+/// Work for the thread of the log.
+enum Job {
+    /// Appends a notification to a topic, sending back its sequence number.
+    Append(Bytes, Bytes, oneshot::Sender<io::Result<u64>>),
+    /// Reads at most a number of notifications from a sequence number on.
+    Read(u64, usize, oneshot::Sender<io::Result<Notifications>>),
+}
+
+struct Inner {
+    path: PathBuf,
+    file: File,
+    retention: Retention,
+    entries: VecDeque<Entry>,
+    /// Size of the records of `entries`.
+    live_bytes: u64,
+    /// Size of the file.
+    file_len: u64,
+    next_seq: u64,
+    /// Set when a failed append could not be cut from the file.
+    failed: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    seq: u64,
+    time: u64,
+    offset: u64,
+    len: u64,
+}
+
+impl NotificationLog {
+    /// Opens the log at `path`, creating it if needed.
+    ///
+    /// Notifications already in the log are kept as allowed by `retention`,
+    /// and sequence numbers go on from the last one.
+    pub fn open<P: AsRef<Path>>(path: P, retention: Retention) -> io::Result<NotificationLog> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        let mut inner = if data.len() < FILE_HEADER_LEN as usize {
+            file.set_len(0)?;
+            file.write_all(&encode_sequence(1))?;
+            Inner {
+                path,
+                file,
+                retention,
+                entries: VecDeque::new(),
+                live_bytes: 0,
+                file_len: FILE_HEADER_LEN,
+                next_seq: 1,
+                failed: false,
+            }
+        } else {
+            let mut next_seq = LittleEndian::read_u64(&data);
+            let mut entries = VecDeque::new();
+            let mut live_bytes = 0;
+            let mut offset = FILE_HEADER_LEN;
+            while let Some(entry) = read_entry(&data, offset) {
+                next_seq = cmp::max(next_seq, entry.seq + 1);
+                offset += entry.len;
+                live_bytes += entry.len;
+                entries.push_back(entry);
+            }
+            // Cut a record left incomplete by a crash while appending.
+            if offset < data.len() as u64 {
+                file.set_len(offset)?;
+            }
+            Inner {
+                path,
+                file,
+                retention,
+                entries,
+                live_bytes,
+                file_len: offset,
+                next_seq,
+                failed: false,
+            }
+        };
+        inner.retain(now())?;
+        Ok(NotificationLog {
+            inner: Arc::new(Mutex::new(inner)),
+            jobs: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    /// Appends `notification` to `topic`, returning its sequence number.
+    ///
+    /// Fails with `InvalidInput` if the topic or notification is too long to
+    /// be recorded. A record which fails to be written is cut from the file. If that fails
+    /// too, every later append fails until the log is opened again.
+    pub fn append(&self, topic: &Bytes, notification: &Bytes) -> io::Result<u64> {
+        self.inner.lock().append(&[(topic, notification)])
+    }
+
+    /// Appends `notification` to `topic` on the thread of the log, returning
+    /// a future of its sequence number.
+    pub fn write(&self, topic: Bytes, notification: Bytes) -> oneshot::Receiver<io::Result<u64>> {
+        let (tx, rx) = oneshot::channel();
+        // Rejected here so that the appends written with it don't fail.
+        match check_len(&topic, &notification) {
+            Ok(()) => self.send(Job::Append(topic, notification, tx)),
+            Err(e) => {
+                let _ = tx.send(Err(e));
+            }
+        }
+        rx
+    }
+
+    /// Returns the kept notifications from sequence number `seq` on, in order,
+    /// with their topic.
+    pub fn read_from(&self, seq: u64) -> io::Result<Notifications> {
+        self.inner.lock().read(seq, usize::MAX)
+    }
+
+    /// Reads at most `count` kept notifications from sequence number `seq` on
+    /// on the thread of the log, returning a future of them.
+    ///
+    /// Notifications given to `write` before are appended first.
+    pub fn read(&self, seq: u64, count: usize) -> oneshot::Receiver<io::Result<Notifications>> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Job::Read(seq, count, tx));
+        rx
+    }
+
+    fn send(&self, job: Job) {
+        let mut jobs = self.jobs.lock();
+        if jobs.is_none() {
+            *jobs = Some(spawn_thread(Arc::clone(&self.inner)));
+        }
+        if let Some(ref jobs) = *jobs {
+            // Should the thread be gone, the sender of `job` is dropped, canceling its receiver.
+            let _ = jobs.send(job);
+        }
+    }
+
+    /// Returns the sequence numbers of the first and last kept notifications.
+    pub fn range(&self) -> Option<(u64, u64)> {
+        let inner = self.inner.lock();
+        Some((inner.entries.front()?.seq, inner.entries.back()?.seq))
+    }
+}
+
+impl fmt::Debug for NotificationLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.lock();
+        f.debug_struct("NotificationLog")
+            .field("path", &inner.path)
+            .field("retention", &inner.retention)
+            .field("notifications", &inner.entries.len())
+            .field("next_seq", &inner.next_seq)
+            .finish()
+    }
+}
+
+impl Inner {
+    /// Reads at most `count` kept notifications from sequence number `seq` on.
+    fn read(&mut self, seq: u64, count: usize) -> io::Result<Notifications> {
+        self.retain(now())?;
+        let (first, end) = {
+            let mut entries = self
+                .entries
+                .iter()
+                .skip_while(|entry| entry.seq < seq)
+                .take(count);
+            let first = match entries.next() {
+                Some(entry) => *entry,
+                None => return Ok(Vec::new()),
+            };
+            let last = entries.last().cloned().unwrap_or(first);
+            (first.offset, last.offset + last.len)
+        };
+        let mut data = vec![0; (end - first) as usize];
+        self.file.seek(SeekFrom::Start(first))?;
+        self.file.read_exact(&mut data)?;
+        let data = Bytes::from(data);
+        let mut notifications = Vec::new();
+        let mut offset = 0;
+        while let Some(entry) = read_entry(&data, offset) {
+            let header = &data[offset as usize..(offset + RECORD_HEADER_LEN) as usize];
+            let start = (offset + RECORD_HEADER_LEN) as usize;
+            let topic_end = start + LittleEndian::read_u16(&header[16..]) as usize;
+            let end = (offset + entry.len) as usize;
+            let topic = data.slice(start, topic_end);
+            notifications.push((entry.seq, topic, data.slice(topic_end, end)));
+            offset += entry.len;
+        }
+        Ok(notifications)
+    }
+
+    /// Appends `notifications`, given with their topic, returning the sequence
+    /// number of the first one.
+    fn append(&mut self, notifications: &[(&Bytes, &Bytes)]) -> io::Result<u64> {
+        if self.failed {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "notification log failed to cut an incomplete record",
+            ));
+        }
+        for &(topic, notification) in notifications {
+            check_len(topic, notification)?;
+        }
+        let time = now();
+        let first = self.next_seq;
+        let mut records = Vec::new();
+        let mut entries = Vec::with_capacity(notifications.len());
+        for &(topic, notification) in notifications {
+            let offset = records.len() as u64;
+            records.put_u64_le(first + entries.len() as u64);
+            records.put_u64_le(time);
+            records.put_u16_le(topic.len() as u16);
+            records.put_u32_le(notification.len() as u32);
+            records.extend_from_slice(topic);
+            records.extend_from_slice(notification);
+            entries.push(Entry {
+                seq: first + entries.len() as u64,
+                time,
+                offset: self.file_len + offset,
+                len: records.len() as u64 - offset,
+            });
+        }
+        self.write_records(&records)?;
+        for entry in entries {
+            self.next_seq += 1;
+            self.file_len += entry.len;
+            self.live_bytes += entry.len;
+            self.entries.push_back(entry);
+        }
+        self.retain(time)?;
+        Ok(first)
+    }
+
+    /// Writes `records` at the end of the file, cutting what was written of them on failure.
+    fn write_records(&mut self, records: &[u8]) -> io::Result<()> {
+        let result = self
+            .file
+            .write_all(records)
+            .and_then(|_| self.file.sync_data());
+        if result.is_err() && self.file.set_len(self.file_len).is_err() {
+            self.failed = true;
+        }
+        result
+    }
+
+    /// Drops the notifications not kept by the retention at `time`.
+    fn retain(&mut self, time: u64) -> io::Result<()> {
+        while let Some(entry) = self.entries.front().cloned() {
+            let too_many = self
+                .retention
+                .max_count
+                .map_or(false, |count| self.entries.len() > count);
+            let too_big = self
+                .retention
+                .max_bytes
+                .map_or(false, |bytes| self.live_bytes > bytes);
+            let too_old = self.retention.max_age.map_or(false, |age| {
+                let age = age.as_secs() * 1000 + u64::from(age.subsec_nanos() / 1_000_000);
+                time.saturating_sub(entry.time) > age
+            });
+            if !(too_many || too_big || too_old) {
+                break;
+            }
+            self.entries.pop_front();
+            self.live_bytes -= entry.len;
+        }
+        if self.file_len - FILE_HEADER_LEN > 2 * self.live_bytes {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites the file with the kept notifications only.
+    fn compact(&mut self) -> io::Result<()> {
+        let first = self
+            .entries
+            .front()
+            .map_or(self.file_len, |entry| entry.offset);
+        let mut data = vec![0; (self.file_len - first) as usize];
+        self.file.seek(SeekFrom::Start(first))?;
+        self.file.read_exact(&mut data)?;
+
+        let base = self.entries.front().map_or(self.next_seq, |entry| entry.seq);
+        // Named after the whole file name, so that logs differing only in
+        // their extension don't share it.
+        let mut tmp_name = self.path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".compact");
+        let tmp_path = self.path.with_file_name(tmp_name);
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            tmp.write_all(&encode_sequence(base))?;
+            tmp.write_all(&data)?;
+            tmp.sync_data()?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+        self.file = OpenOptions::new().read(true).append(true).open(&self.path)?;
+        for entry in &mut self.entries {
+            entry.offset = entry.offset - first + FILE_HEADER_LEN;
+        }
+        self.file_len = FILE_HEADER_LEN + data.len() as u64;
+        Ok(())
+    }
+}
+
+/// Starts the thread doing the jobs sent to it on `inner`.
+///
+/// It stops once the sender, owned by the handles to the log, is dropped.
+fn spawn_thread(inner: Arc<Mutex<Inner>>) -> mpsc::Sender<Job> {
+    let (tx, rx) = mpsc::channel::<Job>();
+    thread::spawn(move || {
+        while let Ok(job) = rx.recv() {
+            // Appends waiting together are written at once.
+            let mut appends = Vec::new();
+            for job in iter::once(job).chain(rx.try_iter()) {
+                match job {
+                    Job::Append(topic, notification, seq) => {
+                        appends.push((topic, notification, seq));
+                    }
+                    Job::Read(seq, count, notifications) => {
+                        append_all(&inner, mem::replace(&mut appends, Vec::new()));
+                        let _ = notifications.send(inner.lock().read(seq, count));
+                    }
+                }
+            }
+            append_all(&inner, appends);
+        }
+    });
+    tx
+}
+
+/// Appends `appends` to `inner`, sending each its sequence number.
+fn append_all(
+    inner: &Mutex<Inner>,
+    appends: Vec<(Bytes, Bytes, oneshot::Sender<io::Result<u64>>)>,
+) {
+    if appends.is_empty() {
+        return;
+    }
+    let result = {
+        let notifications: Vec<_> = appends
+            .iter()
+            .map(|&(ref topic, ref notification, _)| (topic, notification))
+            .collect();
+        inner.lock().append(&notifications)
+    };
+    for (i, (_, _, seq)) in appends.into_iter().enumerate() {
+        let _ = seq.send(match result {
+            Ok(first) => Ok(first + i as u64),
+            Err(ref e) => Err(io::Error::new(e.kind(), e.to_string())),
+        });
+    }
+}
+
+/// Fails with `InvalidInput` if the lengths of `topic` or `notification`
+/// don't fit in a record header.
+fn check_len(topic: &Bytes, notification: &Bytes) -> io::Result<()> {
+    if topic.len() > usize::from(u16::max_value()) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "topic too long to log"));
+    }
+    if notification.len() as u64 > u64::from(u32::max_value()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "notification too long to log",
+        ));
+    }
+    Ok(())
+}
+
+/// Reads the header of the record at `offset` in `data`, if it is complete.
+fn read_entry(data: &[u8], offset: u64) -> Option<Entry> {
+    let header_end = offset.checked_add(RECORD_HEADER_LEN)?;
+    if header_end > data.len() as u64 {
+        return None;
+    }
+    let header = &data[offset as usize..header_end as usize];
+    let topic_len = u64::from(LittleEndian::read_u16(&header[16..]));
+    let len = RECORD_HEADER_LEN + topic_len + u64::from(LittleEndian::read_u32(&header[18..]));
+    if offset + len > data.len() as u64 {
+        return None;
+    }
+    Some(Entry {
+        seq: LittleEndian::read_u64(header),
+        time: LittleEndian::read_u64(&header[8..]),
+        offset,
+        len,
+    })
+}
+
+/// Returns the number of milliseconds since the Unix epoch.
+fn now() -> u64 {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_nanos() / 1_000_000)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::executor::block_on;
+    use std::thread;
+    use util::TempPath;
+
+    fn log_path(name: &str) -> TempPath {
+        TempPath::new(&format!("{}.log", name))
+    }
+
+    fn notification(s: &str) -> Bytes {
+        Bytes::from(s.as_bytes())
+    }
+
+    fn topic() -> Bytes {
+        Bytes::from_static(b"t")
+    }
+
+    #[test]
+    fn append_and_replay() {
+        let path = log_path("replay");
+        let log = NotificationLog::open(&path, Retention::new()).unwrap();
+        assert_eq!(log.append(&topic(), &notification("a")).unwrap(), 1);
+        assert_eq!(log.append(&topic(), &notification("b")).unwrap(), 2);
+        assert_eq!(log.append(&topic(), &notification("c")).unwrap(), 3);
+        assert_eq!(
+            log.read_from(2).unwrap(),
+            vec![(2, topic(), notification("b")), (3, topic(), notification("c"))]
+        );
+        assert!(log.read_from(4).unwrap().is_empty());
+        drop(log);
+
+        let log = NotificationLog::open(&path, Retention::new()).unwrap();
+        assert_eq!(log.range(), Some((1, 3)));
+        assert_eq!(log.append(&topic(), &notification("d")).unwrap(), 4);
+    }
+
+    #[test]
+    fn write_and_read_on_thread() {
+        let path = log_path("write");
+        let log = NotificationLog::open(&path, Retention::new()).unwrap();
+        let written: Vec<_> = ["a", "b", "c"]
+            .iter()
+            .map(|s| log.write(topic(), notification(s)))
+            .collect();
+        let seqs: Vec<_> = written
+            .into_iter()
+            .map(|seq| block_on(seq).unwrap().unwrap())
+            .collect();
+        assert_eq!(seqs, vec![1, 2, 3]);
+        assert_eq!(log.read_from(3).unwrap(), vec![(3, topic(), notification("c"))]);
+        let read = block_on(log.read(2, 1)).unwrap().unwrap();
+        assert_eq!(read, vec![(2, topic(), notification("b"))]);
+        assert!(block_on(log.read(4, 1)).unwrap().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reject_oversize_topic() {
+        let path = log_path("oversize");
+        let log = NotificationLog::open(&path, Retention::new()).unwrap();
+        let long = Bytes::from(vec![b't'; 1 << 16]);
+        let err = log.append(&long, &notification("a")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = block_on(log.write(long, notification("a"))).unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(log.append(&topic(), &notification("b")).unwrap(), 1);
+    }
+
+    #[test]
+    fn cut_incomplete_record() {
+        let path = log_path("incomplete");
+        let log = NotificationLog::open(&path, Retention::new()).unwrap();
+        log.append(&topic(), &notification("a")).unwrap();
+        drop(log);
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&[2, 0, 0])
+            .unwrap();
+
+        let log = NotificationLog::open(&path, Retention::new()).unwrap();
+        assert_eq!(log.append(&topic(), &notification("b")).unwrap(), 2);
+        assert_eq!(
+            log.read_from(0).unwrap(),
+            vec![(1, topic(), notification("a")), (2, topic(), notification("b"))]
+        );
+    }
+
+    #[test]
+    fn retain_notifications() {
+        let path = log_path("retention");
+        let log = NotificationLog::open(&path, Retention::new().max_count(2)).unwrap();
+        for s in &["a", "b", "c", "d", "e"] {
+            log.append(&topic(), &notification(s)).unwrap();
+        }
+        assert_eq!(log.range(), Some((4, 5)));
+        assert!(fs::metadata(&path).unwrap().len() < FILE_HEADER_LEN + 5 * (RECORD_HEADER_LEN + 2));
+        drop(log);
+
+        thread::sleep(Duration::from_millis(10));
+        let retention = Retention::new().max_age(Duration::from_secs(0));
+        let log = NotificationLog::open(&path, retention).unwrap();
+        assert_eq!(log.range(), None);
+        drop(log);
+
+        let log = NotificationLog::open(&path, Retention::new()).unwrap();
+        assert_eq!(log.append(&topic(), &notification("f")).unwrap(), 6);
+    }
+}
@@ -0,0 +1,113 @@
+use bytes::{BufMut, ByteOrder, Bytes, BytesMut, LittleEndian};
+use std::slice;
+
+/// Key/value metadata sent along with a request or a response.
+///
+/// Keys and values are at most `u16::max_value()` bytes long and there are
+/// at most as many entries. Keys starting with `$` are reserved for the
+/// headers of the protocol.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Headers(Vec<(Bytes, Bytes)>);
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers(Vec::new())
+    }
+
+    /// Sets `key` to `value`, replacing the previous value.
+    ///
+    /// Returns `false`, leaving the headers unchanged, if the key or the value
+    /// is too long or if there are too many entries already.
+    pub fn insert(&mut self, key: Bytes, value: Bytes) -> bool {
+        let max = u16::max_value() as usize;
+        if key.len() > max || value.len() > max {
+            return false;
+        }
+        match self.0.iter_mut().find(|(k, _)| k == &key) {
+            Some(entry) => entry.1 = value,
+            None if self.0.len() < max => self.0.push((key, value)),
+            None => return false,
+        }
+        true
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&Bytes> {
+        self.0.iter().find(|(k, _)| &k[..] == key).map(|(_, v)| v)
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<Bytes> {
+        let i = self.0.iter().position(|(k, _)| &k[..] == key)?;
+        Some(self.0.remove(i).1)
+    }
+
+    pub fn iter(&self) -> slice::Iter<(Bytes, Bytes)> {
+        self.0.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        self.0
+            .iter()
+            .fold(2, |len, (k, v)| len + 4 + k.len() + v.len())
+    }
+
+    /// Reads a header section from the front of `b`.
+    pub fn read(b: &mut Bytes) -> Option<Headers> {
+        if b.len() < 2 {
+            return None;
+        }
+        let count = LittleEndian::read_u16(&b.split_to(2));
+        let mut headers = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let key = Self::read_field(b)?;
+            let value = Self::read_field(b)?;
+            headers.push((key, value));
+        }
+        Some(Headers(headers))
+    }
+
+    fn read_field(b: &mut Bytes) -> Option<Bytes> {
+        if b.len() < 2 {
+            return None;
+        }
+        let len = LittleEndian::read_u16(&b.split_to(2)) as usize;
+        if len > b.len() {
+            return None;
+        }
+        Some(b.split_to(len))
+    }
+
+    pub fn write(&self, b: &mut BytesMut) {
+        b.reserve(self.encoded_len());
+        b.put_u16_le(self.0.len() as u16);
+        for (key, value) in &self.0 {
+            b.put_u16_le(key.len() as u16);
+            b.put(key);
+            b.put_u16_le(value.len() as u16);
+            b.put(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reject_long_headers() {
+        let mut headers = Headers::new();
+        let long = Bytes::from(vec![0; u16::max_value() as usize + 1]);
+        assert!(!headers.insert(long.clone(), Bytes::new()));
+        assert!(!headers.insert(Bytes::from(&b"key"[..]), long));
+        assert!(headers.is_empty());
+        assert!(headers.insert(Bytes::from(&b"key"[..]), Bytes::new()));
+        assert_eq!(headers.len(), 1);
+    }
+}
@@ -1,6 +1,8 @@
+mod headers;
 mod request;
 mod response;
 
+pub use self::headers::Headers;
 pub use self::request::{Request, RequestType};
 pub use self::response::{
     NotificationResponse, Response, RpcResponse, SubscriptionResponse, UnsubscriptionResponse,
@@ -1,4 +1,11 @@
+use super::Headers;
 use bytes::{BufMut, ByteOrder, Bytes, BytesMut, LittleEndian};
+use std::time::{Duration, Instant};
+
+/// Set in the type byte when the request carries a deadline.
+const DEADLINE_FLAG: u8 = 0x80;
+/// Set in the type byte when the request carries headers.
+const HEADERS_FLAG: u8 = 0x40;
 
 #[derive(Clone, Copy, Debug)]
 pub enum RequestType {
@@ -6,6 +13,7 @@ pub enum RequestType {
     Subscription,
     Unsubscription,
     Notification,
+    TopicRemoved,
 }
 
 #[derive(Debug)]
@@ -13,6 +21,8 @@ pub struct Request {
     pub kind: RequestType,
     pub topic: Bytes,
     pub message: Bytes,
+    pub deadline: Option<Instant>,
+    pub headers: Headers,
 }
 
 impl RequestType {
@@ -22,6 +32,7 @@ impl RequestType {
             1 => Some(RequestType::Subscription),
             2 => Some(RequestType::Unsubscription),
             3 => Some(RequestType::Notification),
+            4 => Some(RequestType::TopicRemoved),
             _ => None,
         }
     }
@@ -34,6 +45,7 @@ impl From<RequestType> for u8 {
             RequestType::Subscription => 1,
             RequestType::Unsubscription => 2,
             RequestType::Notification => 3,
+            RequestType::TopicRemoved => 4,
         }
     }
 }
@@ -45,11 +57,46 @@ impl Request {
             kind: k,
             topic,
             message,
+            deadline: None,
+            headers: Headers::new(),
         }
     }
 
+    pub fn with_deadline(mut self, deadline: Option<Instant>) -> Request {
+        self.deadline = deadline;
+        self
+    }
+
+    pub fn with_headers(mut self, headers: Headers) -> Request {
+        self.headers = headers;
+        self
+    }
+
     pub fn from_bytes(mut b: Bytes) -> Option<Request> {
-        let kind: RequestType = RequestType::from(b.split_to(1)[0])?;
+        if b.is_empty() {
+            return None;
+        }
+        let t = b.split_to(1)[0];
+        let kind: RequestType = RequestType::from(t & !(DEADLINE_FLAG | HEADERS_FLAG))?;
+        // The deadline is sent as the number of milliseconds left, peers do not share a clock.
+        let deadline = if t & DEADLINE_FLAG != 0 {
+            if b.len() < 8 {
+                return None;
+            }
+            let remaining = LittleEndian::read_u64(&b.split_to(8));
+            // A deadline too far away to be represented is no deadline.
+            Instant::now().checked_add(Duration::from_millis(remaining))
+        } else {
+            None
+        };
+        let headers = if t & HEADERS_FLAG != 0 {
+            Headers::read(&mut b)?
+        } else {
+            Headers::new()
+        };
+        if b.len() < 2 {
+            return None;
+        }
         let topic_len = LittleEndian::read_u16(&b.split_to(2));
         if (topic_len as usize) > b.len() {
             return None;
@@ -60,14 +107,94 @@ impl Request {
             kind,
             topic,
             message,
+            deadline,
+            headers,
         })
     }
 
     pub fn write(&self, b: &mut BytesMut) {
-        b.reserve(3 + self.topic.len() + self.message.len());
-        b.put_u8(self.kind.into());
+        b.reserve(11 + self.topic.len() + self.message.len());
+        let t = if self.headers.is_empty() {
+            u8::from(self.kind)
+        } else {
+            u8::from(self.kind) | HEADERS_FLAG
+        };
+        match self.deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                let remaining = if deadline > now {
+                    deadline - now
+                } else {
+                    Duration::from_secs(0)
+                };
+                let remaining =
+                    remaining.as_secs() * 1000 + u64::from(remaining.subsec_millis());
+                b.put_u8(t | DEADLINE_FLAG);
+                b.put_u64_le(remaining);
+            }
+            None => b.put_u8(t),
+        }
+        if !self.headers.is_empty() {
+            self.headers.write(b);
+        }
         b.put_u16_le(self.topic.len() as u16);
         b.put(&self.topic);
         b.put(&self.message);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn deadline_round_trip() {
+        let topic = Bytes::from(&b"topic"[..]);
+        let message = Bytes::from(&b"message"[..]);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let request = Request::new(RequestType::Rpc, topic.clone(), message.clone())
+            .with_deadline(Some(deadline));
+        let mut buf = BytesMut::new();
+        request.write(&mut buf);
+        assert_eq!(buf[0], 0x80);
+
+        let decoded = Request::from_bytes(buf.freeze()).unwrap();
+        assert_eq!(decoded.topic, topic);
+        assert_eq!(decoded.message, message);
+        let decoded_deadline = decoded.deadline.unwrap();
+        assert!(decoded_deadline <= deadline + Duration::from_millis(100));
+        assert!(decoded_deadline >= deadline - Duration::from_secs(1));
+    }
+
+    #[test]
+    fn headers_round_trip() {
+        let mut headers = Headers::new();
+        headers.insert(Bytes::from(&b"trace"[..]), Bytes::from(&b"1234"[..]));
+        let request = Request::new(
+            RequestType::Notification,
+            Bytes::from(&b"topic"[..]),
+            Bytes::from(&b"message"[..]),
+        ).with_headers(headers.clone());
+        let mut buf = BytesMut::new();
+        request.write(&mut buf);
+        assert_eq!(buf[0], 0x43);
+
+        let decoded = Request::from_bytes(buf.freeze()).unwrap();
+        assert_eq!(decoded.headers, headers);
+        assert_eq!(decoded.topic, Bytes::from(&b"topic"[..]));
+        assert_eq!(decoded.message, Bytes::from(&b"message"[..]));
+    }
+
+    #[test]
+    fn unrepresentable_deadline() {
+        let mut buf = BytesMut::with_capacity(11);
+        buf.put_u8(0x80);
+        buf.put_u64_le(u64::max_value());
+        buf.put_u16_le(0);
+        let decoded = Request::from_bytes(buf.freeze()).unwrap();
+        assert!(decoded.deadline.is_none());
+
+        assert!(Request::from_bytes(Bytes::new()).is_none());
+        assert!(Request::from_bytes(Bytes::from(&[0u8][..])).is_none());
+    }
+}
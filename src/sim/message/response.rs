@@ -1,9 +1,15 @@
+use super::Headers;
 use bytes::{BufMut, Bytes, BytesMut};
 
+/// Set in the type byte when the response carries headers.
+const HEADERS_FLAG: u8 = 0x80;
+
 #[derive(Clone, PartialEq, Debug)]
 pub enum RpcResponse {
     Accepted(Bytes),
     TopicNotFound,
+    DeadlineExceeded,
+    InvalidData(Bytes),
     InvalidResponse,
 }
 
@@ -13,6 +19,7 @@ pub enum SubscriptionResponse {
     TopicNotFound,
     AlreadySubscribed,
     Rejected(Bytes),
+    InvalidData(Bytes),
     InvalidResponse,
 }
 
@@ -21,6 +28,7 @@ pub enum UnsubscriptionResponse {
     Accepted(Bytes),
     TopicNotFound,
     NotSubscribed,
+    InvalidData(Bytes),
     InvalidResponse,
 }
 
@@ -41,6 +49,8 @@ pub enum Response {
     NotSubscribed,
     Notified,
     InvalidRequest,
+    DeadlineExceeded,
+    InvalidData(Bytes),
     InvalidResponse,
 }
 
@@ -53,6 +63,8 @@ enum ResponseType {
     NotSubscribed,
     Notified,
     InvalidRequest,
+    DeadlineExceeded,
+    InvalidData,
     InvalidResponse,
 }
 
@@ -61,6 +73,8 @@ impl From<Response> for RpcResponse {
         match r {
             Response::Accepted(x) => RpcResponse::Accepted(x),
             Response::TopicNotFound => RpcResponse::TopicNotFound,
+            Response::DeadlineExceeded => RpcResponse::DeadlineExceeded,
+            Response::InvalidData(x) => RpcResponse::InvalidData(x),
             _ => RpcResponse::InvalidResponse,
         }
     }
@@ -73,6 +87,7 @@ impl From<Response> for SubscriptionResponse {
             Response::TopicNotFound => SubscriptionResponse::TopicNotFound,
             Response::AlreadySubscribed => SubscriptionResponse::AlreadySubscribed,
             Response::Rejected(x) => SubscriptionResponse::Rejected(x),
+            Response::InvalidData(x) => SubscriptionResponse::InvalidData(x),
             _ => SubscriptionResponse::InvalidResponse,
         }
     }
@@ -84,6 +99,7 @@ impl From<Response> for UnsubscriptionResponse {
             Response::Accepted(x) => UnsubscriptionResponse::Accepted(x),
             Response::TopicNotFound => UnsubscriptionResponse::TopicNotFound,
             Response::NotSubscribed => UnsubscriptionResponse::NotSubscribed,
+            Response::InvalidData(x) => UnsubscriptionResponse::InvalidData(x),
             _ => UnsubscriptionResponse::InvalidResponse,
         }
     }
@@ -105,6 +121,8 @@ impl From<RpcResponse> for Response {
         match r {
             RpcResponse::Accepted(x) => Response::Accepted(x),
             RpcResponse::TopicNotFound => Response::TopicNotFound,
+            RpcResponse::DeadlineExceeded => Response::DeadlineExceeded,
+            RpcResponse::InvalidData(x) => Response::InvalidData(x),
             RpcResponse::InvalidResponse => Response::InvalidResponse,
         }
     }
@@ -117,6 +135,7 @@ impl From<SubscriptionResponse> for Response {
             SubscriptionResponse::TopicNotFound => Response::TopicNotFound,
             SubscriptionResponse::Rejected(x) => Response::Rejected(x),
             SubscriptionResponse::AlreadySubscribed => Response::AlreadySubscribed,
+            SubscriptionResponse::InvalidData(x) => Response::InvalidData(x),
             SubscriptionResponse::InvalidResponse => Response::InvalidResponse,
         }
     }
@@ -128,6 +147,7 @@ impl From<UnsubscriptionResponse> for Response {
             UnsubscriptionResponse::Accepted(x) => Response::Accepted(x),
             UnsubscriptionResponse::TopicNotFound => Response::TopicNotFound,
             UnsubscriptionResponse::NotSubscribed => Response::NotSubscribed,
+            UnsubscriptionResponse::InvalidData(x) => Response::InvalidData(x),
             UnsubscriptionResponse::InvalidResponse => Response::InvalidResponse,
         }
     }
@@ -154,15 +174,30 @@ impl ResponseType {
             4 => ResponseType::NotSubscribed,
             5 => ResponseType::Notified,
             6 => ResponseType::InvalidRequest,
+            7 => ResponseType::DeadlineExceeded,
+            8 => ResponseType::InvalidData,
             _ => ResponseType::InvalidResponse,
         }
     }
 }
 
 impl Response {
-    pub fn from_bytes(mut b: Bytes) -> Self {
-        let kind: ResponseType = ResponseType::from(b.split_to(1)[0]);
-        match kind {
+    pub fn from_bytes(b: Bytes) -> Self {
+        Self::from_bytes_with_headers(b).0
+    }
+
+    pub fn from_bytes_with_headers(mut b: Bytes) -> (Self, Headers) {
+        let t = b.split_to(1)[0];
+        let headers = if t & HEADERS_FLAG != 0 {
+            match Headers::read(&mut b) {
+                Some(headers) => headers,
+                None => return (Response::InvalidResponse, Headers::new()),
+            }
+        } else {
+            Headers::new()
+        };
+        let kind: ResponseType = ResponseType::from(t & !HEADERS_FLAG);
+        let response = match kind {
             ResponseType::Accepted => {
                 let message = b;
                 Response::Accepted(message)
@@ -176,8 +211,26 @@ impl Response {
             ResponseType::NotSubscribed => Response::NotSubscribed,
             ResponseType::Notified => Response::Notified,
             ResponseType::InvalidRequest => Response::InvalidRequest,
+            ResponseType::DeadlineExceeded => Response::DeadlineExceeded,
+            ResponseType::InvalidData => {
+                let message = b;
+                Response::InvalidData(message)
+            }
             ResponseType::InvalidResponse => Response::InvalidResponse,
+        };
+        (response, headers)
+    }
+
+    pub fn write_with_headers(&self, headers: &Headers, b: &mut BytesMut) {
+        if headers.is_empty() {
+            return self.write(b);
         }
+        let mut response = BytesMut::new();
+        self.write(&mut response);
+        b.reserve(response.len() + headers.encoded_len());
+        b.put_u8(response[0] | HEADERS_FLAG);
+        headers.write(b);
+        b.put(&response[1..]);
     }
 
     pub fn write(&self, b: &mut BytesMut) {
@@ -212,6 +265,15 @@ impl Response {
                 b.reserve(1);
                 b.put_u8(6)
             }
+            Response::DeadlineExceeded => {
+                b.reserve(1);
+                b.put_u8(7)
+            }
+            Response::InvalidData(x) => {
+                b.reserve(x.len() + 1);
+                b.put_u8(8);
+                b.put(x);
+            }
             Response::InvalidResponse => panic!("invalid response"),
         }
     }
@@ -1,19 +1,39 @@
+mod context;
+mod fanout;
 mod handler;
+mod log;
 mod message;
+mod reconnect;
 mod requestor;
+mod schema;
+mod topic;
+#[cfg(feature = "typed")]
+pub mod typed;
 
-pub use self::handler::Handler;
+pub use self::context::{
+    deadline, headers, matched_segments, set_response_header, unsubscribe_reason,
+    UnsubscribeReason,
+};
+use self::context::{set_matched_segments, RequestContext};
+use self::log::{decode_sequence, RESUME_HEADER, SEQUENCE_HEADER};
+pub use self::log::{NotificationLog, Retention};
+pub use self::fanout::{SlowSubscriberPolicy, SubscriberStats, Subscribed, SubscriptionConfig};
+pub use self::handler::{Filter, Handler, InvalidRequest, SubscribeDecision};
 pub use self::message::{
-    NotificationResponse, Response, RpcResponse, SubscriptionResponse, UnsubscriptionResponse,
+    Headers, NotificationResponse, Response, RpcResponse, SubscriptionResponse,
+    UnsubscriptionResponse,
 };
 use self::message::{Request, RequestType};
+pub use self::reconnect::{Backoff, ReconnectEvent};
 pub use self::requestor::Requestor;
+pub use self::schema::SCHEMA_TOPIC;
+use self::requestor::Subscriptions;
 
 use bytes::{Bytes, BytesMut};
 use crossbeam::sync::AtomicOption;
-use dialog::{Caller, Dialog};
-use futures::channel::mpsc::Sender;
-use futures::future::ok;
+use dialog::{Caller, DatagramDialog, Dialog, Mux};
+use framed::datagram::AsyncDatagram;
+use futures::future::{join_all, ok};
 use futures::io::{AsyncRead, AsyncWrite};
 use futures::prelude::*;
 use parking_lot::RwLock;
@@ -21,6 +41,7 @@ use std::collections::HashMap;
 use std::io;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Instant;
 
 pub struct Sim(Arc<Handler>);
 
@@ -36,53 +57,193 @@ impl Sim {
     ) -> (Requestor, impl Future<Item = (), Error = io::Error>) {
         let receiving_subs_map = Arc::new(RwLock::new(HashMap::new()));
         let caller_opt = Arc::new(AtomicOption::new()); // FIXME: this may not make inner Sync
-        let (caller, handler) = {
-            let subs_map = receiving_subs_map.clone();
-            let handler = Arc::clone(&self.0);
-            let caller_opt = caller_opt.clone();
-            io.dialog(move |request| {
-                let fut = match Request::from_bytes(request) {
-                    Some(Request {
-                        kind,
-                        topic,
-                        message,
-                    }) => match kind {
-                        RequestType::Rpc => Self::rpc_handler(&*handler, topic, message)
-                            as Box<Future<Item = _, Error = _> + Send + Sync>,
-                        RequestType::Subscription => {
-                            let caller = caller_opt.take(Ordering::Relaxed).unwrap();
-                            Self::sub_handler(handler.clone(), topic, message, caller)
-                        }
-                        RequestType::Unsubscription => {
-                            Self::unsub_handler(&*handler, topic, message)
-                        }
-                        RequestType::Notification => {
-                            Self::notify_handler(&subs_map, topic, message)
-                        }
-                    },
-                    None => Box::new(ok(Response::InvalidRequest)),
-                }.map(|resp| {
-                    let mut resp_message = BytesMut::new();
-                    resp.write(&mut resp_message);
-                    resp_message.freeze()
-                });
-                Box::new(fut)
-            })
-        };
-        // TODO: handler.and_then{ to remove from subs lists }
+        let (caller, handler) = io.dialog(self.request_handler(&receiving_subs_map, &caller_opt));
         caller_opt.swap(caller.clone(), Ordering::Relaxed);
+        let handler = self.unsubscribe_on_exit(handler, caller.clone());
         (Requestor::new(caller, receiving_subs_map), handler)
     }
 
+    /// Runs the sim layer over a datagram socket, such as a `UnixDatagramSocket`, one message
+    /// per datagram.
+    ///
+    /// Requests are sent to `peer`, or to the connected peer if it is `None`. Without `peer`,
+    /// requests of any address are answered, except subscriptions, which are rejected as their
+    /// notifications would have no address to be sent to.
+    pub fn add_datagram<T>(
+        &self,
+        socket: T,
+        peer: Option<T::Addr>,
+    ) -> (Requestor, impl Future<Item = (), Error = io::Error>)
+    where
+        T: AsyncDatagram + Send + Sync + 'static,
+        T::Addr: Clone + PartialEq + Send + Sync + 'static,
+    {
+        let receiving_subs_map = Arc::new(RwLock::new(HashMap::new()));
+        let caller_opt = Arc::new(AtomicOption::new());
+        let subscribable = peer.is_some();
+        let (caller, handler) = socket
+            .datagram_dialog(peer, self.request_handler(&receiving_subs_map, &caller_opt));
+        if subscribable {
+            caller_opt.swap(caller.clone(), Ordering::Relaxed);
+        }
+        let handler = self.unsubscribe_on_exit(handler, caller.clone());
+        (Requestor::new(caller, receiving_subs_map), handler)
+    }
+
+    /// Returns the handler of the topics of this `Sim`.
+    ///
+    /// Topics added or removed through it take effect on all connections, including the
+    /// live ones.
+    pub fn handler(&self) -> &Handler {
+        &self.0
+    }
+
+    /// Runs the sim layer on channel `channel` of a multiplexed connection.
+    ///
+    /// Returns `None` if the channel is already open.
+    pub fn add_channel(
+        &self,
+        mux: &Mux,
+        channel: u16,
+    ) -> Option<(Requestor, impl Future<Item = (), Error = io::Error>)> {
+        let receiving_subs_map = Arc::new(RwLock::new(HashMap::new()));
+        let caller_opt = Arc::new(AtomicOption::new());
+        let (caller, handler) =
+            mux.channel(channel, self.request_handler(&receiving_subs_map, &caller_opt))?;
+        caller_opt.swap(caller.clone(), Ordering::Relaxed);
+        let handler = self.unsubscribe_on_exit(handler, caller.clone());
+        Some((Requestor::new(caller, receiving_subs_map), handler))
+    }
+
+    /// Removes the peer of `caller` from all topics once `connection` ends.
+    ///
+    /// The unsubscribe handler of every topic the peer was subscribed to is called with
+    /// empty data, `unsubscribe_reason` returning `UnsubscribeReason::Disconnected`.
+    fn unsubscribe_on_exit<F>(
+        &self,
+        connection: F,
+        caller: Caller,
+    ) -> impl Future<Item = (), Error = io::Error> + Send
+    where
+        F: Future<Item = (), Error = io::Error> + Send,
+    {
+        let handler = Arc::clone(&self.0);
+        connection.then(move |result| {
+            let unsubs: Vec<_> = handler
+                .remove_caller(&caller)
+                .into_iter()
+                .filter_map(|topic| {
+                    let call_handler = handler.get_unsubs(&topic)?;
+                    let context = RequestContext::new(None, Headers::new())
+                        .with_unsubscribe_reason(UnsubscribeReason::Disconnected);
+                    let unsub = context.run(|| {
+                        set_matched_segments(call_handler.segments);
+                        (call_handler.value)(Bytes::new())
+                    });
+                    Some(unsub.then(|_| Ok::<_, Never>(())))
+                })
+                .collect();
+            join_all(unsubs).then(move |_| result)
+        })
+    }
+
+    fn request_handler(
+        &self,
+        subs_map: &Subscriptions,
+        caller_opt: &Arc<AtomicOption<Caller>>,
+    ) -> impl Fn(Bytes) -> Box<Future<Item = Bytes, Error = io::Error> + Send + Sync>
+                 + Send
+                 + Sync
+                 + 'static {
+        let subs_map = Arc::clone(subs_map);
+        let handler = Arc::clone(&self.0);
+        let caller_opt = Arc::clone(caller_opt);
+        move |request| {
+            let request = match Request::from_bytes(request) {
+                Some(request) => request,
+                None => return Self::respond(ok(Response::InvalidRequest), None),
+            };
+            let Request {
+                kind,
+                topic,
+                message,
+                deadline,
+                headers,
+            } = request;
+            if deadline.map_or(false, |deadline| deadline <= Instant::now()) {
+                return Self::respond(ok(Response::DeadlineExceeded), None);
+            }
+            let mut context = RequestContext::new(deadline, headers);
+            if let RequestType::Unsubscription = kind {
+                context = context.with_unsubscribe_reason(UnsubscribeReason::Requested);
+            }
+            let fut = context.clone().run(|| match kind {
+                RequestType::Rpc => Self::rpc_handler(&*handler, topic, message)
+                    as Box<Future<Item = _, Error = _> + Send + Sync>,
+                RequestType::Subscription => match Self::peer_caller(&caller_opt) {
+                    Some(caller) => Self::sub_handler(handler.clone(), topic, message, caller),
+                    None => {
+                        let reason = Bytes::from_static(b"no peer address to notify");
+                        Box::new(ok(SubscriptionResponse::Rejected(reason).into()))
+                    }
+                },
+                RequestType::Unsubscription => match Self::peer_caller(&caller_opt) {
+                    Some(caller) => Self::unsub_handler(&*handler, topic, message, caller),
+                    None => Box::new(ok(UnsubscriptionResponse::NotSubscribed.into())),
+                },
+                RequestType::Notification => Self::notify_handler(&subs_map, topic, message),
+                RequestType::TopicRemoved => Self::topic_removed_handler(&subs_map, topic),
+            });
+            Self::respond(fut, Some(context))
+        }
+    }
+
+    /// Returns the `Caller` to the peer of the connection, leaving it in place for later
+    /// requests, or `None` if the peer has no address to be called at.
+    fn peer_caller(caller_opt: &AtomicOption<Caller>) -> Option<Caller> {
+        let caller = caller_opt.take(Ordering::Relaxed)?;
+        caller_opt.swap(caller.clone(), Ordering::Relaxed);
+        Some(caller)
+    }
+
+    /// Encodes the response of `fut`.
+    ///
+    /// A handler failing with an `InvalidRequest` error is answered with an invalid data
+    /// response. Other errors end the connection.
+    fn respond<F>(
+        fut: F,
+        context: Option<RequestContext>,
+    ) -> Box<Future<Item = Bytes, Error = io::Error> + Send + Sync>
+    where
+        F: Future<Item = Response, Error = io::Error> + Send + Sync + 'static,
+    {
+        let fut = fut.or_else(|e| {
+            if InvalidRequest::is(&e) {
+                Ok(Response::InvalidData(Bytes::from(e.to_string())))
+            } else {
+                Err(e)
+            }
+        });
+        Box::new(fut.map(move |resp| {
+            let headers = context.map(|c| c.response_headers()).unwrap_or_default();
+            let mut resp_message = BytesMut::new();
+            resp.write_with_headers(&headers, &mut resp_message);
+            resp_message.freeze()
+        }))
+    }
+
     fn rpc_handler(
         handler: &Handler,
         topic: Bytes,
         message: Bytes,
     ) -> Box<Future<Item = Response, Error = io::Error> + Send + Sync> {
         match handler.get_rpc(&topic) {
-            Some(call_handler) => Box::new(
-                call_handler(message).map(|x| RpcResponse::Accepted(x).into()),
-            ) as Box<Future<Item = _, Error = _> + Send + Sync>,
+            Some(call_handler) => {
+                set_matched_segments(call_handler.segments);
+                Box::new(
+                    (call_handler.value)(message).map(|x| RpcResponse::Accepted(x).into()),
+                ) as Box<Future<Item = _, Error = _> + Send + Sync>
+            }
             None => Box::new(ok(RpcResponse::TopicNotFound.into())),
         }
     }
@@ -94,44 +255,75 @@ impl Sim {
         caller: Caller,
     ) -> Box<Future<Item = Response, Error = io::Error> + Send + Sync> {
         match handler.clone().get_subs(&topic) {
-            // TODO: add rejection type in the call_handler
-            Some(call_handler) => Box::new(call_handler(message).map(move |x| {
-                match handler.add_notify_caller(&topic, caller) {
-                    Some(true) => SubscriptionResponse::Accepted(x).into(),
-                    Some(false) => SubscriptionResponse::AlreadySubscribed.into(),
-                    None => SubscriptionResponse::TopicNotFound.into(),
-                }
-            })) as Box<Future<Item = _, Error = _> + Send + Sync>,
+            Some(call_handler) => {
+                set_matched_segments(call_handler.segments);
+                let pattern = call_handler.pattern;
+                let resume = headers().get(RESUME_HEADER).and_then(decode_sequence);
+                Box::new((call_handler.value)(message).and_then(move |decision| {
+                    let (x, filter) = match decision {
+                        SubscribeDecision::Accept(x) => (x, None),
+                        SubscribeDecision::AcceptFiltered(x, filter) => (x, Some(filter)),
+                        SubscribeDecision::Reject(x) => {
+                            return Ok(SubscriptionResponse::Rejected(x).into())
+                        }
+                    };
+                    let added = handler.add_notify_caller(&pattern, caller, topic, filter, resume);
+                    let response = match added {
+                        Some(Subscribed::Added) => SubscriptionResponse::Accepted(x),
+                        Some(Subscribed::Already) => SubscriptionResponse::AlreadySubscribed,
+                        None => SubscriptionResponse::TopicNotFound,
+                    };
+                    Ok(response.into())
+                })) as Box<Future<Item = _, Error = _> + Send + Sync>
+            }
             None => Box::new(ok(SubscriptionResponse::TopicNotFound.into())),
         }
     }
 
-    //TODO
     fn unsub_handler(
         handler: &Handler,
         topic: Bytes,
         message: Bytes,
+        caller: Caller,
     ) -> Box<Future<Item = Response, Error = io::Error> + Send + Sync> {
         match handler.get_unsubs(&topic) {
-            Some(call_handler) => Box::new(
-                call_handler(message).map(|x| UnsubscriptionResponse::Accepted(x).into()),
-            ) as Box<Future<Item = _, Error = _> + Send + Sync>,
+            Some(call_handler) => {
+                match handler.remove_notify_caller(&call_handler.pattern, caller, topic) {
+                    Some(true) => {
+                        set_matched_segments(call_handler.segments);
+                        Box::new(
+                            (call_handler.value)(message)
+                                .map(|x| UnsubscriptionResponse::Accepted(x).into()),
+                        ) as Box<Future<Item = _, Error = _> + Send + Sync>
+                    }
+                    Some(false) => Box::new(ok(UnsubscriptionResponse::NotSubscribed.into())),
+                    None => Box::new(ok(UnsubscriptionResponse::TopicNotFound.into())),
+                }
+            }
             None => Box::new(ok(UnsubscriptionResponse::TopicNotFound.into())),
         }
     }
 
     fn notify_handler(
-        subs_map: &Arc<RwLock<HashMap<Bytes, Sender<Bytes>>>>,
+        subs_map: &Subscriptions,
         topic: Bytes,
         message: Bytes,
     ) -> Box<Future<Item = Response, Error = io::Error> + Send + Sync> {
-        let subs_map_read = subs_map.read();
-        let sub = subs_map_read.get(&topic);
-        match sub {
+        let seq = headers().get(SEQUENCE_HEADER).and_then(decode_sequence);
+        let mut subs_map_write = subs_map.write();
+        match subs_map_write.get_mut(&topic) {
             Some(sub) => {
+                if let (Some(seq), Some(last)) = (seq, sub.last_sequence) {
+                    if seq <= last {
+                        // Received already, before resubscribing.
+                        return Box::new(ok(NotificationResponse::Notified.into()));
+                    }
+                }
+                sub.last_sequence = seq.or(sub.last_sequence);
                 let subs_map = subs_map.clone();
                 Box::new(
-                    sub.clone()
+                    sub.sender
+                        .clone()
                         .send(message)
                         .map(|_| NotificationResponse::Notified.into())
                         .recover(move |_| {
@@ -144,6 +336,17 @@ impl Sim {
             None => Box::new(ok(NotificationResponse::TopicNotFound.into())),
         }
     }
+
+    /// Ends the subscription to `topic`, which the peer removed.
+    fn topic_removed_handler(
+        subs_map: &Subscriptions,
+        topic: Bytes,
+    ) -> Box<Future<Item = Response, Error = io::Error> + Send + Sync> {
+        match subs_map.write().remove(&topic) {
+            Some(_) => Box::new(ok(Response::Accepted(Bytes::new()))),
+            None => Box::new(ok(Response::NotSubscribed)),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -167,7 +370,7 @@ mod test {
 
     #[test]
     fn simple_rpc() {
-        let mut handler = Handler::new();
+        let handler = Handler::new();
         let topic_echo = BytesMut::from(r"echo").freeze();
         let topic_del = BytesMut::from(r"del").freeze();
         handler.on_rpc(topic_echo.clone(), Box::new(|req| Box::new(ok(req))));
@@ -204,15 +407,300 @@ mod test {
         let _ = block_on(f1.join(f2).join(f3)).unwrap();
     }
 
+    #[test]
+    fn rpc_over_channel() {
+        use dialog::Mux;
+        let handler = Handler::new();
+        let topic_echo = BytesMut::from(r"echo").freeze();
+        handler.on_rpc(topic_echo.clone(), Box::new(|req| Box::new(ok(req))));
+        let sim = Sim::new(handler);
+
+        let (io1, io2) = PairIO::new();
+        let (mux1, fut) = Mux::new(io1);
+        block_on(spawn(fut.map_err(|e| panic!("mux1 fut panic {:?}", e)))).unwrap();
+        let (mux2, fut) = Mux::new(io2);
+        block_on(spawn(fut.map_err(|e| panic!("mux2 fut panic {:?}", e)))).unwrap();
+
+        let (req1, fut) = sim.add_channel(&mux1, 1).unwrap();
+        block_on(spawn(fut.map_err(|e| panic!("channel 1 sim fut panic {:?}", e)))).unwrap();
+        let (_req2, fut) = sim.add_channel(&mux2, 1).unwrap();
+        block_on(spawn(fut.map_err(|e| panic!("channel 1 sim fut panic {:?}", e)))).unwrap();
+        assert!(sim.add_channel(&mux1, 1).is_none());
+
+        // Raw dialog traffic shares the connection on another channel.
+        let (raw_caller, fut) = mux1.channel(2, |req| Box::new(ok(req))).unwrap();
+        block_on(spawn(fut.map_err(|e| panic!("channel 2 fut panic {:?}", e)))).unwrap();
+        let (_, fut) = mux2.channel(2, |req| Box::new(ok(req))).unwrap();
+        block_on(spawn(fut.map_err(|e| panic!("channel 2 fut panic {:?}", e)))).unwrap();
+
+        let hello = BytesMut::from(r"hello").freeze();
+        let f1 = req1
+            .rpc(topic_echo, hello.clone())
+            .inspect(|(_, resp)| assert_eq!(resp, &RpcResponse::Accepted(hello.clone())));
+        let f2 = raw_caller
+            .call(hello.clone())
+            .inspect(|(_, resp)| assert_eq!(resp, &hello));
+        let _ = block_on(f1.join(f2)).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn datagram_rpc() {
+        use framed::datagram::UnixDatagramSocket;
+        use util::TempPath;
+
+        let handler = Handler::new();
+        let topic_echo = BytesMut::from(r"echo").freeze();
+        handler.on_rpc(topic_echo.clone(), Box::new(|req| Box::new(ok(req))));
+        let server_path = TempPath::new("server.sock");
+        let server = UnixDatagramSocket::bind(&server_path).unwrap();
+        let (_, fut) = Sim::new(handler).add_datagram(server, None);
+        block_on(spawn(fut.map_err(|e| panic!("server sim fut panic {:?}", e)))).unwrap();
+
+        // Both clients send their first request with the same id.
+        let mut clients = Vec::new();
+        for name in &["client1", "client2"] {
+            let client_path = TempPath::new(&format!("{}.sock", name));
+            let client = UnixDatagramSocket::bind(&client_path).unwrap();
+            client.connect(&server_path).unwrap();
+            let (req, fut) = Sim::new(Handler::new()).add_datagram(client, None);
+            block_on(spawn(fut.map_err(|e| panic!("client sim fut panic {:?}", e)))).unwrap();
+            clients.push((req, client_path, Bytes::from(name.as_bytes())));
+        }
+        let calls: Vec<_> = clients
+            .iter()
+            .map(|&(ref req, _, ref name)| {
+                let name = name.clone();
+                req.clone()
+                    .rpc(topic_echo.clone(), name.clone())
+                    .map(move |(_, resp)| assert_eq!(resp, RpcResponse::Accepted(name)))
+            })
+            .collect();
+        block_on(join_all(calls)).unwrap();
+
+        // The server has no address to send notifications to.
+        let req = clients[0].0.clone();
+        let (_, resp, receiver) = block_on(req.sub(topic_echo, Bytes::new())).unwrap();
+        let reason = Bytes::from_static(b"no peer address to notify");
+        assert_eq!(resp, SubscriptionResponse::Rejected(reason));
+        assert!(receiver.is_none());
+    }
+
+    #[test]
+    fn deadline_propagation() {
+        use std::time::Duration;
+        let topic_check = BytesMut::from(r"check").freeze();
+        let topic_forward = BytesMut::from(r"forward").freeze();
+
+        // Peer b reports whether the request it handles has a deadline.
+        let handler_b = Handler::new();
+        handler_b.on_rpc(
+            topic_check.clone(),
+            Box::new(|_| {
+                let has_deadline = if deadline().is_some() { "yes" } else { "no" };
+                Box::new(ok(BytesMut::from(has_deadline).freeze()))
+            }),
+        );
+        let sim_b = Sim::new(handler_b);
+        let (io_b1, io_b2) = PairIO::new();
+        let (to_b, fut) = Sim::new(Handler::new()).add(io_b1);
+        block_on(spawn(fut.map_err(|e| panic!("to_b sim fut panic {:?}", e)))).unwrap();
+        let (_, fut) = sim_b.add(io_b2);
+        block_on(spawn(fut.map_err(|e| panic!("b sim fut panic {:?}", e)))).unwrap();
+
+        // Peer a forwards its requests to peer b.
+        let handler_a = Handler::new();
+        handler_a.on_rpc(
+            topic_forward.clone(),
+            Box::new(move |req| {
+                Box::new(
+                    to_b.clone()
+                        .rpc(topic_check.clone(), req)
+                        .map(|(_, resp)| match resp {
+                            RpcResponse::Accepted(x) => x,
+                            _ => Bytes::new(),
+                        }),
+                )
+            }),
+        );
+        let sim_a = Sim::new(handler_a);
+        let (io1, io2) = PairIO::new();
+        let (req1, fut) = Sim::new(Handler::new()).add(io1);
+        block_on(spawn(fut.map_err(|e| panic!("io1 sim fut panic {:?}", e)))).unwrap();
+        let (_, fut) = sim_a.add(io2);
+        block_on(spawn(fut.map_err(|e| panic!("io2 sim fut panic {:?}", e)))).unwrap();
+
+        let hello = BytesMut::from(r"hello").freeze();
+        let (req1, resp) = block_on(req1.rpc(topic_forward.clone(), hello.clone())).unwrap();
+        assert_eq!(resp, RpcResponse::Accepted(BytesMut::from(r"no").freeze()));
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let (req1, resp) = block_on(req1.rpc_with_deadline(
+            topic_forward.clone(),
+            hello.clone(),
+            deadline,
+        )).unwrap();
+        assert_eq!(resp, RpcResponse::Accepted(BytesMut::from(r"yes").freeze()));
+
+        let expired = Instant::now();
+        let (_, resp) =
+            block_on(req1.rpc_with_deadline(topic_forward, hello, expired)).unwrap();
+        assert_eq!(resp, RpcResponse::DeadlineExceeded);
+    }
+
+    #[test]
+    fn rpc_headers() {
+        let handler = Handler::new();
+        let topic_auth = BytesMut::from(r"auth").freeze();
+        handler.on_rpc(
+            topic_auth.clone(),
+            Box::new(|_| {
+                let token = headers().get(b"token").cloned().unwrap_or_default();
+                set_response_header(Bytes::from(&b"trace"[..]), Bytes::from(&b"42"[..]));
+                Box::new(ok(token))
+            }),
+        );
+        let sim = Sim::new(handler);
+
+        let (io1, io2) = PairIO::new();
+        let (req1, fut) = sim.add(io1);
+        block_on(spawn(fut.map_err(|e| panic!("io1 sim fut panic {:?}", e)))).unwrap();
+        let (_req2, fut) = sim.add(io2);
+        block_on(spawn(fut.map_err(|e| panic!("io2 sim fut panic {:?}", e)))).unwrap();
+
+        let mut request_headers = Headers::new();
+        request_headers.insert(Bytes::from(&b"token"[..]), Bytes::from(&b"secret"[..]));
+        let (_, resp, response_headers) = block_on(req1.rpc_with_headers(
+            topic_auth,
+            Bytes::new(),
+            request_headers,
+        )).unwrap();
+        assert_eq!(resp, RpcResponse::Accepted(Bytes::from(&b"secret"[..])));
+        assert_eq!(response_headers.get(b"trace"), Some(&Bytes::from(&b"42"[..])));
+    }
+
+    #[test]
+    fn wildcard_topics() {
+        let handler = Handler::new();
+        handler.on_rpc(
+            BytesMut::from(r"sensor/+/temp").freeze(),
+            Box::new(|_| Box::new(ok(matched_segments().remove(0)))),
+        );
+        handler.on_rpc(
+            BytesMut::from(r"sensor/#").freeze(),
+            Box::new(|_| Box::new(ok(BytesMut::from(r"any").freeze()))),
+        );
+        let (sink, fut) = handler.on_subs(
+            BytesMut::from(r"alarm/+").freeze(),
+            Box::new(|_| Box::new(ok(SubscribeDecision::Accept(matched_segments().remove(0))))),
+            Box::new(|req| Box::new(ok(req))),
+        );
+        block_on(spawn(fut.map_err(|e| panic!("on_sub fut panic {:?}", e)))).unwrap();
+        let sim = Sim::new(handler);
+
+        let (io1, io2) = PairIO::new();
+        let (req1, fut) = sim.add(io1);
+        block_on(spawn(fut.map_err(|e| panic!("io1 sim fut panic {:?}", e)))).unwrap();
+        let (_req2, fut) = sim.add(io2);
+        block_on(spawn(fut.map_err(|e| panic!("io2 sim fut panic {:?}", e)))).unwrap();
+
+        let (req1, resp) =
+            block_on(req1.rpc(BytesMut::from(r"sensor/7/temp").freeze(), Bytes::new())).unwrap();
+        assert_eq!(resp, RpcResponse::Accepted(BytesMut::from(r"7").freeze()));
+        let (req1, resp) =
+            block_on(req1.rpc(BytesMut::from(r"sensor/7/hum").freeze(), Bytes::new())).unwrap();
+        assert_eq!(resp, RpcResponse::Accepted(BytesMut::from(r"any").freeze()));
+
+        let door = BytesMut::from(r"alarm/door").freeze();
+        let window = BytesMut::from(r"alarm/window").freeze();
+        let (req1, resp, door_receiver) = block_on(req1.sub(door.clone(), Bytes::new())).unwrap();
+        assert_eq!(resp, SubscriptionResponse::Accepted(BytesMut::from(r"door").freeze()));
+        let (_, _, window_receiver) = block_on(req1.sub(window.clone(), Bytes::new())).unwrap();
+
+        // Each notification only reaches the subscribers of its own topic.
+        let open = BytesMut::from(r"open").freeze();
+        let hello = BytesMut::from(r"hello").freeze();
+        let sink = block_on(sink.send((window, open.clone()))).unwrap();
+        block_on(sink.send((door, hello.clone()))).unwrap();
+        let (notification, _) = block_on(door_receiver.unwrap().next())
+            .map_err(|(e, _)| e)
+            .unwrap();
+        assert_eq!(notification, Some(hello));
+        let (notification, _) = block_on(window_receiver.unwrap().next())
+            .map_err(|(e, _)| e)
+            .unwrap();
+        assert_eq!(notification, Some(open));
+    }
+
+    #[test]
+    fn invalid_request() {
+        use futures::future::err;
+
+        let handler = Handler::new();
+        let topic = BytesMut::from(r"parse").freeze();
+        handler.on_rpc(
+            topic.clone(),
+            Box::new(|_| Box::new(err(InvalidRequest::error("not a number")))),
+        );
+        let sim = Sim::new(handler);
+
+        let (io1, io2) = PairIO::new();
+        let (req1, fut) = sim.add(io1);
+        block_on(spawn(fut.map_err(|e| panic!("io1 sim fut panic {:?}", e)))).unwrap();
+        let (_req2, fut) = sim.add(io2);
+        block_on(spawn(fut.map_err(|e| panic!("io2 sim fut panic {:?}", e)))).unwrap();
+
+        let (_, resp) = block_on(req1.rpc(topic, Bytes::new())).unwrap();
+        let message = BytesMut::from(r"not a number").freeze();
+        assert_eq!(resp, RpcResponse::InvalidData(message));
+    }
+
+    #[test]
+    fn runtime_topics() {
+        let sim = Sim::new(Handler::new());
+        let (io1, io2) = PairIO::new();
+        let (req1, fut) = sim.add(io1);
+        block_on(spawn(fut.map_err(|e| panic!("io1 sim fut panic {:?}", e)))).unwrap();
+        let (_req2, fut) = sim.add(io2);
+        block_on(spawn(fut.map_err(|e| panic!("io2 sim fut panic {:?}", e)))).unwrap();
+
+        let topic_echo = BytesMut::from(r"echo").freeze();
+        let hello = BytesMut::from(r"hello").freeze();
+        sim.handler().on_rpc(topic_echo.clone(), Box::new(|req| Box::new(ok(req))));
+        let (req1, resp) = block_on(req1.rpc(topic_echo.clone(), hello.clone())).unwrap();
+        assert_eq!(resp, RpcResponse::Accepted(hello.clone()));
+
+        assert!(sim.handler().remove_rpc(&topic_echo));
+        let (req1, resp) = block_on(req1.rpc(topic_echo, hello)).unwrap();
+        assert_eq!(resp, RpcResponse::TopicNotFound);
+
+        let topic = BytesMut::from(r"topic").freeze();
+        let (_, fut) = sim.handler().on_subs(
+            topic.clone(),
+            Box::new(|req| Box::new(ok(SubscribeDecision::Accept(req)))),
+            Box::new(|req| Box::new(ok(req))),
+        );
+        block_on(spawn(fut.map_err(|e| panic!("on_sub fut panic {:?}", e)))).unwrap();
+        let (req1, _, receiver) = block_on(req1.sub(topic.clone(), Bytes::new())).unwrap();
+
+        block_on(sim.handler().remove_subs(&topic)).unwrap();
+        let (notification, _) = block_on(receiver.unwrap().next())
+            .map_err(|(e, _)| e)
+            .unwrap();
+        assert_eq!(notification, None);
+        let (_, resp, _) = block_on(req1.sub(topic, Bytes::new())).unwrap();
+        assert_eq!(resp, SubscriptionResponse::TopicNotFound);
+    }
+
     #[test]
     fn simple_notify() {
-        use futures::future::ok;
-        let mut handler = Handler::new();
+        use futures::future::{join_all, ok};
+        let handler = Handler::new();
         let topic_once = BytesMut::from(r"once").freeze();
 
         let (once_sink, fut) = handler.on_subs(
             topic_once.clone(),
-            Box::new(|req| Box::new(ok(req))),
+            Box::new(|req| Box::new(ok(SubscribeDecision::Accept(req)))),
             Box::new(|req| Box::new(ok(req))),
         );
         block_on(spawn(fut.map_err(|e| panic!("on_sub fut panic {:?}", e)))).unwrap();
@@ -226,7 +714,7 @@ mod test {
         block_on(spawn(fut.map_err(|e| panic!("io2 sim fut panic {:?}", e)))).unwrap();
 
         let fut = once_sink
-            .send(BytesMut::from(b"Hello" as &[u8]).freeze())
+            .send((topic_once.clone(), BytesMut::from(b"Hello" as &[u8]).freeze()))
             .map(|_| ())
             .map_err(|e| panic!("Sending notification panic {:?}", e));
 
@@ -249,4 +737,248 @@ mod test {
         block_on(fut.map_err(|e| panic!("Subscribe and get notification panic {:?}", e)))
             .unwrap();
     }
+
+    #[test]
+    fn unsubscribe() {
+        let handler = Handler::new();
+        let topic = BytesMut::from(r"topic").freeze();
+        let (_, fut) = handler.on_subs(
+            topic.clone(),
+            Box::new(|req| Box::new(ok(SubscribeDecision::Accept(req)))),
+            Box::new(|req| Box::new(ok(req))),
+        );
+        block_on(spawn(fut.map_err(|e| panic!("on_sub fut panic {:?}", e)))).unwrap();
+        let sim = Sim::new(handler);
+
+        let (io1, io2) = PairIO::new();
+        let (req1, fut) = sim.add(io1);
+        block_on(spawn(fut.map_err(|e| panic!("io1 sim fut panic {:?}", e)))).unwrap();
+        let (_req2, fut) = sim.add(io2);
+        block_on(spawn(fut.map_err(|e| panic!("io2 sim fut panic {:?}", e)))).unwrap();
+
+        let (req1, resp, receiver) = block_on(req1.sub(topic.clone(), Bytes::new())).unwrap();
+        assert_eq!(resp, SubscriptionResponse::Accepted(Bytes::new()));
+
+        let bye = BytesMut::from(r"bye").freeze();
+        let (req1, resp) = block_on(req1.unsub(topic.clone(), bye.clone())).unwrap();
+        assert_eq!(resp, UnsubscriptionResponse::Accepted(bye));
+        let (notification, _) = block_on(receiver.unwrap().next())
+            .map_err(|(e, _)| e)
+            .unwrap();
+        assert_eq!(notification, None);
+
+        let (_, resp) = block_on(req1.unsub(topic, Bytes::new())).unwrap();
+        assert_eq!(resp, UnsubscriptionResponse::NotSubscribed);
+    }
+
+    #[test]
+    fn subscribe_twice() {
+        let handler = Handler::new();
+        let topic = BytesMut::from(r"topic").freeze();
+        let (sink, fut) = handler.on_subs(
+            topic.clone(),
+            Box::new(|req| Box::new(ok(SubscribeDecision::Accept(req)))),
+            Box::new(|req| Box::new(ok(req))),
+        );
+        block_on(spawn(fut.map_err(|e| panic!("on_sub fut panic {:?}", e)))).unwrap();
+        let sim = Sim::new(handler);
+
+        let (io1, io2) = PairIO::new();
+        let (req1, fut) = sim.add(io1);
+        block_on(spawn(fut.map_err(|e| panic!("io1 sim fut panic {:?}", e)))).unwrap();
+        let (_req2, fut) = sim.add(io2);
+        block_on(spawn(fut.map_err(|e| panic!("io2 sim fut panic {:?}", e)))).unwrap();
+
+        let (req1, resp, receiver) = block_on(req1.sub(topic.clone(), Bytes::new())).unwrap();
+        assert_eq!(resp, SubscriptionResponse::Accepted(Bytes::new()));
+        let (_, resp, again) = block_on(req1.sub(topic.clone(), Bytes::new())).unwrap();
+        assert_eq!(resp, SubscriptionResponse::AlreadySubscribed);
+        assert!(again.is_none());
+
+        let hello = BytesMut::from(r"hello").freeze();
+        block_on(sink.send((topic, hello.clone()))).unwrap();
+        let (notification, _) = block_on(receiver.unwrap().next())
+            .map_err(|(e, _)| e)
+            .unwrap();
+        assert_eq!(notification, Some(hello));
+    }
+
+    #[test]
+    fn reject_subscriber() {
+        let handler = Handler::new();
+        let topic = BytesMut::from(r"topic").freeze();
+        let (_, fut) = handler.on_subs(
+            topic.clone(),
+            Box::new(|req| Box::new(ok(SubscribeDecision::Reject(req)))),
+            Box::new(|req| Box::new(ok(req))),
+        );
+        block_on(spawn(fut.map_err(|e| panic!("on_sub fut panic {:?}", e)))).unwrap();
+        let sim = Sim::new(handler);
+
+        let (io1, io2) = PairIO::new();
+        let (req1, fut) = sim.add(io1);
+        block_on(spawn(fut.map_err(|e| panic!("io1 sim fut panic {:?}", e)))).unwrap();
+        let (_req2, fut) = sim.add(io2);
+        block_on(spawn(fut.map_err(|e| panic!("io2 sim fut panic {:?}", e)))).unwrap();
+
+        let denied = BytesMut::from(r"denied").freeze();
+        let (req1, resp, receiver) = block_on(req1.sub(topic.clone(), denied.clone())).unwrap();
+        assert_eq!(resp, SubscriptionResponse::Rejected(denied));
+        assert!(receiver.is_none());
+
+        let (_, resp) = block_on(req1.unsub(topic, Bytes::new())).unwrap();
+        assert_eq!(resp, UnsubscriptionResponse::NotSubscribed);
+    }
+
+    #[test]
+    fn retained_notify() {
+        let handler = Handler::new();
+        let topic = BytesMut::from(r"state").freeze();
+        let (sink, fut) = handler.on_subs_with_config(
+            topic.clone(),
+            Box::new(|req| Box::new(ok(SubscribeDecision::Accept(req)))),
+            Box::new(|req| Box::new(ok(req))),
+            SubscriptionConfig::new().retained(true),
+        );
+        block_on(spawn(fut.map_err(|e| panic!("on_sub fut panic {:?}", e)))).unwrap();
+        let sim = Sim::new(handler);
+
+        let (io1, io2) = PairIO::new();
+        let (req1, fut) = sim.add(io1);
+        block_on(spawn(fut.map_err(|e| panic!("io1 sim fut panic {:?}", e)))).unwrap();
+        let (_req2, fut) = sim.add(io2);
+        block_on(spawn(fut.map_err(|e| panic!("io2 sim fut panic {:?}", e)))).unwrap();
+
+        let on = BytesMut::from(r"on").freeze();
+        let off = BytesMut::from(r"off").freeze();
+        assert!(sim.handler().set_retained(&topic, topic.clone(), Some(on.clone())));
+        let other = BytesMut::from(r"other").freeze();
+        assert!(!sim.handler().set_retained(&other, other.clone(), None));
+        let (_, resp, receiver) = block_on(req1.sub(topic.clone(), Bytes::new())).unwrap();
+        assert_eq!(resp, SubscriptionResponse::Accepted(Bytes::new()));
+        let (notification, receiver) = block_on(receiver.unwrap().next())
+            .map_err(|(e, _)| e)
+            .unwrap();
+        assert_eq!(notification, Some(on));
+
+        block_on(sink.send((topic.clone(), off.clone()))).unwrap();
+        let (notification, _) = block_on(receiver.next()).map_err(|(e, _)| e).unwrap();
+        assert_eq!(notification, Some(off.clone()));
+        assert_eq!(sim.handler().retained(&topic, &topic), Some(off));
+
+        assert!(sim.handler().set_retained(&topic, topic.clone(), None));
+        assert_eq!(sim.handler().retained(&topic, &topic), None);
+    }
+
+    #[test]
+    fn resume_notify() {
+        use util::TempPath;
+
+        let path = TempPath::new("resume.log");
+        let log = NotificationLog::open(&path, Retention::new().max_count(10)).unwrap();
+        let first = BytesMut::from(r"1").freeze();
+        let second = BytesMut::from(r"2").freeze();
+        let third = BytesMut::from(r"3").freeze();
+        let topic = BytesMut::from(r"events").freeze();
+        assert_eq!(log.append(&topic, &first).unwrap(), 1);
+        assert_eq!(log.append(&topic, &second).unwrap(), 2);
+
+        let handler = Handler::new();
+        let (sink, fut) = handler.on_subs_with_config(
+            topic.clone(),
+            Box::new(|req| Box::new(ok(SubscribeDecision::Accept(req)))),
+            Box::new(|req| Box::new(ok(req))),
+            SubscriptionConfig::new().log(log.clone()),
+        );
+        block_on(spawn(fut.map_err(|e| panic!("on_sub fut panic {:?}", e)))).unwrap();
+        let sim = Sim::new(handler);
+
+        let (io1, io2) = PairIO::new();
+        let (req1, fut) = sim.add(io1);
+        block_on(spawn(fut.map_err(|e| panic!("io1 sim fut panic {:?}", e)))).unwrap();
+        let (_req2, fut) = sim.add(io2);
+        block_on(spawn(fut.map_err(|e| panic!("io2 sim fut panic {:?}", e)))).unwrap();
+
+        let (req1, resp, receiver) =
+            block_on(req1.sub_from(topic.clone(), Bytes::new(), 1)).unwrap();
+        assert_eq!(resp, SubscriptionResponse::Accepted(Bytes::new()));
+        block_on(sink.send((topic.clone(), third.clone()))).unwrap();
+        let notifications = block_on(receiver.unwrap().take(3).collect()).unwrap();
+        assert_eq!(notifications, vec![first, second, third]);
+        assert_eq!(req1.last_sequence(&topic), Some(3));
+        assert_eq!(log.range(), Some((1, 3)));
+    }
+
+    #[test]
+    fn filtered_notify() {
+        let handler = Handler::new();
+        let topic = BytesMut::from(r"prices").freeze();
+        let (sink, fut) = handler.on_subs(
+            topic.clone(),
+            Box::new(|req: Bytes| {
+                let filter = Filter::prefixes(vec![req]);
+                Box::new(ok(SubscribeDecision::AcceptFiltered(Bytes::new(), filter)))
+            }),
+            Box::new(|req| Box::new(ok(req))),
+        );
+        block_on(spawn(fut.map_err(|e| panic!("on_sub fut panic {:?}", e)))).unwrap();
+        let sim = Sim::new(handler);
+
+        let (io1, io2) = PairIO::new();
+        let (req1, fut) = sim.add(io1);
+        block_on(spawn(fut.map_err(|e| panic!("io1 sim fut panic {:?}", e)))).unwrap();
+        let (_req2, fut) = sim.add(io2);
+        block_on(spawn(fut.map_err(|e| panic!("io2 sim fut panic {:?}", e)))).unwrap();
+
+        let key = BytesMut::from(r"abc").freeze();
+        let (_, resp, receiver) = block_on(req1.sub(topic.clone(), key)).unwrap();
+        assert_eq!(resp, SubscriptionResponse::Accepted(Bytes::new()));
+
+        let other = BytesMut::from(r"xyz=1").freeze();
+        let matching = BytesMut::from(r"abc=2").freeze();
+        let sink = block_on(sink.send((topic.clone(), other))).unwrap();
+        block_on(sink.send((topic, matching.clone()))).unwrap();
+        let (notification, _) = block_on(receiver.unwrap().next())
+            .map_err(|(e, _)| e)
+            .unwrap();
+        assert_eq!(notification, Some(matching));
+    }
+
+    #[test]
+    fn unsubscribe_on_disconnect() {
+        use futures::channel::oneshot;
+        use parking_lot::Mutex;
+
+        let (reason_tx, reason_rx) = oneshot::channel();
+        let reason_tx = Mutex::new(Some(reason_tx));
+        let handler = Handler::new();
+        let topic = BytesMut::from(r"topic").freeze();
+        let (_, fut) = handler.on_subs(
+            topic.clone(),
+            Box::new(|req| Box::new(ok(SubscribeDecision::Accept(req)))),
+            Box::new(move |req| {
+                if let Some(tx) = reason_tx.lock().take() {
+                    let _ = tx.send(unsubscribe_reason());
+                }
+                Box::new(ok(req))
+            }),
+        );
+        block_on(spawn(fut.map_err(|e| panic!("on_sub fut panic {:?}", e)))).unwrap();
+        let sim = Sim::new(handler);
+
+        let (io1, io2) = PairIO::new();
+        let (req1, fut) = Sim::new(Handler::new()).add(io1);
+        let (kill, killed) = oneshot::channel::<()>();
+        let fut = fut.select(killed).then(|_| Ok::<_, Never>(()));
+        block_on(spawn(fut)).unwrap();
+        let (_, fut) = sim.add(io2);
+        block_on(spawn(fut.map_err(|e| panic!("io2 sim fut panic {:?}", e)))).unwrap();
+
+        let (_, resp, _) = block_on(req1.sub(topic, Bytes::new())).unwrap();
+        assert_eq!(resp, SubscriptionResponse::Accepted(Bytes::new()));
+
+        let _ = kill.send(());
+        let reason = block_on(reason_rx).unwrap();
+        assert_eq!(reason, Some(UnsubscribeReason::Disconnected));
+    }
 }
@@ -0,0 +1,360 @@
+use super::{Requestor, Sim, SubscriptionResponse};
+
+use bytes::Bytes;
+use crossbeam::sync::AtomicOption;
+use dialog::{Caller, Handler};
+use futures::channel::{mpsc, oneshot};
+use futures::future::{err, loop_fn, ok, Loop};
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::prelude::*;
+use parking_lot::{Mutex, RwLock};
+use std::cmp;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use util::Delay;
+
+/// Delays between failed connection attempts of a reconnecting `Requestor`.
+///
+/// The delay starts at `initial` and doubles after every failed attempt up
+/// to `max`. A connection which ends before staying up for `min_uptime`,
+/// `max` by default, counts as a failed attempt.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    max_attempts: Option<usize>,
+    min_uptime: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff {
+            initial,
+            max,
+            max_attempts: None,
+            min_uptime: max,
+        }
+    }
+
+    /// Reconnects right away only after connections which stayed up for `uptime`.
+    pub fn min_uptime(mut self, uptime: Duration) -> Backoff {
+        self.min_uptime = uptime;
+        self
+    }
+
+    /// Gives up after `attempts` consecutive failed connection attempts.
+    pub fn max_attempts(mut self, attempts: usize) -> Backoff {
+        self.max_attempts = Some(attempts);
+        self
+    }
+
+    fn delay(&self, failures: usize) -> Duration {
+        let factor = 1u32 << cmp::min(failures.saturating_sub(1), 16) as u32;
+        cmp::min(self.initial * factor, self.max)
+    }
+
+    fn exhausted(&self, failures: usize) -> bool {
+        self.max_attempts.map_or(false, |max| failures >= max)
+    }
+}
+
+/// Connection state changes of a reconnecting `Requestor`.
+#[derive(Debug)]
+pub enum ReconnectEvent {
+    /// A new connection is established.
+    Connected,
+    /// The connection ended, with the error if it failed.
+    Disconnected(Option<io::Error>),
+    /// Connecting failed, `attempt` counts the consecutive failures.
+    ConnectFailed { attempt: usize, error: io::Error },
+    /// An active subscription was replayed on the new connection.
+    Resubscribed(Bytes, SubscriptionResponse),
+}
+
+type CallerRequest = (usize, oneshot::Sender<Bytes>, Bytes);
+
+type ConnectionFuture = Box<Future<Item = Loop<(), usize>, Error = io::Error> + Send>;
+
+/// Caller channel shared by the successive connections of one `Caller`.
+#[derive(Clone)]
+struct SharedReceiver(Arc<Mutex<mpsc::Receiver<CallerRequest>>>);
+
+impl Stream for SharedReceiver {
+    type Item = CallerRequest;
+    type Error = Never;
+
+    fn poll_next(&mut self, cx: &mut task::Context) -> Poll<Option<Self::Item>, Self::Error> {
+        self.0.lock().poll_next(cx)
+    }
+}
+
+impl Sim {
+    /// Creates a `Requestor` which stays usable across connections.
+    ///
+    /// `connector` is called for every new connection. After a connection
+    /// ends a new one is established right away if it stayed up long enough,
+    /// failed attempts are retried according to `backoff`. Active
+    /// subscriptions are replayed on every new connection, so their receivers
+    /// keep working.
+    ///
+    /// The returned future drives the connections and fails once `backoff`
+    /// gives up.
+    pub fn add_reconnecting<C, Fut, A>(
+        &self,
+        mut connector: C,
+        backoff: Backoff,
+    ) -> (
+        Requestor,
+        mpsc::UnboundedReceiver<ReconnectEvent>,
+        impl Future<Item = (), Error = io::Error> + Send,
+    )
+    where
+        C: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Item = A, Error = io::Error> + Send + 'static,
+        A: AsyncRead + AsyncWrite + Send + Sync + 'static,
+    {
+        let (tx, rx) = mpsc::channel(1);
+        let caller = Caller::new(tx);
+        let caller_ch = SharedReceiver(Arc::new(Mutex::new(rx)));
+        let receiving_subs_map = Arc::new(RwLock::new(HashMap::new()));
+        let requestor = Requestor::new(caller.clone(), Arc::clone(&receiving_subs_map));
+        let (events, events_rx) = mpsc::unbounded();
+        let sim = Sim(Arc::clone(&self.0));
+
+        let connections = {
+            let requestor = requestor.clone();
+            loop_fn(0, move |failures| {
+                let sim = Sim(Arc::clone(&sim.0));
+                let caller = caller.clone();
+                let caller_ch = caller_ch.clone();
+                let receiving_subs_map = Arc::clone(&receiving_subs_map);
+                let requestor = requestor.clone();
+                let backoff = backoff.clone();
+                let events = events.clone();
+                connector().then(move |result| -> ConnectionFuture {
+                    let io = match result {
+                        Ok(io) => io,
+                        Err(error) => {
+                            let failures = failures + 1;
+                            if backoff.exhausted(failures) {
+                                return Box::new(err(error));
+                            }
+                            let event = ReconnectEvent::ConnectFailed {
+                                attempt: failures,
+                                error,
+                            };
+                            let _ = events.unbounded_send(event);
+                            return Box::new(
+                                Delay::new(backoff.delay(failures))
+                                    .map(move |_| Loop::Continue(failures))
+                                    .map_err(Never::never_into),
+                            );
+                        }
+                    };
+                    let _ = events.unbounded_send(ReconnectEvent::Connected);
+                    let connected = Instant::now();
+                    let caller_opt = Arc::new(AtomicOption::new());
+                    caller_opt.swap(caller.clone(), Ordering::Relaxed);
+                    let handler = Handler::new(
+                        io,
+                        caller_ch,
+                        sim.request_handler(&receiving_subs_map, &caller_opt),
+                    );
+                    let handler = sim.unsubscribe_on_exit(handler, caller);
+                    let resubscribe = {
+                        let events = events.clone();
+                        requestor
+                            .resubscribe()
+                            .map(move |(_, responses)| {
+                                for (topic, response) in responses {
+                                    let event = ReconnectEvent::Resubscribed(topic, response);
+                                    let _ = events.unbounded_send(event);
+                                }
+                            })
+                            .then(|_| Ok::<_, io::Error>(()))
+                    };
+                    let fut = handler
+                        .then(|result| Ok::<_, io::Error>(result))
+                        .join(resubscribe)
+                        .and_then(move |(result, _)| -> ConnectionFuture {
+                            let error = result.err();
+                            let event = ReconnectEvent::Disconnected(
+                                error.as_ref().map(|e| io::Error::new(e.kind(), e.to_string())),
+                            );
+                            let _ = events.unbounded_send(event);
+                            if connected.elapsed() >= backoff.min_uptime {
+                                return Box::new(ok(Loop::Continue(0)));
+                            }
+                            // Closed right away, such as by a peer refusing connections.
+                            let failures = failures + 1;
+                            if backoff.exhausted(failures) {
+                                return Box::new(err(error.unwrap_or_else(|| {
+                                    io::Error::new(
+                                        io::ErrorKind::ConnectionAborted,
+                                        "connection closed right away",
+                                    )
+                                })));
+                            }
+                            Box::new(
+                                Delay::new(backoff.delay(failures))
+                                    .map(move |_| Loop::Continue(failures))
+                                    .map_err(Never::never_into),
+                            )
+                        });
+                    Box::new(fut)
+                })
+            })
+        };
+        (requestor, events_rx, connections)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::executor::{block_on, spawn};
+    use sim::{Handler, SubscribeDecision};
+    use util::{OneEndIO, PairIO};
+
+    fn next_event(
+        events: mpsc::UnboundedReceiver<ReconnectEvent>,
+    ) -> (ReconnectEvent, mpsc::UnboundedReceiver<ReconnectEvent>) {
+        let (event, events) = block_on(events.next()).map_err(|(e, _)| e).unwrap();
+        (event.unwrap(), events)
+    }
+
+    #[test]
+    fn reconnect_and_resubscribe() {
+        let handler = Handler::new();
+        let topic = Bytes::from(&b"topic"[..]);
+        let (sink, fut) = handler.on_subs(
+            topic.clone(),
+            Box::new(|req| Box::new(ok(SubscribeDecision::Accept(req)))),
+            Box::new(|req| Box::new(ok(req))),
+        );
+        block_on(spawn(fut.map_err(|e| panic!("on_sub fut panic {:?}", e)))).unwrap();
+        let server = Arc::new(Sim::new(handler));
+
+        // The first attempt fails, every later one connects to `server`.
+        let kills = Arc::new(Mutex::new(Vec::new()));
+        let connector = {
+            let kills = Arc::clone(&kills);
+            let mut attempts = 0;
+            move || -> Box<Future<Item = OneEndIO, Error = io::Error> + Send> {
+                attempts += 1;
+                if attempts == 1 {
+                    return Box::new(err(io::Error::new(io::ErrorKind::Other, "refused")));
+                }
+                let (io1, io2) = PairIO::new();
+                let (_, fut) = server.add(io2);
+                let (kill, killed) = oneshot::channel::<()>();
+                kills.lock().push(kill);
+                let fut = fut.select(killed).then(|_| Ok(()));
+                Box::new(spawn(fut).map(move |_| io1).map_err(Never::never_into))
+            }
+        };
+
+        let client = Sim::new(Handler::new());
+        let backoff = Backoff::new(Duration::from_millis(1), Duration::from_millis(10));
+        let (requestor, events, fut) = client.add_reconnecting(connector, backoff);
+        block_on(spawn(fut.map_err(|e| panic!("reconnect fut panic {:?}", e)))).unwrap();
+
+        let (event, events) = next_event(events);
+        match event {
+            ReconnectEvent::ConnectFailed { attempt: 1, .. } => (),
+            e => panic!("unexpected event {:?}", e),
+        }
+        let (event, events) = next_event(events);
+        match event {
+            ReconnectEvent::Connected => (),
+            e => panic!("unexpected event {:?}", e),
+        }
+
+        let (_, resp, receiver) = block_on(requestor.sub(topic.clone(), Bytes::new())).unwrap();
+        assert_eq!(resp, SubscriptionResponse::Accepted(Bytes::new()));
+
+        let _ = kills.lock().remove(0).send(());
+        let (event, events) = next_event(events);
+        match event {
+            ReconnectEvent::Disconnected(_) => (),
+            e => panic!("unexpected event {:?}", e),
+        }
+        let (event, events) = next_event(events);
+        match event {
+            ReconnectEvent::Connected => (),
+            e => panic!("unexpected event {:?}", e),
+        }
+        let (event, _) = next_event(events);
+        match event {
+            ReconnectEvent::Resubscribed(ref t, SubscriptionResponse::Accepted(_)) if t == &topic => (),
+            e => panic!("unexpected event {:?}", e),
+        }
+
+        let hello = Bytes::from(&b"hello"[..]);
+        block_on(sink.send((topic, hello.clone()))).unwrap();
+        let (notification, _) = block_on(receiver.unwrap().next())
+            .map_err(|(e, _)| e)
+            .unwrap();
+        assert_eq!(notification, Some(hello));
+    }
+
+    #[test]
+    fn back_off_short_connections() {
+        let connects = Arc::new(Mutex::new(Vec::new()));
+        let connector = {
+            let connects = Arc::clone(&connects);
+            move || {
+                connects.lock().push(Instant::now());
+                // The other end is dropped, so the connection ends right away.
+                let (io1, _) = PairIO::new();
+                ok::<_, io::Error>(io1)
+            }
+        };
+
+        let client = Sim::new(Handler::new());
+        let backoff = Backoff::new(Duration::from_millis(20), Duration::from_millis(20));
+        let (_requestor, events, fut) = client.add_reconnecting(connector, backoff);
+        block_on(spawn(fut.map_err(|e| panic!("reconnect fut panic {:?}", e)))).unwrap();
+
+        let (event, events) = next_event(events);
+        match event {
+            ReconnectEvent::Connected => (),
+            e => panic!("unexpected event {:?}", e),
+        }
+        let (event, events) = next_event(events);
+        match event {
+            ReconnectEvent::Disconnected(_) => (),
+            e => panic!("unexpected event {:?}", e),
+        }
+        let (event, _) = next_event(events);
+        match event {
+            ReconnectEvent::Connected => (),
+            e => panic!("unexpected event {:?}", e),
+        }
+        let connects = connects.lock();
+        assert!(connects[1] - connects[0] >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn give_up_on_short_connections() {
+        let connects = Arc::new(Mutex::new(0));
+        let connector = {
+            let connects = Arc::clone(&connects);
+            move || {
+                *connects.lock() += 1;
+                let (io1, _) = PairIO::new();
+                ok::<_, io::Error>(io1)
+            }
+        };
+
+        let client = Sim::new(Handler::new());
+        let backoff =
+            Backoff::new(Duration::from_millis(1), Duration::from_millis(20)).max_attempts(2);
+        let (_requestor, _events, fut) = client.add_reconnecting(connector, backoff);
+        assert!(block_on(fut).is_err());
+        assert_eq!(*connects.lock(), 2);
+    }
+}
@@ -1,38 +1,112 @@
-use super::message::{Request, RequestType, Response, RpcResponse, SubscriptionResponse};
+use super::context;
+use super::log::{encode_sequence, RESUME_HEADER};
+use super::message::{
+    Headers, Request, RequestType, Response, RpcResponse, SubscriptionResponse,
+    UnsubscriptionResponse,
+};
 use bytes::{Bytes, BytesMut};
 use dialog::Caller;
 use futures::channel::mpsc;
+use futures::future::{ok, Either};
 use futures::prelude::*;
+use futures::stream;
 use parking_lot::RwLock;
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::io;
 use std::sync::Arc;
+use std::time::Instant;
+
+/// Active subscription of a `Requestor`.
+pub struct Subscription {
+    pub sender: mpsc::Sender<Bytes>,
+    /// Data and headers sent with the subscription request, kept to replay it.
+    pub data: Bytes,
+    pub headers: Headers,
+    /// Sequence number of the last logged notification received.
+    pub last_sequence: Option<u64>,
+}
+
+impl Subscription {
+    fn new(sender: mpsc::Sender<Bytes>, data: Bytes, headers: Headers) -> Subscription {
+        Subscription {
+            sender,
+            data,
+            headers,
+            last_sequence: None,
+        }
+    }
+}
+
+pub type Subscriptions = Arc<RwLock<HashMap<Bytes, Subscription>>>;
 
 #[derive(Clone)]
 pub struct Requestor {
     caller: Caller,
-    subs: Arc<RwLock<HashMap<Bytes, mpsc::Sender<Bytes>>>>,
+    subs: Subscriptions,
 }
 
 impl Requestor {
-    pub fn new(
-        caller: Caller,
-        subs: Arc<RwLock<HashMap<Bytes, mpsc::Sender<Bytes>>>>,
-    ) -> Requestor {
+    pub fn new(caller: Caller, subs: Subscriptions) -> Requestor {
         Requestor { caller, subs }
     }
 
+    /// Calls `topic` on the peer.
+    ///
+    /// When called while handling a request, the deadline of that request is
+    /// passed on.
     pub fn rpc(
         self,
         topic: Bytes,
         data: Bytes,
     ) -> impl Future<Item = (Requestor, RpcResponse), Error = io::Error> {
+        self.rpc_request(topic, data, context::deadline(), Headers::new())
+            .map(|(requestor, response, _)| (requestor, response))
+    }
+
+    /// Calls `topic` on the peer with `headers`, returning the headers of the response.
+    pub fn rpc_with_headers(
+        self,
+        topic: Bytes,
+        data: Bytes,
+        headers: Headers,
+    ) -> impl Future<Item = (Requestor, RpcResponse, Headers), Error = io::Error> {
+        self.rpc_request(topic, data, context::deadline(), headers)
+    }
+
+    /// Calls `topic` on the peer, which rejects the call once `deadline` has passed.
+    ///
+    /// An earlier deadline of the request being handled takes precedence.
+    pub fn rpc_with_deadline(
+        self,
+        topic: Bytes,
+        data: Bytes,
+        deadline: Instant,
+    ) -> impl Future<Item = (Requestor, RpcResponse), Error = io::Error> {
+        let deadline = match context::deadline() {
+            Some(inherited) if inherited < deadline => inherited,
+            _ => deadline,
+        };
+        self.rpc_request(topic, data, Some(deadline), Headers::new())
+            .map(|(requestor, response, _)| (requestor, response))
+    }
+
+    fn rpc_request(
+        self,
+        topic: Bytes,
+        data: Bytes,
+        deadline: Option<Instant>,
+        headers: Headers,
+    ) -> impl Future<Item = (Requestor, RpcResponse, Headers), Error = io::Error> {
         let mut request = BytesMut::new();
-        Request::new(RequestType::Rpc, topic, data).write(&mut request);
+        Request::new(RequestType::Rpc, topic, data)
+            .with_deadline(deadline)
+            .with_headers(headers)
+            .write(&mut request);
         let Requestor { caller, subs } = self;
         caller.call(request.freeze()).map(|(caller, response)| {
-            let response = Response::from_bytes(response);
-            (Self::new(caller, subs), response.into())
+            let (response, headers) = Response::from_bytes_with_headers(response);
+            (Self::new(caller, subs), response.into(), headers)
         })
     }
 
@@ -48,20 +122,151 @@ impl Requestor {
         ),
         Error = io::Error,
     > {
+        self.sub_with_headers(topic, data, Headers::new())
+    }
+
+    /// Subscribes to `topic`, first receiving the notifications logged by the
+    /// peer from sequence number `sequence` on.
+    ///
+    /// Topics without a log only send the later notifications. Resubscribing
+    /// resumes from the last logged notification received.
+    pub fn sub_from(
+        self,
+        topic: Bytes,
+        data: Bytes,
+        sequence: u64,
+    ) -> impl Future<
+        Item = (
+            Requestor,
+            SubscriptionResponse,
+            Option<mpsc::Receiver<Bytes>>,
+        ),
+        Error = io::Error,
+    > {
+        let mut headers = Headers::new();
+        headers.insert(Bytes::from_static(RESUME_HEADER), encode_sequence(sequence));
+        self.sub_with_headers(topic, data, headers)
+    }
+
+    /// Subscribes to `topic` with `headers`, which are sent again on every resubscription.
+    ///
+    /// A topic which is subscribed, or being subscribed, is answered with
+    /// `AlreadySubscribed` without asking the peer.
+    pub fn sub_with_headers(
+        self,
+        topic: Bytes,
+        data: Bytes,
+        headers: Headers,
+    ) -> impl Future<
+        Item = (
+            Requestor,
+            SubscriptionResponse,
+            Option<mpsc::Receiver<Bytes>>,
+        ),
+        Error = io::Error,
+    > {
+        // Registered beforehand, as notifications may arrive before the response.
+        let (ch_sink, ch_stream) = mpsc::channel(1);
+        let registered = match self.subs.write().entry(topic.clone()) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(Subscription::new(ch_sink, data.clone(), headers.clone()));
+                true
+            }
+        };
+        if !registered {
+            let response = (self, SubscriptionResponse::AlreadySubscribed, None);
+            return Either::A(ok::<_, io::Error>(response));
+        }
+        let subs = Arc::clone(&self.subs);
+        Either::B(self.subscribe(topic.clone(), data, headers).then(move |result| {
+            let accepted = match result {
+                Ok((_, SubscriptionResponse::Accepted(_))) => true,
+                _ => false,
+            };
+            if !accepted {
+                subs.write().remove(&topic);
+                let (requestor, response) = result?;
+                return Ok((requestor, response, None));
+            }
+            let (requestor, response) = result?;
+            Ok((requestor, response, Some(ch_stream)))
+        }))
+    }
+
+    /// Returns the sequence number of the last logged notification received on `topic`.
+    pub fn last_sequence(&self, topic: &Bytes) -> Option<u64> {
+        self.subs.read().get(topic)?.last_sequence
+    }
+
+    /// Unsubscribes from `topic`, ending the receiver of the subscription.
+    pub fn unsub(
+        self,
+        topic: Bytes,
+        data: Bytes,
+    ) -> impl Future<Item = (Requestor, UnsubscriptionResponse), Error = io::Error> {
         let mut request = BytesMut::new();
-        Request::new(RequestType::Subscription, topic.clone(), data).write(&mut request);
+        Request::new(RequestType::Unsubscription, topic.clone(), data).write(&mut request);
         let Requestor { caller, subs } = self;
-        caller.call(request.freeze()).map(|(caller, response)| {
+        caller.call(request.freeze()).map(move |(caller, response)| {
+            subs.write().remove(&topic);
             let response = Response::from_bytes(response).into();
-            let chan = if let SubscriptionResponse::Accepted(_) = response {
-                let mut subs = subs.write();
-                let (ch_sink, ch_stream) = mpsc::channel(1);
-                subs.insert(topic, ch_sink);
-                Some(ch_stream)
-            } else {
-                None
-            };
-            (Self::new(caller, subs), response, chan)
+            (Self::new(caller, subs), response)
+        })
+    }
+
+    /// Sends the subscription requests of all active subscriptions again.
+    ///
+    /// Existing receivers keep working for accepted subscriptions. The others
+    /// are removed, which ends their receivers.
+    pub fn resubscribe(
+        self,
+    ) -> impl Future<Item = (Requestor, Vec<(Bytes, SubscriptionResponse)>), Error = io::Error>
+    {
+        let subs: Vec<_> = self
+            .subs
+            .read()
+            .iter()
+            .map(|(topic, sub)| {
+                let mut headers = sub.headers.clone();
+                if let Some(seq) = sub.last_sequence {
+                    headers.insert(Bytes::from_static(RESUME_HEADER), encode_sequence(seq + 1));
+                }
+                (topic.clone(), sub.data.clone(), headers)
+            })
+            .collect();
+        stream::iter_ok(subs).fold(
+            (self, Vec::new()),
+            |(requestor, mut responses), (topic, data, headers)| {
+                requestor
+                    .subscribe(topic.clone(), data, headers)
+                    .map(move |(requestor, response)| {
+                        match response {
+                            SubscriptionResponse::Accepted(_) => (),
+                            _ => {
+                                requestor.subs.write().remove(&topic);
+                            }
+                        }
+                        responses.push((topic, response));
+                        (requestor, responses)
+                    })
+            },
+        )
+    }
+
+    fn subscribe(
+        self,
+        topic: Bytes,
+        data: Bytes,
+        headers: Headers,
+    ) -> impl Future<Item = (Requestor, SubscriptionResponse), Error = io::Error> {
+        let mut request = BytesMut::new();
+        Request::new(RequestType::Subscription, topic, data)
+            .with_headers(headers)
+            .write(&mut request);
+        let Requestor { caller, subs } = self;
+        caller.call(request.freeze()).map(|(caller, response)| {
+            (Self::new(caller, subs), Response::from_bytes(response).into())
         })
     }
 }
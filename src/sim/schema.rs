@@ -0,0 +1,57 @@
+//! Introspection of the services of a peer.
+
+use super::{Handler, Requestor, RpcResponse};
+
+use bytes::Bytes;
+use futures::future::ok;
+use futures::prelude::*;
+use std::io;
+
+/// RPC topic on which a peer serves the schema of its services.
+pub const SCHEMA_TOPIC: &str = "$schema";
+
+impl Handler {
+    /// Serves `schema` on `SCHEMA_TOPIC`, replacing any schema served before.
+    ///
+    /// The schema is usually the `SCHEMA` generated by `simproto-codegen`.
+    pub fn serve_schema(&self, schema: Bytes) {
+        self.on_rpc(
+            Bytes::from_static(SCHEMA_TOPIC.as_bytes()),
+            Box::new(move |_| Box::new(ok(schema.clone()))),
+        );
+    }
+}
+
+impl Requestor {
+    /// Asks the peer for the schema of its services.
+    ///
+    /// A peer serving no schema answers with `RpcResponse::TopicNotFound`.
+    pub fn schema(self) -> impl Future<Item = (Requestor, RpcResponse), Error = io::Error> {
+        self.rpc(Bytes::from_static(SCHEMA_TOPIC.as_bytes()), Bytes::new())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::executor::{block_on, spawn};
+    use sim::Sim;
+    use util::PairIO;
+
+    #[test]
+    fn introspect_schema() {
+        let schema = Bytes::from_static(b"service Echo {\n    rpc echo(bytes) -> bytes;\n}\n");
+        let handler = Handler::new();
+        handler.serve_schema(schema.clone());
+        let sim = Sim::new(handler);
+
+        let (io1, io2) = PairIO::new();
+        let (req1, fut) = sim.add(io1);
+        block_on(spawn(fut.map_err(|e| panic!("io1 sim fut panic {:?}", e)))).unwrap();
+        let (_, fut) = sim.add(io2);
+        block_on(spawn(fut.map_err(|e| panic!("io2 sim fut panic {:?}", e)))).unwrap();
+
+        let (_, resp) = block_on(req1.schema()).unwrap();
+        assert_eq!(resp, RpcResponse::Accepted(schema));
+    }
+}
@@ -0,0 +1,172 @@
+use bytes::Bytes;
+use std::collections::HashMap;
+
+/// Separator of the levels of a hierarchical topic.
+pub const SEPARATOR: u8 = b'/';
+/// Pattern level matching exactly one level of a topic.
+pub const SINGLE_LEVEL: &[u8] = b"+";
+/// Last pattern level matching all remaining levels of a topic, if any.
+pub const MULTI_LEVEL: &[u8] = b"#";
+
+/// Values registered under topic patterns.
+///
+/// A pattern is a topic whose levels may be wildcards, `+` for a single level
+/// and `#` as the last level for all remaining ones. A topic is routed to the
+/// most specific matching pattern: levels are compared from the first one, a
+/// literal level being more specific than `+`, itself more specific than `#`.
+pub struct TopicMap<V> {
+    entries: HashMap<Bytes, V>,
+}
+
+/// Pattern matched by a topic.
+pub struct Match<V> {
+    pub pattern: Bytes,
+    pub value: V,
+    /// Levels matched by the wildcards of the pattern, in order. A `#` matches
+    /// the remaining levels joined by their separators.
+    pub segments: Vec<Bytes>,
+}
+
+impl<V> Match<V> {
+    pub fn map<U, F: FnOnce(V) -> U>(self, f: F) -> Match<U> {
+        Match {
+            pattern: self.pattern,
+            value: f(self.value),
+            segments: self.segments,
+        }
+    }
+}
+
+impl<V> TopicMap<V> {
+    pub fn new() -> TopicMap<V> {
+        TopicMap {
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, pattern: Bytes, value: V) -> Option<V> {
+        self.entries.insert(pattern, value)
+    }
+
+    pub fn remove(&mut self, pattern: &Bytes) -> Option<V> {
+        self.entries.remove(pattern)
+    }
+
+    /// Returns the most specific pattern matching `topic`.
+    pub fn get(&self, topic: &Bytes) -> Option<Match<&V>> {
+        if !is_pattern(topic) {
+            if let Some(value) = self.entries.get(topic) {
+                return Some(Match {
+                    pattern: topic.clone(),
+                    value,
+                    segments: Vec::new(),
+                });
+            }
+        }
+        self.entries
+            .iter()
+            .filter_map(|(pattern, value)| {
+                let segments = matches(pattern, topic)?;
+                Some((specificity(pattern), pattern, value, segments))
+            })
+            .min_by(|a, b| a.0.cmp(&b.0))
+            .map(|(_, pattern, value, segments)| Match {
+                pattern: pattern.clone(),
+                value,
+                segments,
+            })
+    }
+}
+
+fn levels(topic: &[u8]) -> impl Iterator<Item = &[u8]> {
+    topic.split(|&b| b == SEPARATOR)
+}
+
+fn is_pattern(pattern: &[u8]) -> bool {
+    levels(pattern).any(|level| level == SINGLE_LEVEL || level == MULTI_LEVEL)
+}
+
+/// Ranks of the levels of `pattern`, lower ones being more specific.
+fn specificity(pattern: &[u8]) -> Vec<u8> {
+    levels(pattern)
+        .map(|level| {
+            if level == SINGLE_LEVEL {
+                1
+            } else if level == MULTI_LEVEL {
+                2
+            } else {
+                0
+            }
+        })
+        .collect()
+}
+
+/// Returns the levels of `topic` matched by the wildcards of `pattern`, or
+/// `None` if `pattern` doesn't match `topic`.
+pub fn matches(pattern: &Bytes, topic: &Bytes) -> Option<Vec<Bytes>> {
+    let mut segments = Vec::new();
+    let mut topic_levels = levels(topic);
+    let mut offset = 0;
+    for level in levels(pattern) {
+        if level == MULTI_LEVEL {
+            let rest = if offset > topic.len() {
+                Bytes::new()
+            } else {
+                topic.slice_from(offset)
+            };
+            segments.push(rest);
+            return Some(segments);
+        }
+        let topic_level = topic_levels.next()?;
+        if level == SINGLE_LEVEL {
+            segments.push(topic.slice(offset, offset + topic_level.len()));
+        } else if level != topic_level {
+            return None;
+        }
+        offset += topic_level.len() + 1;
+    }
+    match topic_levels.next() {
+        Some(_) => None,
+        None => Some(segments),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn topic(s: &str) -> Bytes {
+        Bytes::from(s.as_bytes())
+    }
+
+    #[test]
+    fn wildcard_segments() {
+        assert_eq!(
+            matches(&topic("sensor/+/temp"), &topic("sensor/1/temp")),
+            Some(vec![topic("1")])
+        );
+        assert_eq!(matches(&topic("sensor/+/temp"), &topic("sensor/1/hum")), None);
+        assert_eq!(matches(&topic("sensor/+"), &topic("sensor/1/temp")), None);
+        assert_eq!(
+            matches(&topic("sensor/#"), &topic("sensor/1/temp")),
+            Some(vec![topic("1/temp")])
+        );
+        assert_eq!(matches(&topic("sensor/#"), &topic("sensor")), Some(vec![topic("")]));
+        assert_eq!(matches(&topic("sensor/#"), &topic("actuator/1")), None);
+    }
+
+    #[test]
+    fn most_specific_pattern() {
+        let mut map = TopicMap::new();
+        map.insert(topic("sensor/#"), 0);
+        map.insert(topic("sensor/+/temp"), 1);
+        map.insert(topic("sensor/+/+"), 2);
+        map.insert(topic("sensor/1/temp"), 3);
+
+        assert_eq!(*map.get(&topic("sensor/1/temp")).unwrap().value, 3);
+        assert_eq!(*map.get(&topic("sensor/2/temp")).unwrap().value, 1);
+        assert_eq!(*map.get(&topic("sensor/2/hum")).unwrap().value, 2);
+        assert_eq!(*map.get(&topic("sensor/2")).unwrap().value, 0);
+        assert!(map.get(&topic("actuator/2")).is_none());
+    }
+}
@@ -0,0 +1,364 @@
+//! Typed RPC and subscriptions, with messages serialized by serde.
+//!
+//! Messages are encoded with a `Format`, `Json` or `Bincode`, which both peers
+//! must agree on. A request which fails to decode is answered with an invalid
+//! data response, such as `RpcResponse::InvalidData`, holding the error.
+
+use super::{
+    Handler, InvalidRequest, Requestor, RpcResponse, SubscribeDecision, SubscriptionResponse,
+};
+
+use bincode;
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::future::{err, Either};
+use futures::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json;
+use std::error::Error;
+use std::marker::PhantomData;
+use std::{fmt, io};
+
+/// Wire format of typed messages.
+pub trait Format: Clone + Send + Sync + 'static {
+    fn encode<T: Serialize>(&self, value: &T) -> io::Result<Bytes>;
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> io::Result<T>;
+}
+
+/// Messages encoded as JSON.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Json;
+
+impl Format for Json {
+    fn encode<T: Serialize>(&self, value: &T) -> io::Result<Bytes> {
+        serde_json::to_vec(value)
+            .map(Bytes::from)
+            .map_err(invalid_data)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> io::Result<T> {
+        serde_json::from_slice(data).map_err(invalid_data)
+    }
+}
+
+/// Messages encoded with the compact binary format of bincode.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bincode;
+
+impl Format for Bincode {
+    fn encode<T: Serialize>(&self, value: &T) -> io::Result<Bytes> {
+        bincode::serialize(value)
+            .map(Bytes::from)
+            .map_err(invalid_data)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> io::Result<T> {
+        bincode::deserialize(data).map_err(invalid_data)
+    }
+}
+
+fn invalid_data<E: Into<Box<Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Error of a typed notification sink.
+#[derive(Debug)]
+pub enum NotifyError {
+    /// The notification could not be encoded.
+    Encode(io::Error),
+    /// The notification could not be sent.
+    Send(mpsc::SendError),
+}
+
+impl From<mpsc::SendError> for NotifyError {
+    fn from(e: mpsc::SendError) -> NotifyError {
+        NotifyError::Send(e)
+    }
+}
+
+impl fmt::Display for NotifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NotifyError::Encode(ref e) => write!(f, "failed to encode notification: {}", e),
+            NotifyError::Send(ref e) => write!(f, "failed to send notification: {}", e),
+        }
+    }
+}
+
+impl Error for NotifyError {
+    fn description(&self) -> &str {
+        match *self {
+            NotifyError::Encode(_) => "failed to encode notification",
+            NotifyError::Send(_) => "failed to send notification",
+        }
+    }
+}
+
+/// Notifications of a typed subscription.
+///
+/// A notification which fails to decode is yielded as an error, the stream
+/// goes on with the next one.
+pub struct Notifications<T, F> {
+    receiver: mpsc::Receiver<Bytes>,
+    format: F,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T, F> Stream for Notifications<T, F>
+where
+    T: DeserializeOwned,
+    F: Format,
+{
+    type Item = T;
+    type Error = io::Error;
+
+    fn poll_next(&mut self, cx: &mut task::Context) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.receiver.poll_next(cx) {
+            Ok(Async::Ready(Some(data))) => self.format.decode(&data).map(|x| Async::Ready(Some(x))),
+            Ok(Async::Ready(None)) => Ok(Async::Ready(None)),
+            Ok(Async::Pending) => Ok(Async::Pending),
+            Err(e) => e.never_into(),
+        }
+    }
+}
+
+impl Handler {
+    /// Handles RPC on `topic` with requests and responses encoded in `format`.
+    pub fn on_rpc_typed<Req, Resp, F, H>(&self, topic: Bytes, format: F, handler: H)
+    where
+        Req: DeserializeOwned,
+        Resp: Serialize,
+        F: Format,
+        H: Fn(Req) -> Box<Future<Item = Resp, Error = io::Error> + Send + Sync>,
+        H: Send + Sync + 'static,
+    {
+        self.on_rpc(
+            topic,
+            Box::new(move |data: Bytes| -> Box<Future<Item = _, Error = _> + Send + Sync> {
+                let request = match format.decode(&data) {
+                    Ok(request) => request,
+                    Err(e) => return Box::new(err(InvalidRequest::error(e))),
+                };
+                let format = format.clone();
+                Box::new(handler(request).and_then(move |response| format.encode(&response)))
+            }),
+        );
+    }
+
+    /// Handles subscriptions to the topics matching `topic` with messages encoded in `format`.
+    ///
+    /// Subscribe and unsubscribe requests are of type `Req`. The subscribe handler accepts a
+    /// subscriber with `Ok` and rejects it with `Err`, both answered with data of type `Resp`.
+    /// Notifications of type `N` are sent to the returned sink with the topic they are for, as
+    /// with `on_subs`.
+    pub fn on_subs_typed<Req, Resp, N, F, S, U>(
+        &self,
+        topic: Bytes,
+        format: F,
+        sub_handler: S,
+        unsub_handler: U,
+    ) -> (
+        impl Sink<SinkItem = (Bytes, N), SinkError = NotifyError> + Send + Sync,
+        impl Future<Item = (), Error = Never> + Send + Sync,
+    )
+    where
+        Req: DeserializeOwned,
+        Resp: Serialize,
+        N: Serialize,
+        F: Format,
+        S: Fn(Req) -> Box<Future<Item = Result<Resp, Resp>, Error = io::Error> + Send + Sync>,
+        S: Send + Sync + 'static,
+        U: Fn(Req) -> Box<Future<Item = Resp, Error = io::Error> + Send + Sync>,
+        U: Send + Sync + 'static,
+    {
+        let sub_handler = {
+            let format = format.clone();
+            move |data: Bytes| -> Box<Future<Item = _, Error = _> + Send + Sync> {
+                let request = match format.decode(&data) {
+                    Ok(request) => request,
+                    Err(e) => return Box::new(err(InvalidRequest::error(e))),
+                };
+                let format = format.clone();
+                Box::new(sub_handler(request).and_then(move |decision| match decision {
+                    Ok(x) => format.encode(&x).map(SubscribeDecision::Accept),
+                    Err(x) => format.encode(&x).map(SubscribeDecision::Reject),
+                }))
+            }
+        };
+        let unsub_handler = {
+            let format = format.clone();
+            move |data: Bytes| -> Box<Future<Item = _, Error = _> + Send + Sync> {
+                let request = match format.decode(&data) {
+                    Ok(request) => request,
+                    Err(e) => return Box::new(err(InvalidRequest::error(e))),
+                };
+                let format = format.clone();
+                Box::new(unsub_handler(request).and_then(move |x| format.encode(&x)))
+            }
+        };
+        let (sink, fut) = self.on_subs(topic, Box::new(sub_handler), Box::new(unsub_handler));
+        let sink = sink.with(move |(topic, notification): (Bytes, N)| {
+            format
+                .encode(&notification)
+                .map(|notification| (topic, notification))
+                .map_err(NotifyError::Encode)
+        });
+        (sink, fut)
+    }
+}
+
+impl Requestor {
+    /// Calls `topic` on the peer with `request` encoded in `format`.
+    ///
+    /// An accepted call gives the decoded response, any other response is
+    /// returned as is. A response which fails to decode is an error.
+    pub fn rpc_typed<Req, Resp, F>(
+        self,
+        topic: Bytes,
+        format: F,
+        request: &Req,
+    ) -> impl Future<Item = (Requestor, Result<Resp, RpcResponse>), Error = io::Error>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+        F: Format,
+    {
+        let rpc = match format.encode(request) {
+            Ok(data) => Either::A(self.rpc(topic, data)),
+            Err(e) => Either::B(err(e)),
+        };
+        rpc.and_then(move |(requestor, response)| match response {
+            RpcResponse::Accepted(x) => format.decode(&x).map(|x| (requestor, Ok(x))),
+            response => Ok((requestor, Err(response))),
+        })
+    }
+
+    /// Subscribes to `topic` with `request` encoded in `format`.
+    ///
+    /// An accepted subscription gives the decoded response and its
+    /// notifications of type `N`, any other response is returned as is. The
+    /// data of a rejection can be decoded with `Format::decode`.
+    pub fn sub_typed<Req, Resp, N, F>(
+        self,
+        topic: Bytes,
+        format: F,
+        request: &Req,
+    ) -> impl Future<
+        Item = (
+            Requestor,
+            Result<(Resp, Notifications<N, F>), SubscriptionResponse>,
+        ),
+        Error = io::Error,
+    >
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+        N: DeserializeOwned,
+        F: Format,
+    {
+        let sub = match format.encode(request) {
+            Ok(data) => Either::A(self.sub(topic, data)),
+            Err(e) => Either::B(err(e)),
+        };
+        sub.and_then(move |(requestor, response, receiver)| match (response, receiver) {
+            (SubscriptionResponse::Accepted(x), Some(receiver)) => {
+                let response = format.decode(&x)?;
+                let notifications = Notifications {
+                    receiver,
+                    format,
+                    _marker: PhantomData,
+                };
+                Ok((requestor, Ok((response, notifications))))
+            }
+            (response, _) => Ok((requestor, Err(response))),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::executor::{block_on, spawn};
+    use futures::future::ok;
+    use sim::Sim;
+    use util::PairIO;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Add {
+        a: u32,
+        b: u32,
+    }
+
+    fn connect(handler: Handler) -> Requestor {
+        let sim = Sim::new(handler);
+        let (io1, io2) = PairIO::new();
+        let (req1, fut) = sim.add(io1);
+        block_on(spawn(fut.map_err(|e| panic!("io1 sim fut panic {:?}", e)))).unwrap();
+        let (_, fut) = sim.add(io2);
+        block_on(spawn(fut.map_err(|e| panic!("io2 sim fut panic {:?}", e)))).unwrap();
+        req1
+    }
+
+    fn typed_rpc<F: Format>(format: F) {
+        let handler = Handler::new();
+        let topic = Bytes::from(&b"add"[..]);
+        handler.on_rpc_typed(topic.clone(), format.clone(), |req: Add| {
+            Box::new(ok(req.a + req.b))
+        });
+        let req1 = connect(handler);
+
+        let add = Add { a: 1, b: 2 };
+        let (req1, resp) = block_on(req1.rpc_typed(topic.clone(), format.clone(), &add)).unwrap();
+        assert_eq!(resp, Ok(3u32));
+
+        let (_, resp) = block_on(req1.rpc_typed::<_, u32, _>(topic, format, &"add")).unwrap();
+        match resp {
+            Err(RpcResponse::InvalidData(_)) => (),
+            resp => panic!("unexpected response {:?}", resp),
+        }
+    }
+
+    #[test]
+    fn json_rpc() {
+        typed_rpc(Json);
+    }
+
+    #[test]
+    fn bincode_rpc() {
+        typed_rpc(Bincode);
+    }
+
+    #[test]
+    fn typed_notify() {
+        let handler = Handler::new();
+        let topic = Bytes::from(&b"counter"[..]);
+        let (sink, fut) = handler.on_subs_typed(
+            topic.clone(),
+            Json,
+            |start: u32| Box::new(ok(if start > 0 { Ok(start) } else { Err(0) })),
+            |_: u32| Box::new(ok(0u32)),
+        );
+        block_on(spawn(fut.map_err(|e| panic!("on_sub fut panic {:?}", e)))).unwrap();
+        let req1 = connect(handler);
+
+        let (req1, resp) = block_on(req1.sub_typed::<_, u32, Add, _>(topic.clone(), Json, &0u32))
+            .unwrap();
+        match resp {
+            Err(SubscriptionResponse::Rejected(x)) => assert_eq!(Json.decode::<u32>(&x).unwrap(), 0),
+            _ => panic!("subscription not rejected"),
+        }
+
+        let (_, resp) =
+            block_on(req1.sub_typed::<_, u32, Add, _>(topic.clone(), Json, &1u32)).unwrap();
+        let (start, notifications) = resp.unwrap();
+        assert_eq!(start, 1);
+
+        block_on(sink.send((topic, Add { a: 1, b: 2 }))).unwrap();
+        let (notification, _) = block_on(notifications.next())
+            .map_err(|(e, _)| e)
+            .unwrap();
+        assert_eq!(notification, Some(Add { a: 1, b: 2 }));
+    }
+}
@@ -1,3 +1,10 @@
+mod pair_datagram;
 mod pair_io;
+#[cfg(test)]
+mod temp_path;
 
+pub use framed::Delay;
+pub use self::pair_datagram::{OneEndDatagram, PairDatagram};
 pub use self::pair_io::{OneEndIO, PairIO};
+#[cfg(test)]
+pub use self::temp_path::TempPath;
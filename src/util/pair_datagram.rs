@@ -0,0 +1,71 @@
+use bytes::Bytes;
+use framed::datagram::AsyncDatagram;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::prelude::*;
+use std::cmp;
+use std::io;
+
+/// One end of an in-memory datagram socket pair.
+///
+/// Receiving fails with `BrokenPipe` once the other end is dropped.
+pub struct OneEndDatagram {
+    in_ch: UnboundedReceiver<Bytes>,
+    out_ch: UnboundedSender<Bytes>,
+}
+
+impl AsyncDatagram for OneEndDatagram {
+    type Addr = ();
+
+    fn poll_recv_from(
+        &mut self,
+        cx: &mut task::Context,
+        buf: &mut [u8],
+    ) -> Poll<(usize, ()), io::Error> {
+        match self.in_ch.poll_next(cx) {
+            Ok(Async::Ready(Some(datagram))) => {
+                let len = cmp::min(datagram.len(), buf.len());
+                buf[..len].copy_from_slice(&datagram[..len]);
+                Ok(Async::Ready((len, ())))
+            }
+            Ok(Async::Pending) => Ok(Async::Pending),
+            Ok(Async::Ready(None)) | Err(_) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "other end closed",
+            )),
+        }
+    }
+
+    fn poll_send_to(
+        &mut self,
+        _: &mut task::Context,
+        buf: &[u8],
+        _: Option<&()>,
+    ) -> Poll<usize, io::Error> {
+        match self.out_ch.unbounded_send(Bytes::from(buf)) {
+            Ok(()) => Ok(Async::Ready(buf.len())),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "other end closed",
+            )),
+        }
+    }
+}
+
+pub struct PairDatagram;
+
+impl PairDatagram {
+    pub fn new() -> (OneEndDatagram, OneEndDatagram) {
+        let (out_ch1, in_ch1) = unbounded();
+        let (out_ch2, in_ch2) = unbounded();
+        (
+            OneEndDatagram {
+                in_ch: in_ch1,
+                out_ch: out_ch2,
+            },
+            OneEndDatagram {
+                in_ch: in_ch2,
+                out_ch: out_ch1,
+            },
+        )
+    }
+}
@@ -0,0 +1,45 @@
+use std::env;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+static NEXT_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Path of a file in the temporary directory, removed when dropped.
+///
+/// Paths are unique to the process and the call, so tests running at the
+/// same time don't share their files.
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    /// Returns a new path ending with `name`, such as `server.sock`.
+    pub fn new(name: &str) -> TempPath {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let file_name = format!("simproto-{}-{}-{}", process::id(), id, name);
+        let path = env::temp_dir().join(file_name);
+        let _ = fs::remove_file(&path);
+        TempPath(path)
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
@@ -0,0 +1,77 @@
+#![cfg(feature = "service")]
+extern crate futures;
+#[macro_use]
+extern crate serde_derive;
+extern crate simproto;
+extern crate simproto_codegen;
+
+use futures::executor::{block_on, spawn};
+use futures::future::ok;
+use futures::prelude::*;
+use futures::stream;
+use simproto::sim::typed::Json;
+use simproto::sim::{Handler, RpcResponse, Sim, SCHEMA_TOPIC};
+use simproto::util::PairIO;
+use std::io;
+
+// Generated from `codegen/calculator.simproto`, checked to be up to date by `current_bindings`.
+include!("codegen/calculator.rs");
+
+#[test]
+fn current_bindings() {
+    let code = simproto_codegen::generate(include_str!("codegen/calculator.simproto")).unwrap();
+    assert_eq!(code, include_str!("codegen/calculator.rs"));
+}
+
+struct Adder;
+
+impl Calculator for Adder {
+    fn add(&self, request: Add) -> Box<Future<Item = u32, Error = io::Error> + Send + Sync> {
+        Box::new(ok(request.a + request.b))
+    }
+
+    fn zero(&self) -> Box<Future<Item = u32, Error = io::Error> + Send + Sync> {
+        Box::new(ok(0))
+    }
+
+    fn readings(&self) -> Box<Stream<Item = Reading, Error = Never> + Send> {
+        let reading = Reading {
+            sensor: "temp".to_string(),
+            values: vec![Some(1.5), None],
+            raw: vec![1, 2],
+        };
+        Box::new(stream::iter_ok(vec![reading]))
+    }
+}
+
+#[test]
+fn generated_service() {
+    let handler = Handler::new();
+    let fut = register_calculator(&handler, Adder, Json);
+    handler.serve_schema(SCHEMA.into());
+    let sim = Sim::new(handler);
+
+    let (io1, io2) = PairIO::new();
+    let (req1, fut1) = sim.add(io1);
+    block_on(spawn(fut1.map_err(|e| panic!("io1 sim fut panic {:?}", e)))).unwrap();
+    let (_, fut2) = sim.add(io2);
+    block_on(spawn(fut2.map_err(|e| panic!("io2 sim fut panic {:?}", e)))).unwrap();
+
+    let client = CalculatorClient::new(req1, Json);
+    let (client, sum) = block_on(client.add(&Add { a: 1, b: 2 })).unwrap();
+    assert_eq!(sum, Ok(3));
+    let (client, zero) = block_on(client.zero()).unwrap();
+    assert_eq!(zero, Ok(0));
+
+    let (client, readings) = block_on(client.readings()).unwrap();
+    block_on(spawn(fut)).unwrap();
+    let (reading, _) = block_on(readings.unwrap().next()).map_err(|(e, _)| e).unwrap();
+    let reading = reading.unwrap();
+    assert_eq!(reading.sensor, "temp");
+    assert_eq!(reading.values, vec![Some(1.5), None]);
+    assert_eq!(reading.raw, vec![1, 2]);
+
+    let requestor = client.into_inner();
+    let (_, resp) = block_on(requestor.rpc(SCHEMA_TOPIC.into(), Default::default())).unwrap();
+    assert_eq!(resp, RpcResponse::Accepted(SCHEMA.into()));
+}
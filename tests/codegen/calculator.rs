@@ -0,0 +1,24 @@
+// Generated by simproto-codegen, do not edit.
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Add {
+    pub a: u32,
+    pub b: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Reading {
+    pub sensor: String,
+    pub values: Vec<Option<f64>>,
+    pub raw: Vec<u8>,
+}
+
+#[simproto::service]
+pub trait Calculator {
+    fn add(&self, request: Add) -> Box<::simproto::__service::Future<Item = u32, Error = ::std::io::Error> + Send + Sync>;
+    fn zero(&self) -> Box<::simproto::__service::Future<Item = u32, Error = ::std::io::Error> + Send + Sync>;
+    fn readings(&self) -> Box<::simproto::__service::Stream<Item = Reading, Error = ::simproto::__service::Never> + Send>;
+}
+
+/// Schema of the services, see `Handler::serve_schema`.
+pub const SCHEMA: &str = "// Operands of an addition.\nstruct Add {\n    a: u32,\n    b: u32,\n}\n\nstruct Reading {\n    sensor: string,\n    values: list<optional<f64>>,\n    raw: bytes,\n}\n\nservice Calculator {\n    rpc add(Add) -> u32;\n    rpc zero() -> u32;\n    sub readings -> Reading;\n}\n";
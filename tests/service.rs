@@ -0,0 +1,100 @@
+#![cfg(feature = "service")]
+extern crate futures;
+extern crate simproto;
+
+use futures::executor::{block_on, spawn};
+use futures::future::ok;
+use futures::prelude::*;
+use futures::stream;
+use simproto::service;
+use simproto::sim::typed::Json;
+use simproto::sim::{Handler, RpcResponse, Sim};
+use simproto::util::PairIO;
+use std::io;
+
+#[service]
+pub trait Calculator {
+    fn add(&self, request: (u32, u32)) -> Box<Future<Item = u32, Error = io::Error> + Send + Sync>;
+    fn zero(&self) -> Box<Future<Item = u32, Error = io::Error> + Send + Sync>;
+    fn ticks(&self) -> Box<Stream<Item = u64, Error = Never> + Send>;
+}
+
+/// A service without subscriptions.
+#[service]
+pub trait Echo {
+    fn echo(&self, request: String) -> Box<Future<Item = String, Error = io::Error> + Send + Sync>;
+}
+
+struct Adder;
+
+impl Calculator for Adder {
+    fn add(&self, (a, b): (u32, u32)) -> Box<Future<Item = u32, Error = io::Error> + Send + Sync> {
+        Box::new(ok(a + b))
+    }
+
+    fn zero(&self) -> Box<Future<Item = u32, Error = io::Error> + Send + Sync> {
+        Box::new(ok(0))
+    }
+
+    fn ticks(&self) -> Box<Stream<Item = u64, Error = Never> + Send> {
+        Box::new(stream::iter_ok(1..))
+    }
+}
+
+#[test]
+fn service() {
+    let handler = Handler::new();
+    let fut = register_calculator(&handler, Adder, Json);
+    let sim = Sim::new(handler);
+
+    let (io1, io2) = PairIO::new();
+    let (req1, fut1) = sim.add(io1);
+    block_on(spawn(fut1.map_err(|e| panic!("io1 sim fut panic {:?}", e)))).unwrap();
+    let (_, fut2) = sim.add(io2);
+    block_on(spawn(fut2.map_err(|e| panic!("io2 sim fut panic {:?}", e)))).unwrap();
+
+    let client = CalculatorClient::new(req1, Json);
+    let (client, sum) = block_on(client.add(&(1, 2))).unwrap();
+    assert_eq!(sum, Ok(3));
+    let (client, zero) = block_on(client.zero()).unwrap();
+    assert_eq!(zero, Ok(0));
+
+    let (client, ticks) = block_on(client.ticks()).unwrap();
+    block_on(spawn(fut)).unwrap();
+    let (tick, _) = block_on(ticks.unwrap().next()).map_err(|(e, _)| e).unwrap();
+    assert!(tick.is_some());
+
+    let requestor = client.into_inner();
+    let (_, resp) = block_on(requestor.rpc_typed::<_, u32, _>(
+        "Calculator/mul".into(),
+        Json,
+        &(1, 2),
+    )).unwrap();
+    assert_eq!(resp, Err(RpcResponse::TopicNotFound));
+}
+
+struct Repeater;
+
+impl Echo for Repeater {
+    fn echo(&self, request: String) -> Box<Future<Item = String, Error = io::Error> + Send + Sync> {
+        Box::new(ok(request))
+    }
+}
+
+#[test]
+fn rpc_only_service() {
+    let handler = Handler::new();
+    let fut = register_echo(&handler, Repeater, Json);
+    block_on(fut).unwrap();
+    let sim = Sim::new(handler);
+
+    let (io1, io2) = PairIO::new();
+    let (req1, fut1) = sim.add(io1);
+    block_on(spawn(fut1.map_err(|e| panic!("io1 sim fut panic {:?}", e)))).unwrap();
+    let (_, fut2) = sim.add(io2);
+    block_on(spawn(fut2.map_err(|e| panic!("io2 sim fut panic {:?}", e)))).unwrap();
+
+    let client = EchoClient::new(req1, Json);
+    let (_, echo) = block_on(client.echo(&"hello".to_string())).unwrap();
+    assert_eq!(echo, Ok("hello".to_string()));
+}